@@ -0,0 +1,265 @@
+/* Addresses of nodes this node has heard of, learned from seeds, handshakes and `Addr` messages.
+Every address keeps when it was last seen and how its connections went, so outbound slots go to the nodes most
+likely to answer and addresses that keep failing are forgotten. The book is persisted, so a restarted node can
+rejoin the network without its seeds. */
+
+use crate::time::now;
+
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+
+/// Most addresses kept. The stalest one is dropped to make room for a new one.
+pub const MAX_ADDRESSES: usize = 2000;
+
+/// Most addresses sent in a single `Addr` message.
+pub const MAX_ADDR_PER_MESSAGE: usize = 1000;
+
+/// Failed connections in a row after which an address is forgotten.
+pub const MAX_FAILURES: u32 = 5;
+
+/// Address of a node along with when it was last seen, as sent in `Addr` messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerAddress {
+    pub address: SocketAddr,
+    /// Unix time in seconds.
+    pub last_seen: u64,
+}
+
+/// What a node knows about an address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AddressEntry {
+    pub address: SocketAddr,
+    /// Unix time in seconds the node was last heard from, directly or through a peer.
+    pub last_seen: u64,
+    /// Connections that completed an exchange with the node.
+    pub successes: u32,
+    /// Connections that failed since the last successful one.
+    pub failures: u32,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AddressBook {
+    entries: HashMap<SocketAddr, AddressEntry>,
+    /// Whether the entries changed since `take_changed` was last called.
+    changed: bool,
+}
+
+impl AddressBook {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Book holding previously persisted `entries`.
+    pub fn from_entries(entries: Vec<AddressEntry>) -> Self {
+        let mut book = Self::new();
+        for entry in entries {
+            book.make_room();
+            book.entries.insert(entry.address, entry);
+        }
+        book.changed = false;
+        book
+    }
+
+    /// Whether the entries changed since this was last called, so the book only needs saving when it did.
+    pub fn take_changed(&mut self) -> bool {
+        std::mem::take(&mut self.changed)
+    }
+
+    pub fn entries(&self) -> Vec<AddressEntry> {
+        self.entries.values().copied().collect()
+    }
+
+    pub fn contains(&self, address: &SocketAddr) -> bool {
+        self.entries.contains_key(address)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Adds an address a peer told us about, or moves its last seen time forward.
+    /// Times in the future are taken as now.
+    pub fn add(&mut self, peer: PeerAddress) {
+        let last_seen = peer.last_seen.min(now());
+        if let Some(entry) = self.entries.get_mut(&peer.address) {
+            if last_seen > entry.last_seen {
+                entry.last_seen = last_seen;
+                self.changed = true;
+            }
+            return;
+        }
+
+        self.make_room();
+        self.changed = true;
+        self.entries.insert(
+            peer.address,
+            AddressEntry {
+                address: peer.address,
+                last_seen,
+                successes: 0,
+                failures: 0,
+            },
+        );
+    }
+
+    /// Records that the node at `address` was just heard from.
+    pub fn mark_seen(&mut self, address: SocketAddr) {
+        self.add(PeerAddress {
+            address,
+            last_seen: now(),
+        });
+    }
+
+    pub fn record_success(&mut self, address: SocketAddr) {
+        self.mark_seen(address);
+        if let Some(entry) = self.entries.get_mut(&address) {
+            entry.successes += 1;
+            entry.failures = 0;
+            self.changed = true;
+        }
+    }
+
+    /// Counts a failed connection, forgetting the address after `MAX_FAILURES` in a row.
+    pub fn record_failure(&mut self, address: &SocketAddr) {
+        let Some(entry) = self.entries.get_mut(address) else {
+            return;
+        };
+        entry.failures += 1;
+        self.changed = true;
+        if entry.failures >= MAX_FAILURES {
+            self.entries.remove(address);
+        }
+    }
+
+    /// Up to `max` of the most recently seen addresses, to share with a peer.
+    pub fn recent(&self, max: usize) -> Vec<PeerAddress> {
+        let mut entries = self.entries();
+        entries.sort_by_key(|entry| Reverse(entry.last_seen));
+        entries
+            .into_iter()
+            .take(max)
+            .map(|entry| PeerAddress {
+                address: entry.address,
+                last_seen: entry.last_seen,
+            })
+            .collect()
+    }
+
+    /// Up to `max` addresses to connect to, leaving out `exclude`: those that failed least often first,
+    /// then those that succeeded most often, then the most recently seen.
+    pub fn candidates(&self, exclude: &HashSet<SocketAddr>, max: usize) -> Vec<SocketAddr> {
+        let mut entries = self
+            .entries
+            .values()
+            .filter(|entry| !exclude.contains(&entry.address))
+            .collect::<Vec<_>>();
+        entries.sort_by(|a, b| {
+            a.failures
+                .cmp(&b.failures)
+                .then(b.successes.cmp(&a.successes))
+                .then(b.last_seen.cmp(&a.last_seen))
+        });
+        entries
+            .into_iter()
+            .take(max)
+            .map(|entry| entry.address)
+            .collect()
+    }
+
+    /// Drops the stalest address if the book is full.
+    fn make_room(&mut self) {
+        if self.entries.len() < MAX_ADDRESSES {
+            return;
+        }
+        let stalest = self
+            .entries
+            .values()
+            .min_by_key(|entry| (entry.last_seen, entry.successes))
+            .map(|entry| entry.address);
+        if let Some(address) = stalest {
+            self.entries.remove(&address);
+            self.changed = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(n: u32) -> SocketAddr {
+        (std::net::Ipv4Addr::from(0x0a00_0000 + n), 1729).into()
+    }
+
+    fn book_of(count: u32) -> AddressBook {
+        let mut book = AddressBook::new();
+        for n in 0..count {
+            book.add(PeerAddress {
+                address: address(n),
+                last_seen: 1_000 + n as u64,
+            });
+        }
+        book
+    }
+
+    #[test]
+    fn full_book_drops_the_stalest_address() {
+        let mut book = book_of(MAX_ADDRESSES as u32);
+        assert!(book.take_changed());
+
+        book.mark_seen(address(5_000));
+        assert_eq!(book.len(), MAX_ADDRESSES);
+        assert!(book.contains(&address(5_000)));
+        assert!(!book.contains(&address(0)));
+        assert!(book.contains(&address(1)));
+        assert!(book.take_changed());
+
+        // Hearing of a known address again only moves its time forward.
+        book.add(PeerAddress {
+            address: address(1),
+            last_seen: 1,
+        });
+        assert!(!book.take_changed());
+    }
+
+    #[test]
+    fn failing_addresses_sink_and_are_forgotten() {
+        let mut book = book_of(3);
+        book.record_failure(&address(2));
+        book.record_success(address(0));
+        let none = HashSet::new();
+        assert_eq!(book.candidates(&none, 3), vec![address(0), address(1), address(2)]);
+        assert_eq!(book.candidates(&HashSet::from([address(0)]), 1), vec![address(1)]);
+
+        // A success clears the failures before it.
+        book.record_success(address(2));
+        for _ in 1..MAX_FAILURES {
+            book.record_failure(&address(2));
+        }
+        assert!(book.contains(&address(2)));
+        book.record_failure(&address(2));
+        assert!(!book.contains(&address(2)));
+    }
+
+    #[test]
+    fn restored_book_keeps_its_entries_and_limit() {
+        let mut book = book_of(3);
+        book.record_success(address(1));
+        let restored = AddressBook::from_entries(book.entries());
+        let mut entries = restored.entries();
+        entries.sort_by_key(|entry| entry.last_seen);
+        let mut expected = book.entries();
+        expected.sort_by_key(|entry| entry.last_seen);
+        assert_eq!(entries, expected);
+        assert!(!AddressBook::from_entries(book.entries()).take_changed());
+
+        let restored = AddressBook::from_entries(book_of(MAX_ADDRESSES as u32 + 10).entries());
+        assert_eq!(restored.len(), MAX_ADDRESSES);
+    }
+}
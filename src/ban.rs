@@ -0,0 +1,265 @@
+/* Misbehavior scores and bans of peers, by IP address.
+Every offence adds to the score of the address it came from. Once the score reaches `BAN_SCORE` the address is
+banned for `BAN_DURATION`: its connections are closed and new ones refused. The list is shared between the node and
+the receiver accepting its peer connections, and the bans are persisted so a restart doesn't lift them. */
+
+use crate::error::{SyncError, ValidationError};
+use crate::time::now;
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+/// Score at which a peer is banned.
+pub const BAN_SCORE: u32 = 100;
+
+/// How long a ban lasts.
+pub const BAN_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Something a peer did that an honest peer wouldn't.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Offence {
+    /// A block or header that breaks a consensus rule.
+    InvalidBlock,
+    /// A block or header without the proof of work it claims.
+    InvalidProofOfWork,
+    /// A frame longer than the receiver accepts.
+    OversizedMessage,
+    /// A frame that doesn't decode to a message.
+    MalformedMessage,
+    /// A block or headers nobody asked the peer for.
+    UnsolicitedData,
+    /// Headers of a chain the peer can't deliver the blocks of, or with less work than ours.
+    UselessHeaders,
+}
+
+impl Offence {
+    pub fn score(&self) -> u32 {
+        match self {
+            Offence::InvalidProofOfWork => 100,
+            Offence::InvalidBlock | Offence::OversizedMessage => 50,
+            Offence::MalformedMessage | Offence::UselessHeaders => 20,
+            Offence::UnsolicitedData => 10,
+        }
+    }
+
+    /// Offence of sending a block or header that fails with `error`, if it is one.
+    /// A timestamp ahead of our clock may just be clock drift, so it isn't held against the peer.
+    pub fn for_invalid_block(error: &ValidationError) -> Option<Self> {
+        match error {
+            ValidationError::TimestampTooNew { .. } => None,
+            ValidationError::InsufficientWork(_)
+            | ValidationError::HashMismatch { .. } => Some(Offence::InvalidProofOfWork),
+            _ => Some(Offence::InvalidBlock),
+        }
+    }
+
+    /// Offence of the peer whose headers made a sync fail with `error`, if it is one.
+    pub fn for_sync_error(error: &SyncError) -> Option<Self> {
+        match error {
+            SyncError::InvalidHeader(e) => Self::for_invalid_block(e),
+            SyncError::TooManyHeaders { .. } => Some(Offence::OversizedMessage),
+            SyncError::LessWork { .. } | SyncError::BlockUnavailable { .. } => {
+                Some(Offence::UselessHeaders)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Offence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let offence = match self {
+            Offence::InvalidBlock => "sent an invalid block",
+            Offence::InvalidProofOfWork => "sent a block without valid proof of work",
+            Offence::OversizedMessage => "sent an oversized message",
+            Offence::MalformedMessage => "sent a malformed message",
+            Offence::UnsolicitedData => "sent data nobody asked for",
+            Offence::UselessHeaders => "sent headers of a chain it couldn't back up",
+        };
+        f.write_str(offence)
+    }
+}
+
+/// A banned address and when its ban ends, in unix seconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ban {
+    pub ip: IpAddr,
+    pub until: u64,
+}
+
+/// Scores and bans, shared by every clone.
+#[derive(Debug, Clone, Default)]
+pub struct BanList {
+    inner: Arc<Mutex<Bans>>,
+}
+
+#[derive(Debug, Default)]
+struct Bans {
+    scores: HashMap<IpAddr, u32>,
+    banned: HashMap<IpAddr, u64>,
+    /// Whether bans were added or lifted since `take_changed` was last called.
+    changed: bool,
+    /// Whether loopback addresses are left unscored.
+    exempt_loopback: bool,
+}
+
+impl BanList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// List holding previously persisted `bans`.
+    pub fn from_bans(bans: Vec<Ban>) -> Self {
+        let list = Self::new();
+        list.lock()
+            .banned
+            .extend(bans.into_iter().map(|ban| (ban.ip, ban.until)));
+        list
+    }
+
+    /// Bans that haven't run out yet.
+    pub fn bans(&self) -> Vec<Ban> {
+        let now = now();
+        self.lock()
+            .banned
+            .iter()
+            .filter(|(_, until)| **until > now)
+            .map(|(ip, until)| Ban {
+                ip: *ip,
+                until: *until,
+            })
+            .collect()
+    }
+
+    /// Leaves loopback addresses unscored if `exempt` is set, so nodes sharing a host for testing can't ban one
+    /// another. Off by default, since it turns banning off for every peer on the same host.
+    pub fn set_exempt_loopback(&self, exempt: bool) {
+        self.lock().exempt_loopback = exempt;
+    }
+
+    /// Whether bans were added or lifted since this was last called, by any clone of the list.
+    pub fn take_changed(&self) -> bool {
+        std::mem::take(&mut self.lock().changed)
+    }
+
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        self.lock()
+            .banned
+            .get(ip)
+            .is_some_and(|until| *until > now())
+    }
+
+    /// Adds the score of `offence` to that of `ip`, banning it once the score reaches `BAN_SCORE`.
+    /// Returns true if this banned it. Loopback addresses are left alone if they are exempt.
+    pub fn punish(&self, ip: IpAddr, offence: Offence) -> bool {
+        let mut bans = self.lock();
+        if bans.exempt_loopback && ip.is_loopback() {
+            return false;
+        }
+
+        let score = bans.scores.entry(ip).or_default();
+        *score += offence.score();
+        if *score < BAN_SCORE {
+            return false;
+        }
+
+        bans.scores.remove(&ip);
+        bans.banned.insert(ip, now() + BAN_DURATION.as_secs());
+        bans.changed = true;
+        true
+    }
+
+    /// Lifts the bans that ran out. Returns how many were lifted.
+    pub fn expire(&self) -> usize {
+        let now = now();
+        let mut bans = self.lock();
+        let before = bans.banned.len();
+        bans.banned.retain(|_, until| *until > now);
+        let lifted = before - bans.banned.len();
+        bans.changed |= lifted > 0;
+        lifted
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Bans> {
+        self.inner.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const PEER: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+    #[test]
+    fn scores_add_up_to_a_ban() {
+        let bans = BanList::new();
+        for _ in 0..4 {
+            assert!(!bans.punish(PEER, Offence::MalformedMessage));
+        }
+        assert!(!bans.is_banned(&PEER));
+        assert!(!bans.take_changed());
+
+        assert!(bans.punish(PEER, Offence::UselessHeaders));
+        assert!(bans.is_banned(&PEER));
+        assert!(bans.take_changed());
+        assert_eq!(bans.bans().len(), 1);
+        assert!(bans.bans()[0].until >= now() + BAN_DURATION.as_secs() - 1);
+
+        // Invalid proof of work is enough on its own.
+        let other = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        assert!(bans.punish(other, Offence::InvalidProofOfWork));
+    }
+
+    #[test]
+    fn invalid_blocks_map_to_offences() {
+        assert_eq!(
+            Offence::for_invalid_block(&ValidationError::InsufficientWork(0)),
+            Some(Offence::InvalidProofOfWork)
+        );
+        assert_eq!(
+            Offence::for_invalid_block(&ValidationError::TimestampTooNew { timestamp: 2, max: 1 }),
+            None
+        );
+        assert_eq!(
+            Offence::for_invalid_block(&ValidationError::UnsupportedVersion(9)),
+            Some(Offence::InvalidBlock)
+        );
+    }
+
+    #[test]
+    fn bans_run_out() {
+        let bans = BanList::from_bans(vec![
+            Ban { ip: PEER, until: now() - 1 },
+            Ban {
+                ip: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+                until: now() + 60,
+            },
+        ]);
+        assert!(!bans.is_banned(&PEER));
+        assert_eq!(bans.bans().len(), 1);
+        assert!(!bans.take_changed());
+
+        assert_eq!(bans.expire(), 1);
+        assert!(bans.take_changed());
+        assert_eq!(bans.expire(), 0);
+        assert!(!bans.take_changed());
+    }
+
+    #[test]
+    fn loopback_is_only_exempt_when_asked() {
+        let loopback = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let bans = BanList::new();
+        assert!(bans.punish(loopback, Offence::InvalidProofOfWork));
+
+        let bans = BanList::new();
+        bans.set_exempt_loopback(true);
+        assert!(!bans.punish(loopback, Offence::InvalidProofOfWork));
+        assert!(!bans.is_banned(&loopback));
+        assert!(bans.punish(PEER, Offence::InvalidProofOfWork));
+    }
+}
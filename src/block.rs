@@ -1,26 +1,80 @@
+use crate::hash::Hash256;
+use crate::merkle::{MerkleLeaf as _, MerkleProof, MerkleTree};
+use crate::pow::target_from_leading_zeros;
+use crate::time::now;
 use crate::transaction::*;
+use crate::utxo::UtxoTxn;
 use rand::{thread_rng, Rng};
 use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
 
+/// Default difficulty of the genesis block, in leading zero bits. Later blocks follow `ChainParams::retarget`.
 pub const DIFFICULTY: u8 = 10;
-pub static mut BLOCK_INDEX: u32 = 0;
+/// Subsidy of the first blocks, before any halving.
+pub const REWARD: u64 = 50;
+pub const GENESIS_PREVIOUS_HASH: Hash256 = Hash256::ZERO;
 
-#[derive(Debug, Serialize, Deserialize, Clone, Hash)]
+/// Header layout written by `BlockHeader::encode`. Blocks of any other version are rejected.
+pub const BLOCK_VERSION: u32 = 2;
+
+/// Length of an encoded header.
+pub const HEADER_SIZE: usize = 156;
+
+/// Most transactions a block may carry, of either kind.
+pub const MAX_BLOCK_TXNS: usize = 1000;
+
+/// Largest serialized block, in bytes.
+pub const MAX_BLOCK_SIZE: usize = 1_000_000;
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, Hash)]
 pub struct Body {
     pub txn_data: Vec<Txn>,
+    pub utxo_txns: Vec<UtxoTxn>,
+}
+
+impl Body {
+    pub fn len(&self) -> usize {
+        self.txn_data.len() + self.utxo_txns.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Ids of the account transactions followed by those of the UTXO transactions, in Merkle tree order.
+    pub fn txn_ids(&self) -> impl Iterator<Item = Hash256> + '_ {
+        self.txn_data
+            .iter()
+            .map(|txn| txn.id)
+            .chain(self.utxo_txns.iter().map(|txn| txn.id))
+    }
+
+    /// Tree over the full encodings of the account transactions followed by those of the UTXO transactions.
+    pub fn merkle_tree(&self) -> MerkleTree {
+        let leaves = self
+            .txn_data
+            .iter()
+            .map(|txn| txn.leaf_hash())
+            .chain(self.utxo_txns.iter().map(|txn| txn.leaf_hash()))
+            .collect();
+        MerkleTree::new(leaves)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Hash)]
 pub struct BlockHeader {
+    pub version: u32,
     pub index: u32,
-    pub previous_hash: String,
+    pub previous_hash: Hash256,
     pub timestamp: u64,
-    pub current_hash: String,
+    /// Id of the block, `hash()` of the other fields.
+    pub current_hash: Hash256,
     pub coinbase_txn: CoinbaseTxn,
-    pub merkle_root: String,
+    pub merkle_root: Hash256,
+    /// Root of the sparse Merkle tree over account state after applying the block. Zero for a UTXO chain.
+    pub state_root: Hash256,
     pub nonce: u32,
-    pub difficulty: u8,
+    /// Compact encoding of the target the block hash must not exceed.
+    pub bits: u32,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Hash)]
@@ -29,107 +83,131 @@ pub struct Block {
     pub body: Body,
 }
 
+impl BlockHeader {
+    /// Canonical encoding of every field but `current_hash`:
+    /// version (u32 BE) || index (u32 BE) || previous hash (32) || merkle root (32) || state root (32) || timestamp (u64 BE) ||
+    /// bits (u32 BE) || nonce (u32 BE) || coinbase amount (u64 BE) || coinbase validator (20) || coinbase extra nonce (u64 BE).
+    pub fn encode(&self) -> [u8; HEADER_SIZE] {
+        let coinbase = &self.coinbase_txn;
+        let fields: [&[u8]; 11] = [
+            &self.version.to_be_bytes(),
+            &self.index.to_be_bytes(),
+            self.previous_hash.as_bytes(),
+            self.merkle_root.as_bytes(),
+            self.state_root.as_bytes(),
+            &self.timestamp.to_be_bytes(),
+            &self.bits.to_be_bytes(),
+            &self.nonce.to_be_bytes(),
+            &coinbase.amount.to_be_bytes(),
+            coinbase.validator.as_bytes(),
+            &coinbase.extra_nonce.to_be_bytes(),
+        ];
+
+        let mut bytes = [0u8; HEADER_SIZE];
+        let mut offset = 0;
+        for field in fields {
+            bytes[offset..offset + field.len()].copy_from_slice(field);
+            offset += field.len();
+        }
+        bytes
+    }
+
+    /// Block id, which is also what proof of work is checked against: double SHA-256 of the encoded header.
+    pub fn hash(&self) -> Hash256 {
+        Hash256::double_digest(&self.encode())
+    }
+}
+
 impl Block {
-    pub fn new(previous_hash: String, txn_data: Vec<Txn>) -> Block {
+    pub fn new(index: u32, previous_hash: Hash256, body: Body) -> Block {
         let random = thread_rng().gen::<u32>();
         let block_header = BlockHeader {
-            timestamp: std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_secs(),
-            index: update_index(),
+            version: BLOCK_VERSION,
+            timestamp: now(),
+            index,
             previous_hash,
-            current_hash: String::new(),
+            current_hash: Hash256::ZERO,
             coinbase_txn: CoinbaseTxn::new(),
-            merkle_root: MerkleRoot::new(),
+            merkle_root: MerkleRoot::empty(),
+            state_root: Hash256::ZERO,
             nonce: random,
-            difficulty: DIFFICULTY,
+            bits: target_from_leading_zeros(DIFFICULTY).to_compact(),
         };
 
-        let body = Body { txn_data };
-
         Block { block_header, body }
     }
+
+    /// Proof that the transaction with id `txid` is committed to by the header's merkle root,
+    /// checked with `merkle::verify_proof` against the `MerkleLeaf::leaf_hash` of the transaction.
+    pub fn merkle_proof(&self, txid: &Hash256) -> Option<MerkleProof> {
+        let index = self.body.txn_ids().position(|id| id == *txid)?;
+        self.body.merkle_tree().proof(index)
+    }
+
+    /// Size of the serialized block, which `MAX_BLOCK_SIZE` limits.
+    pub fn size(&self) -> usize {
+        bincode::serialized_size(self).unwrap_or(u64::MAX) as usize
+    }
 }
 
 #[derive(Debug)]
 pub struct MerkleRoot;
 
 impl MerkleRoot {
-    pub fn new() -> String {
-        String::new()
+    pub fn empty() -> Hash256 {
+        Hash256::ZERO
     }
 
-    pub fn from(txns: Vec<Txn>) -> String {
-        let mut txns = txns;
-        if txns.is_empty() {
-            let mut hasher = Sha256::new();
-            hasher.update(String::default().as_bytes());
-            let hash = hasher.finalize().as_slice().to_owned();
-            return hex::encode(hash);
-        }
-
-        if txns.len() % 2 != 0 {
-            txns.push(txns[txns.len() - 1].clone());
-        }
-
-        let hashed_txns = txns
-            .iter()
-            .map(|txn| {
-                let mut hasher = Sha256::new();
-                hasher.update(&txn.sender.as_bytes());
-                hasher.update(&txn.receiver.as_bytes());
-                hasher.update(&txn.amount.to_string().as_bytes());
-                let hash = hasher.finalize().as_slice().to_owned();
-                hex::encode(hash)
-            })
-            .collect::<Vec<String>>();
-
-        Self::construct_root(hashed_txns)
+    pub fn from(txns: Vec<Txn>) -> Hash256 {
+        MerkleTree::new(txns.iter().map(|txn| txn.leaf_hash()).collect()).root()
     }
 
-    fn construct_root(hashed_leaves: Vec<String>) -> String {
-        let mut merkle_root = String::new();
-
-        let mut hashes = hashed_leaves;
-
-        while hashes.len() > 1 {
-            let mut nodes = hashes.clone();
-            let mut parent_nodes = Vec::<String>::new();
-            for i in 0..nodes.len() / 2 {
-                let index = 2 * i;
-                let left = nodes[index].clone();
-                let right = nodes[index + 1].clone();
-
-                let mut hasher = Sha256::new();
-                hasher.update(&left.as_bytes());
-                hasher.update(&right.as_bytes());
-                let hash = hasher.finalize().as_slice().to_owned();
-                let hash = hex::encode(hash);
-                parent_nodes.push(hash);
-            }
-
-            nodes = parent_nodes;
-            if nodes.len() == 1 {
-                merkle_root = nodes[0].clone();
-                break;
-            }
-
-            if nodes.len() % 2 != 0 {
-                nodes.push(nodes[nodes.len() - 1].clone());
-            }
-
-            hashes = nodes;
-        }
-
-        merkle_root
+    pub fn from_body(body: &Body) -> Hash256 {
+        body.merkle_tree().root()
     }
 }
 
-fn update_index() -> u32 {
-    unsafe {
-        let pre_level = BLOCK_INDEX;
-        BLOCK_INDEX += 1;
-        pre_level
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_encoding_and_hash_match_known_vector() {
+        let header = BlockHeader {
+            version: BLOCK_VERSION,
+            index: 0x0102_0304,
+            previous_hash: Hash256::from([0x11; 32]),
+            timestamp: 1_700_000_000,
+            current_hash: Hash256::from([0xff; 32]),
+            coinbase_txn: CoinbaseTxn {
+                amount: 50,
+                validator: Address::from([0x44; 20]),
+                extra_nonce: 7,
+            },
+            merkle_root: Hash256::from([0x22; 32]),
+            state_root: Hash256::from([0x33; 32]),
+            nonce: 0xdead_beef,
+            bits: 0x1d00_ffff,
+        };
+
+        let expected = [
+            "00000002",
+            "01020304",
+            &"11".repeat(32),
+            &"22".repeat(32),
+            &"33".repeat(32),
+            "000000006553f100",
+            "1d00ffff",
+            "deadbeef",
+            "0000000000000032",
+            &"44".repeat(20),
+            "0000000000000007",
+        ]
+        .concat();
+        assert_eq!(hex::encode(header.encode()), expected);
+        assert_eq!(
+            header.hash(),
+            "99c7c6fcf91a6abde14ba4926bc1f34d9a5b3b20b62a2ea9791faf797ff944fe".parse().unwrap()
+        );
     }
 }
@@ -1,87 +1,426 @@
 use crate::block::*;
-use crate::transaction::*;
+use crate::error::{ChainError, ValidationError};
+use crate::hash::Hash256;
+use crate::ledger::{BlockUndo, Ledger};
+use crate::params::ChainParams;
+use crate::state::AccountProof;
+use crate::pow::{work_from_compact, U256};
+use crate::transaction::Address;
+use crate::validation::BlockValidator;
 
-use anyhow::Result;
-use log::error;
-use serde::Deserialize;
-use serde::Serialize;
-use sha2::{Digest as _, Sha256};
+use std::collections::HashMap;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Number of most recent blocks a locator lists one by one before it starts skipping.
+const LOCATOR_DENSE_SPAN: usize = 10;
+
+/// A block kept in the tree along with its height and the cumulative work of the branch ending at it.
+#[derive(Debug, Clone)]
+struct ChainEntry {
+    block: Block,
+    height: u32,
+    total_work: U256,
+    // How to revert this block from the ledger of its branch.
+    undo: BlockUndo,
+}
+
+/// Changes to the canonical chain caused by adding blocks to the tree.
+#[derive(Debug, Clone, Default)]
+pub struct ChainUpdate {
+    /// Blocks that left the canonical chain, newest first.
+    pub disconnected: Vec<Block>,
+    /// Blocks that joined the canonical chain, oldest first.
+    pub connected: Vec<Block>,
+}
+
+impl ChainUpdate {
+    pub fn tip_changed(&self) -> bool {
+        !self.connected.is_empty() || !self.disconnected.is_empty()
+    }
+
+    pub fn is_reorg(&self) -> bool {
+        !self.disconnected.is_empty()
+    }
+}
+
+/// Block tree keyed by block hash.
+/// Every branch is kept, and the canonical tip is the block with the most cumulative work.
+#[derive(Debug, Clone)]
 pub struct BlockChain {
-    pub blocks: Vec<Block>,
+    params: ChainParams,
+    entries: HashMap<Hash256, ChainEntry>,
+    genesis: Option<Hash256>,
+    tip: Option<Hash256>,
+    // Ledger after applying the canonical tip.
+    ledger: Ledger,
+}
+
+impl Default for BlockChain {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl BlockChain {
     pub fn new() -> Self {
-        Self { blocks: vec![] }
+        Self::with_params(ChainParams::default())
     }
 
-    pub fn tip(&self) -> String {
-        self.blocks
-            .last()
-            .unwrap()
-            .block_header
-            .current_hash
-            .clone()
+    pub fn with_params(params: ChainParams) -> Self {
+        Self {
+            ledger: Ledger::new(&params),
+            params,
+            entries: HashMap::new(),
+            genesis: None,
+            tip: None,
+        }
     }
 
-    pub fn all_blocks_in_longest_chain(&self) -> Vec<Block> {
-        self.blocks.clone()
+    pub fn params(&self) -> &ChainParams {
+        &self.params
     }
 
-    pub fn add_block(&mut self, new_block: Block) -> Result<Self> {
-        match self.blocks.last() {
-            Some(previous_block) => {
-                if new_block.block_header.previous_hash != previous_block.block_header.current_hash
-                {
-                    error!("Block is an invalid extension of the previous blockchain state");
-                }
-                self.blocks.push(new_block);
-                Ok(self.clone())
+    /// Hash of the canonical tip.
+    pub fn tip(&self) -> Option<Hash256> {
+        self.tip
+    }
+
+    pub fn latest_block(&self) -> Option<&Block> {
+        self.tip.as_ref().map(|hash| &self.entries[hash].block)
+    }
+
+    /// Height of the canonical tip.
+    pub fn height(&self) -> Option<u32> {
+        self.tip.as_ref().map(|hash| self.entries[hash].height)
+    }
+
+    /// Cumulative work of the canonical chain.
+    pub fn total_work(&self) -> U256 {
+        self.tip
+            .as_ref()
+            .map_or(U256::ZERO, |hash| self.entries[hash].total_work)
+    }
+
+    /// Ledger after the canonical tip.
+    pub fn ledger(&self) -> &Ledger {
+        &self.ledger
+    }
+
+    pub fn balance_of(&self, address: &Address) -> u64 {
+        self.ledger.balance_of(address)
+    }
+
+    /// Ledger after the canonical block at `height`.
+    pub fn state_at(&self, height: u32) -> Option<Ledger> {
+        let hash = self.canonical_hash_at(height)?;
+        Some(self.ledger_after(Some(&hash)))
+    }
+
+    /// Hash of the canonical block at `height`.
+    pub fn canonical_hash_at(&self, height: u32) -> Option<Hash256> {
+        let tip = self.tip.as_ref()?;
+        let tip_height = self.entries[tip].height;
+        if height > tip_height {
+            return None;
+        }
+
+        self.ancestors(tip)
+            .nth((tip_height - height) as usize)
+            .map(|block| block.block_header.current_hash)
+    }
+
+    /// Hashes of canonical blocks going back from the tip, for a peer to find where its chain forks from ours:
+    /// the last ten blocks one by one, then doubling the step each time, always ending at genesis.
+    pub fn locator(&self) -> Vec<Hash256> {
+        let chain = self.canonical_hashes();
+        let mut locator = Vec::new();
+        let Some(mut height) = chain.len().checked_sub(1) else {
+            return locator;
+        };
+
+        let mut step = 1;
+        loop {
+            locator.push(chain[height]);
+            if height == 0 {
+                return locator;
             }
+            if locator.len() >= LOCATOR_DENSE_SPAN {
+                step *= 2;
+            }
+            height = height.saturating_sub(step);
+        }
+    }
+
+    /// Hashes of up to `max` canonical blocks following the fork point with a peer's chain: the first block of
+    /// `locator` that is on the canonical chain, or from genesis if there is none. Ends early at `stop` if it comes up.
+    pub fn hashes_after(&self, locator: &[Hash256], stop: Option<&Hash256>, max: usize) -> Vec<Hash256> {
+        let chain = self.canonical_hashes();
+        let start = locator
+            .iter()
+            .find_map(|hash| {
+                let height = self.height_of(hash)? as usize;
+                (chain.get(height) == Some(hash)).then_some(height + 1)
+            })
+            .unwrap_or(0);
+
+        let mut hashes = chain.into_iter().skip(start).take(max).collect::<Vec<_>>();
+        if let Some(end) = stop.and_then(|stop| hashes.iter().position(|hash| hash == stop)) {
+            hashes.truncate(end + 1);
+        }
+        hashes
+    }
+
+    /// Headers of the blocks `hashes_after` returns.
+    pub fn headers_after(&self, locator: &[Hash256], stop: Option<&Hash256>, max: usize) -> Vec<BlockHeader> {
+        self.hashes_after(locator, stop, max)
+            .iter()
+            .map(|hash| self.entries[hash].block.block_header.clone())
+            .collect()
+    }
+
+    /// Hashes of the canonical chain, indexed by height.
+    fn canonical_hashes(&self) -> Vec<Hash256> {
+        let mut hashes = match &self.tip {
+            Some(tip) => self
+                .ancestors(tip)
+                .map(|block| block.block_header.current_hash)
+                .collect::<Vec<_>>(),
+            None => vec![],
+        };
+        hashes.reverse();
+        hashes
+    }
+
+    /// Ledger after the given block, or the empty ledger for `None`.
+    /// Only the tip ledger is cached. Other ledgers are reached from it by reverting blocks with their undo data
+    /// and applying the blocks of the branch, falling back to replaying from genesis.
+    fn ledger_after(&self, hash: Option<&Hash256>) -> Ledger {
+        if self.tip.as_ref() == hash {
+            return self.ledger.clone();
+        }
+
+        let route = self.route(self.tip.as_ref(), hash);
+        let mut ledger = self.ledger.clone();
+
+        let reverted = route
+            .disconnected
+            .iter()
+            .all(|block| ledger.undo_block(&self.entries[&block.block_header.current_hash].undo));
+
+        let blocks = if reverted {
+            route.connected
+        } else {
+            ledger = Ledger::new(&self.params);
+            let mut branch = hash
+                .map(|hash| self.ancestors(hash).cloned().collect::<Vec<_>>())
+                .unwrap_or_default();
+            branch.reverse();
+            branch
+        };
+
+        for block in &blocks {
+            ledger
+                .apply_block(block, self.params.subsidy(block.block_header.index))
+                .expect("blocks in the tree were validated against their branch");
+        }
+        ledger
+    }
+
+    /// Account of `address` after the given block, provable against that block's `state_root`.
+    /// `None` if the block is unknown or the chain doesn't track accounts.
+    pub fn account_proof(&self, address: &Address, hash: &Hash256) -> Option<AccountProof> {
+        if !self.contains(hash) {
+            return None;
+        }
+        match self.ledger_after(Some(hash)) {
+            Ledger::Account(state) => Some(state.account_proof(address)),
+            Ledger::Utxo(_) => None,
+        }
+    }
+
+    pub fn contains(&self, hash: &Hash256) -> bool {
+        self.entries.contains_key(hash)
+    }
+
+    pub fn block(&self, hash: &Hash256) -> Option<&Block> {
+        self.entries.get(hash).map(|entry| &entry.block)
+    }
+
+    pub fn height_of(&self, hash: &Hash256) -> Option<u32> {
+        self.entries.get(hash).map(|entry| entry.height)
+    }
+
+    /// Compact target required of a block extending `parent`, or of the genesis block if `None`.
+    pub fn next_bits(&self, parent: Option<&Hash256>) -> u32 {
+        let ancestors = parent
+            .into_iter()
+            .flat_map(|hash| self.ancestors(hash))
+            .map(|block| &block.block_header);
+        self.params.next_bits(ancestors)
+    }
+
+    /// Cumulative work of the branch ending at `hash`.
+    pub fn work_of(&self, hash: &Hash256) -> Option<U256> {
+        self.entries.get(hash).map(|entry| entry.total_work)
+    }
+
+    /// Iterates from the given block back to genesis, starting with the block itself.
+    pub fn ancestors<'a>(&'a self, hash: &Hash256) -> impl Iterator<Item = &'a Block> + 'a {
+        let mut cursor = self.entries.contains_key(hash).then_some(*hash);
+        std::iter::from_fn(move || {
+            let hash = cursor.take()?;
+            cursor = self.parent_of(&hash);
+            Some(&self.entries[&hash].block)
+        })
+    }
+
+    /// Number of blocks in the tree, across all branches.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Walks the heaviest branch from the tip back to genesis and returns it oldest first.
+    pub fn all_blocks_in_longest_chain(&self) -> Vec<Block> {
+        let mut blocks = match &self.tip {
+            Some(tip) => self.ancestors(tip).cloned().collect::<Vec<_>>(),
+            None => vec![],
+        };
+
+        blocks.reverse();
+        blocks
+    }
+
+    /// Inserts a block into the tree and switches to its branch if that branch now has the most work.
+    pub fn add_block(&mut self, new_block: Block) -> Result<ChainUpdate, ChainError> {
+        let hash = new_block.block_header.current_hash;
+
+        if self.entries.contains_key(&hash) {
+            return Err(ChainError::AlreadyKnown(hash));
+        }
+
+        let (height, parent_work) = match &self.genesis {
             None => {
-                self.blocks.push(new_block);
-                Ok(self.clone())
+                if new_block.block_header.index != 0 {
+                    return Err(ChainError::OrphanBlock {
+                        hash,
+                        parent: new_block.block_header.previous_hash,
+                    });
+                }
+                (0, U256::ZERO)
             }
+            Some(_) => match self.entries.get(&new_block.block_header.previous_hash) {
+                Some(parent) => (parent.height + 1, parent.total_work),
+                None => {
+                    return Err(ChainError::OrphanBlock {
+                        hash,
+                        parent: new_block.block_header.previous_hash,
+                    });
+                }
+            },
+        };
+
+        BlockValidator::new(self)
+            .validate(&new_block)
+            .map_err(|source| ChainError::InvalidBlock {
+                hash,
+                source,
+            })?;
+
+        // Only a block that passed validation has its proof of work counted.
+        let total_work = parent_work.saturating_add(Self::block_work(&new_block));
+
+        let parent = (height > 0).then_some(new_block.block_header.previous_hash);
+        let mut ledger = self.ledger_after(parent.as_ref());
+        let undo = ledger
+            .apply_block(&new_block, self.params.subsidy(height))
+            .map_err(|source| ChainError::InvalidBlock {
+                hash,
+                source,
+            })?;
+
+        let state_root = ledger.state_root();
+        if state_root != new_block.block_header.state_root {
+            return Err(ChainError::InvalidBlock {
+                hash,
+                source: ValidationError::StateRootMismatch {
+                    expected: state_root,
+                    found: new_block.block_header.state_root,
+                },
+            });
+        }
+
+        if self.genesis.is_none() {
+            self.genesis = Some(hash);
+        }
+
+        self.entries.insert(
+            hash,
+            ChainEntry {
+                block: new_block,
+                height,
+                total_work,
+                undo,
+            },
+        );
+
+        if total_work > self.total_work() {
+            self.ledger = ledger;
+            let old_tip = self.tip.replace(hash);
+            return Ok(self.changes_since(old_tip.as_ref()));
         }
+
+        Ok(ChainUpdate::default())
     }
 
-    pub fn hash_block(block: Block) -> Vec<u8> {
-        let mut hasher = Sha256::new();
-        hasher.update(&block.block_header.index.to_string().as_bytes());
-        hasher.update(&block.block_header.previous_hash.as_bytes());
-        hasher.update(&block.block_header.difficulty.to_string().as_bytes());
-        hasher.update(&block.block_header.timestamp.to_string().as_bytes());
-        hasher.update(&block.block_header.nonce.to_string().as_bytes());
-        hasher.update(Self::hash_txn_batch(&block.body.txn_data).as_bytes());
-        let hash = hasher.finalize().as_slice().to_owned();
-        hash
+    /// Describes how to move from `old_tip` to the current canonical tip.
+    pub fn changes_since(&self, old_tip: Option<&Hash256>) -> ChainUpdate {
+        self.route(old_tip, self.tip.as_ref())
     }
 
-    pub fn hash_txn_batch(txns: &Vec<Txn>) -> String {
-        let mut hasher = Sha256::new();
-        for txn in txns {
-            let txn_hash = Self::hash_txn(txn);
-            if txns.len() == 1 {
-                return txn_hash;
+    /// Blocks to disconnect from `from` and connect towards `to` to move between the two.
+    fn route(&self, from: Option<&Hash256>, to: Option<&Hash256>) -> ChainUpdate {
+        let mut update = ChainUpdate::default();
+
+        let mut old = from.copied();
+        let mut new = to.copied();
+
+        loop {
+            match (&old, &new) {
+                (Some(o), Some(n)) if o == n => break,
+                (Some(o), Some(n)) if self.entries[o].height >= self.entries[n].height => {
+                    update.disconnected.push(self.entries[o].block.clone());
+                    old = self.parent_of(o);
+                }
+                (_, Some(n)) => {
+                    update.connected.push(self.entries[n].block.clone());
+                    new = self.parent_of(n);
+                }
+                (Some(o), None) => {
+                    update.disconnected.push(self.entries[o].block.clone());
+                    old = self.parent_of(o);
+                }
+                (None, None) => break,
             }
-            hasher.update(&txn_hash.as_bytes());
         }
 
-        let hash = hasher.finalize().as_slice().to_owned();
-        hex::encode(hash)
+        update.connected.reverse();
+        update
+    }
+
+    fn parent_of(&self, hash: &Hash256) -> Option<Hash256> {
+        let entry = self.entries.get(hash)?;
+        if entry.height == 0 {
+            return None;
+        }
+        Some(entry.block.block_header.previous_hash)
     }
 
-    pub fn hash_txn(txn: &Txn) -> String {
-        let mut hasher = Sha256::new();
-        hasher.update(txn.id.as_bytes());
-        hasher.update(txn.sender.as_bytes());
-        hasher.update(txn.receiver.as_bytes());
-        hasher.update(txn.amount.to_string().as_bytes());
-        let hash = hasher.finalize().as_slice().to_owned();
-        let hex_string = hex::encode(hash);
-        hex_string
+    /// Expected number of hashes needed to find a block at its target.
+    pub fn block_work(block: &Block) -> U256 {
+        work_from_compact(block.block_header.bits)
     }
 }
 
@@ -90,7 +429,232 @@ impl std::fmt::Display for BlockChain {
         write!(
             f,
             "Current State:\nLatest Block: {:?}",
-            self.blocks.last().unwrap()
+            self.latest_block()
         )
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::error::StateError;
+    use crate::pow::meets_target;
+    use crate::transaction::Txn;
+    use ed25519_dalek::SigningKey;
+
+    /// Parameters under which blocks are found in a few tries and a coinbase matures after two blocks.
+    pub(crate) fn test_params() -> ChainParams {
+        ChainParams {
+            initial_difficulty: 1,
+            retarget_interval: 1000,
+            coinbase_maturity: 2,
+            ..ChainParams::default()
+        }
+    }
+
+    /// Mines a block on `parent`, or a genesis block if `None`, paying `miner` and including `txns`.
+    /// The block is valid unless the ledger rejects `txns`, in which case its state root is left zero.
+    pub(crate) fn mine(chain: &BlockChain, parent: Option<Hash256>, miner: Address, txns: Vec<Txn>) -> Block {
+        let (index, previous_hash) = match parent {
+            Some(parent) => (chain.height_of(&parent).unwrap() + 1, parent),
+            None => (0, GENESIS_PREVIOUS_HASH),
+        };
+        let fees = txns.iter().map(|txn| txn.fee as u64).sum::<u64>();
+        let body = Body {
+            txn_data: txns,
+            ..Body::default()
+        };
+
+        let mut block = Block::new(index, previous_hash, body);
+        let header = &mut block.block_header;
+        header.merkle_root = MerkleRoot::from_body(&block.body);
+        header.coinbase_txn.validator = miner;
+        header.coinbase_txn.amount = chain.params().subsidy(index) + fees;
+        header.bits = chain.next_bits(parent.as_ref());
+
+        let mut ledger = chain.ledger_after(parent.as_ref());
+        if ledger.apply_block(&block, chain.params().subsidy(index)).is_ok() {
+            block.block_header.state_root = ledger.state_root();
+        }
+
+        while !meets_target(&block.block_header.hash(), block.block_header.bits) {
+            block.block_header.nonce = block.block_header.nonce.wrapping_add(1);
+        }
+        block.block_header.current_hash = block.block_header.hash();
+        block
+    }
+
+    /// Mines a block on `parent` and adds it, returning its hash.
+    pub(crate) fn extend(chain: &mut BlockChain, parent: Option<Hash256>, miner: Address, txns: Vec<Txn>) -> Hash256 {
+        let block = mine(chain, parent, miner, txns);
+        let hash = block.block_header.current_hash;
+        chain.add_block(block).unwrap();
+        hash
+    }
+
+    fn transfer(key: &SigningKey, receiver: Address, amount: u32, nonce: u64) -> Txn {
+        let mut txn = Txn::new(&key.verifying_key(), receiver, amount, 0, nonce);
+        txn.sign(key);
+        txn
+    }
+
+    fn state(chain: &BlockChain) -> &crate::state::WorldState {
+        match chain.ledger() {
+            Ledger::Account(state) => state,
+            Ledger::Utxo(_) => unreachable!("test chains use the account ledger"),
+        }
+    }
+
+    #[test]
+    fn coinbase_is_spendable_once_mature_and_not_after_a_reorg_drops_it() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let alice = Address::from_public_key(&key.verifying_key());
+        let bob = Address::from([2; 20]);
+        let carol = Address::from([3; 20]);
+
+        let mut chain = BlockChain::with_params(test_params());
+        let genesis = extend(&mut chain, None, bob, vec![]);
+        let paid = extend(&mut chain, Some(genesis), alice, vec![]);
+        let spend = transfer(&key, carol, 30, 0);
+
+        // Alice's reward at height 1 is still locked in the block at height 2.
+        assert!(matches!(
+            state(&chain).check_txn(&spend),
+            Err(StateError::ImmatureBalance { spendable: 0, .. })
+        ));
+        let second = extend(&mut chain, Some(paid), bob, vec![]);
+
+        // It can be spent in the block at height 3.
+        let spent = extend(&mut chain, Some(second), bob, vec![spend.clone()]);
+        assert_eq!(chain.tip(), Some(spent));
+        assert_eq!(chain.balance_of(&alice), 20);
+        assert_eq!(chain.balance_of(&carol), 30);
+
+        // A longer branch without Alice's reward takes over, and the spend with it.
+        let mut fork = genesis;
+        for _ in 0..4 {
+            fork = extend(&mut chain, Some(fork), bob, vec![]);
+        }
+        assert_eq!(chain.tip(), Some(fork));
+        let update = chain.changes_since(Some(&spent));
+        assert!(update.is_reorg());
+        assert_eq!(chain.balance_of(&alice), 0);
+        assert_eq!(chain.balance_of(&carol), 0);
+        assert!(matches!(
+            state(&chain).check_txn(&spend),
+            Err(StateError::InsufficientBalance { .. })
+        ));
+    }
+
+    #[test]
+    fn immature_spend_makes_the_block_invalid() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let alice = Address::from_public_key(&key.verifying_key());
+        let bob = Address::from([2; 20]);
+
+        let mut chain = BlockChain::with_params(test_params());
+        let genesis = extend(&mut chain, None, bob, vec![]);
+        let paid = extend(&mut chain, Some(genesis), alice, vec![]);
+
+        let block = mine(&chain, Some(paid), bob, vec![transfer(&key, bob, 10, 0)]);
+        assert!(matches!(
+            chain.add_block(block),
+            Err(ChainError::InvalidBlock {
+                source: ValidationError::InvalidStateTransition(StateError::ImmatureBalance { .. }),
+                ..
+            })
+        ));
+        assert_eq!(chain.tip(), Some(paid));
+    }
+
+    #[test]
+    fn state_at_follows_the_canonical_chain_after_a_reorg() {
+        let alice = Address::from([1; 20]);
+        let bob = Address::from([2; 20]);
+        let carol = Address::from([3; 20]);
+
+        let mut chain = BlockChain::with_params(test_params());
+        let genesis = extend(&mut chain, None, bob, vec![]);
+        let paid = extend(&mut chain, Some(genesis), alice, vec![]);
+        extend(&mut chain, Some(paid), alice, vec![]);
+        assert_eq!(chain.state_at(1).unwrap().balance_of(&alice), 50);
+
+        // Carol's longer branch replaces Alice's blocks at heights 1 and 2.
+        let mut fork = genesis;
+        for _ in 0..3 {
+            fork = extend(&mut chain, Some(fork), carol, vec![]);
+        }
+        assert_eq!(chain.tip(), Some(fork));
+
+        let at_genesis = chain.state_at(0).unwrap();
+        assert_eq!(at_genesis.balance_of(&bob), 50);
+        assert_eq!(at_genesis.balance_of(&carol), 0);
+
+        let at_one = chain.state_at(1).unwrap();
+        assert_eq!(at_one.balance_of(&alice), 0);
+        assert_eq!(at_one.balance_of(&carol), 50);
+        assert_eq!(chain.state_at(2).unwrap().balance_of(&carol), 100);
+        assert_eq!(chain.state_at(3).unwrap().state_root(), chain.ledger().state_root());
+        assert!(chain.state_at(4).is_none());
+    }
+
+    #[test]
+    fn zero_bits_are_rejected_without_counting_work() {
+        let mut chain = BlockChain::with_params(test_params());
+        let mut block = mine(&chain, None, Address::from([2; 20]), vec![]);
+        block.block_header.bits = 0;
+        block.block_header.current_hash = block.block_header.hash();
+
+        assert!(matches!(
+            chain.add_block(block),
+            Err(ChainError::InvalidBlock {
+                source: ValidationError::UnexpectedTarget { found: 0, .. },
+                ..
+            })
+        ));
+        assert_eq!(chain.total_work(), U256::ZERO);
+        assert!(chain.is_empty());
+    }
+
+    #[test]
+    fn locator_is_dense_near_the_tip_and_ends_at_genesis() {
+        let mut chain = BlockChain::with_params(test_params());
+        assert!(chain.locator().is_empty());
+
+        let miner = Address::from([2; 20]);
+        let mut hashes = vec![extend(&mut chain, None, miner, vec![])];
+        for _ in 1..30 {
+            hashes.push(extend(&mut chain, hashes.last().copied(), miner, vec![]));
+        }
+
+        let heights = (20..30).rev().chain([18, 14, 6, 0]);
+        assert_eq!(chain.locator(), heights.map(|height| hashes[height]).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn hashes_after_start_at_the_fork_with_the_locator() {
+        let miner = Address::from([2; 20]);
+        let mut chain = BlockChain::with_params(test_params());
+        let mut hashes = vec![extend(&mut chain, None, miner, vec![])];
+        for _ in 1..10 {
+            hashes.push(extend(&mut chain, hashes.last().copied(), miner, vec![]));
+        }
+        // A peer on a branch off height 5 lists its own block first, which isn't on our canonical chain.
+        let side = extend(&mut chain, Some(hashes[5]), Address::from([3; 20]), vec![]);
+        assert_eq!(chain.tip(), Some(hashes[9]));
+        let locator = [side, hashes[5], hashes[0]];
+
+        assert_eq!(chain.hashes_after(&locator, None, 100), hashes[6..].to_vec());
+        assert_eq!(chain.hashes_after(&locator, None, 2), hashes[6..8].to_vec());
+        assert_eq!(chain.hashes_after(&locator, Some(&hashes[7]), 100), hashes[6..8].to_vec());
+        // A stop outside the range doesn't shorten it.
+        assert_eq!(chain.hashes_after(&locator, Some(&hashes[2]), 100), hashes[6..].to_vec());
+        // Nothing in common means the peer gets everything from genesis on.
+        assert_eq!(chain.hashes_after(&[Hash256::ZERO], None, 3), hashes[..3].to_vec());
+        assert!(chain.hashes_after(&[hashes[9]], None, 100).is_empty());
+
+        let headers = chain.headers_after(&locator, None, 2);
+        let header_hashes = headers.iter().map(|header| header.current_hash).collect::<Vec<_>>();
+        assert_eq!(header_hashes, hashes[6..8].to_vec());
+    }
+}
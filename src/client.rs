@@ -1,12 +1,17 @@
-mod transaction;
-mod sender;
-
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use blockchain::hash::Hash256;
+use blockchain::node::Message;
+use blockchain::transaction::{Address, Txn};
+use blockchain::utxo::{OutPoint, TxOut, UtxoTxn};
 use clap::Parser;
+use ed25519_dalek::SigningKey;
+use futures::{SinkExt as _, StreamExt as _};
 use log::{info, warn};
-use crate::sender::MessageSender;
-use serde::{Deserialize, Serialize};
+use rand::rngs::OsRng;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use tokio::net::TcpStream;
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
 
 #[derive(Parser)]
 #[clap(
@@ -18,31 +23,136 @@ struct Cli {
     #[clap(subcommand)]
     command: Command,
 
-    #[clap(long, short, value_parser, value_name = "NUM", default_value_t = 1729)]
+    /// Client port of the node, set with its `-c` option.
+    #[clap(long, short, value_parser, value_name = "NUM", default_value_t = 7291)]
     port: u16,
 
     #[clap(long, short, value_parser, value_name="NUM", default_value_t=IpAddr::V4(Ipv4Addr::LOCALHOST))]
     address: IpAddr,
 }
 
-#[derive(Parser, Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Parser, Debug, Clone, PartialEq, Eq)]
 pub enum Command {
+    /// Generate a new keypair and print its secret key and address
+    Keygen,
+
+    /// Sign a transfer with the given secret key and send it to the node
     Txn {
-        sender: String,
-        receiver: String,
+        secret_key: String,
+        receiver: Address,
         value: u32,
+
+        /// Paid to the miner on top of the value. Higher fees are mined first
+        #[clap(long, short, default_value_t = 0)]
+        fee: u32,
+
+        /// Number of transactions already sent from this key
+        #[clap(long, short, default_value_t = 0)]
+        nonce: u64,
+    },
+
+    /// Spend unspent outputs with the given secret key on a node running the UTXO ledger
+    Spend {
+        secret_key: String,
+
+        /// Output to spend, as <txid>:<index>
+        #[clap(long = "input", short, required = true)]
+        inputs: Vec<OutPoint>,
+
+        /// Output to create, as <address>:<amount>
+        #[clap(long = "output", short, required = true)]
+        outputs: Vec<TxOut>,
+    },
+
+    /// Look up the balance and nonce of an account, checked against the state root of the block
+    Account {
+        address: Address,
+
+        /// Block to look the account up at. Defaults to the node's tip
+        #[clap(long, short)]
+        block: Option<Hash256>,
     },
 }
 
 impl Command {
     pub async fn request(self, address: SocketAddr) -> Result<()> {
-        let mut sender = MessageSender::new();
-        let txn = bincode::serialize(&self)?;
-        sender.send(address, txn.into()).await;
+        match self {
+            Command::Keygen => {
+                let key = SigningKey::generate(&mut OsRng);
+                println!("Secret key: {}", hex::encode(key.to_bytes()));
+                println!("Address: {}", Address::from_public_key(&key.verifying_key()));
+            }
+
+            Command::Txn {
+                secret_key,
+                receiver,
+                value,
+                fee,
+                nonce,
+            } => {
+                let key = parse_secret_key(&secret_key)?;
+
+                let mut txn = Txn::new(&key.verifying_key(), receiver, value, fee, nonce);
+                txn.sign(&key);
+
+                txn.send_to(address).await?;
+                info!("Sent transaction to Node: {}", address);
+            }
+
+            Command::Spend {
+                secret_key,
+                inputs,
+                outputs,
+            } => {
+                let key = parse_secret_key(&secret_key)?;
+
+                let mut txn = UtxoTxn::new(inputs, outputs);
+                for index in 0..txn.inputs.len() {
+                    txn.sign_input(index, &key);
+                }
+
+                info!("Spending with transaction {}", txn.id);
+                txn.send_to(address).await?;
+                info!("Sent transaction to Node: {}", address);
+            }
+
+            Command::Account {
+                address: account,
+                block,
+            } => {
+                let request = Message::GetAccount {
+                    address: account,
+                    block,
+                };
+                if let Some(answer) = query(address, &request).await? {
+                    println!("{}", answer);
+                }
+            }
+        }
         Ok(())
     }
 }
 
+/// Sends `request` to the node and waits for its answer.
+async fn query(address: SocketAddr, request: &Message) -> Result<Option<String>> {
+    let stream = TcpStream::connect(address).await?;
+    let mut framed = Framed::new(stream, LengthDelimitedCodec::new());
+    framed.send(Bytes::from(bincode::serialize(request)?)).await?;
+
+    let answer = framed
+        .next()
+        .await
+        .ok_or_else(|| anyhow!("{} closed the connection", address))??;
+    bincode::deserialize::<Result<Option<String>, String>>(&answer)?.map_err(|e| anyhow!(e))
+}
+
+fn parse_secret_key(secret_key: &str) -> Result<SigningKey> {
+    let secret_key: [u8; 32] = hex::decode(secret_key)?
+        .try_into()
+        .map_err(|_| anyhow!("Secret key must be 32 hex-encoded bytes"))?;
+    Ok(SigningKey::from_bytes(&secret_key))
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
@@ -51,9 +161,8 @@ async fn main() -> Result<()> {
 
     let address = SocketAddr::new(cli.address, cli.port);
 
-    match cli.command.request(address).await {
-        Ok(()) => info!("Sent transaction to Node: {}", address),
-        Err(e) => warn!("Failed to send transaction: {:?}", e),
+    if let Err(e) = cli.command.request(address).await {
+        warn!("Request failed: {:?}", e);
     }
 
     Ok(())
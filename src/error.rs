@@ -1,3 +1,9 @@
+use crate::ban::Offence;
+use crate::hash::Hash256;
+use crate::params::LedgerMode;
+use crate::pow::U256;
+use crate::transaction::Address;
+use crate::utxo::OutPoint;
 use std::net::SocketAddr;
 
 use thiserror::Error;
@@ -24,4 +30,245 @@ pub enum NetworkError {
 
     #[error("Failed to deserialize message")]
     DeserializeError,
+
+    #[error("{0} sent a message before completing the handshake")]
+    HandshakeRequired(SocketAddr),
+
+    #[error("Rejected handshake from {0}")]
+    HandshakeRejected(SocketAddr),
+
+    #[error("Handshake with {0} failed: {1}")]
+    HandshakeFailed(SocketAddr, String),
+
+    #[error("{0} {1}")]
+    Misbehaved(SocketAddr, Offence),
+
+    #[error("{0} is banned")]
+    Banned(SocketAddr),
+
+    #[error("Connection to {0} was closed")]
+    ConnectionClosed(SocketAddr),
+
+    #[error("Queue of messages to {0} is full")]
+    QueueFull(SocketAddr),
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum HandshakeError {
+    #[error("Peer speaks protocol version {version}, at least {min} is required")]
+    UnsupportedVersion { version: u32, min: u32 },
+
+    #[error("Peer is on chain {found}, expected {expected}")]
+    ChainMismatch { expected: Hash256, found: Hash256 },
+
+    #[error("Connected to self")]
+    SelfConnection,
+}
+
+#[derive(Error, Debug)]
+pub enum ChainError {
+    #[error("Block {0} is already in the chain")]
+    AlreadyKnown(Hash256),
+
+    #[error("Block {hash} does not extend a known block (parent {parent})")]
+    OrphanBlock { hash: Hash256, parent: Hash256 },
+
+    #[error("Block {hash} is invalid: {source}")]
+    InvalidBlock {
+        hash: Hash256,
+        source: ValidationError,
+    },
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum SyncError {
+    #[error("Invalid header: {0}")]
+    InvalidHeader(#[from] ValidationError),
+
+    #[error("Received {count} headers, at most {max} are sent at once")]
+    TooManyHeaders { count: usize, max: usize },
+
+    #[error("Peer's headers carry {theirs} work, less than our {ours}")]
+    LessWork { ours: U256, theirs: U256 },
+
+    #[error("Block {hash} announced by {peer} could not be downloaded")]
+    BlockUnavailable { hash: Hash256, peer: SocketAddr },
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ValidationError {
+    #[error("Block version {0} is not supported")]
+    UnsupportedVersion(u32),
+
+    #[error("Header hash is {found}, expected {expected}")]
+    HashMismatch { expected: Hash256, found: Hash256 },
+
+    #[error("Target bits are {found:#010x}, expected {expected:#010x}")]
+    UnexpectedTarget { expected: u32, found: u32 },
+
+    #[error("Header {0} does not extend the headers before it")]
+    UnconnectedHeader(Hash256),
+
+    #[error("Proof of work does not meet target bits {0:#010x}")]
+    InsufficientWork(u32),
+
+    #[error("Timestamp {timestamp} is earlier than median time past {median_time_past}")]
+    TimestampTooOld { timestamp: u64, median_time_past: u64 },
+
+    #[error("Timestamp {timestamp} is too far in the future (max {max})")]
+    TimestampTooNew { timestamp: u64, max: u64 },
+
+    #[error("Index is {found}, expected {expected}")]
+    UnexpectedIndex { expected: u32, found: u32 },
+
+    #[error("Genesis block must point to {expected}, found {found}")]
+    InvalidGenesisParent { expected: Hash256, found: Hash256 },
+
+    #[error("Merkle root is {found}, expected {expected}")]
+    MerkleRootMismatch { expected: Hash256, found: Hash256 },
+
+    #[error("State root is {found}, expected {expected}")]
+    StateRootMismatch { expected: Hash256, found: Hash256 },
+
+    #[error("Block has {count} transactions, at most {max} are allowed")]
+    TooManyTransactions { count: usize, max: usize },
+
+    #[error("Block is {size} bytes, at most {max} are allowed")]
+    BlockTooLarge { size: usize, max: usize },
+
+    #[error("Transaction {0} appears more than once")]
+    DuplicateTransaction(Hash256),
+
+    #[error("Transaction {id} is invalid: {source}")]
+    InvalidTransaction { id: Hash256, source: TxnError },
+
+    #[error("Block can't be applied to the ledger: {0}")]
+    InvalidStateTransition(StateError),
+
+    #[error("Block can't be applied to the UTXO set: {0}")]
+    InvalidUtxoTransition(UtxoError),
+
+    #[error("{0} transactions are not accepted by this chain's ledger")]
+    UnsupportedTransactions(LedgerMode),
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum StateError {
+    #[error("Nonce of {address} is {found}, expected {expected}")]
+    NonceMismatch {
+        address: Address,
+        expected: u64,
+        found: u64,
+    },
+
+    #[error("{address} has a balance of {balance}, can't spend {amount}")]
+    InsufficientBalance {
+        address: Address,
+        balance: u64,
+        amount: u64,
+    },
+
+    #[error("{address} can only spend {spendable} until its coinbase rewards mature, can't spend {amount}")]
+    ImmatureBalance {
+        address: Address,
+        spendable: u64,
+        amount: u64,
+    },
+
+    #[error("Balance of {0} overflows")]
+    BalanceOverflow(Address),
+
+    #[error("Coinbase claims {found}, subsidy and fees add up to {expected}")]
+    InvalidCoinbaseAmount { expected: u64, found: u64 },
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum TxnError {
+    #[error("Address must be 20 hex-encoded bytes")]
+    InvalidAddress,
+
+    #[error("Public key is not a valid Ed25519 key")]
+    InvalidPublicKey,
+
+    #[error("Sender {0} is not derived from the public key")]
+    SenderMismatch(Address),
+
+    #[error("Transaction id {0} does not match its contents")]
+    IdMismatch(Hash256),
+
+    #[error("Signature does not verify")]
+    InvalidSignature,
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("{0:?} is not a hash, expected 32 hex-encoded bytes")]
+pub struct HashError(pub String);
+
+#[derive(Error, Debug)]
+pub enum StorageError {
+    #[error("Record is shorter than its length prefix")]
+    TruncatedRecord,
+
+    #[error("Record checksum does not match its contents")]
+    ChecksumMismatch,
+
+    #[error("Record does not decode to a block")]
+    CorruptRecord,
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum UtxoError {
+    #[error("Transaction {0} has no inputs")]
+    NoInputs(Hash256),
+
+    #[error("Output {0} does not exist or is already spent")]
+    MissingOutput(OutPoint),
+
+    #[error("Output {0} is spent more than once")]
+    DoubleSpend(OutPoint),
+
+    #[error("Key does not unlock output {0}")]
+    LockMismatch(OutPoint),
+
+    #[error("Output {0} already exists")]
+    DuplicateOutput(OutPoint),
+
+    #[error("Transaction {id} spends {outputs} but only has {inputs} in inputs")]
+    OutputsExceedInputs { id: Hash256, inputs: u64, outputs: u64 },
+
+    #[error("Values in transaction {0} overflow")]
+    ValueOverflow(Hash256),
+
+    #[error("Coinbase output {outpoint} can't be spent before block {spendable_at}")]
+    ImmatureCoinbase {
+        outpoint: OutPoint,
+        spendable_at: u32,
+    },
+
+    #[error("Can't parse {0:?}, expected <txid>:<index>")]
+    InvalidOutPoint(String),
+
+    #[error("Can't parse {0:?}, expected <address>:<amount>")]
+    InvalidOutput(String),
+
+    #[error("Coinbase claims {found}, subsidy and fees add up to {expected}")]
+    InvalidCoinbaseAmount { expected: u64, found: u64 },
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum MempoolError {
+    #[error("Transaction {0} is already pending")]
+    AlreadyKnown(Hash256),
+
+    #[error("Mempool is full and fee rate {fee_rate} is too low to evict anything")]
+    Full { fee_rate: u64 },
+
+    #[error("Pending transaction {pending} with the same nonce pays {fee}, a replacement must pay more")]
+    ReplacementUnderpriced { pending: Hash256, fee: u64 },
+
+    #[error("Transaction {0} was replaced by one paying a higher fee")]
+    Replaced(Hash256),
+
+    #[error("Output {outpoint} is already spent by pending transaction {pending}")]
+    DoubleSpend { outpoint: OutPoint, pending: Hash256 },
 }
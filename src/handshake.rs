@@ -0,0 +1,106 @@
+/* Opening exchange of every connection between nodes.
+The connecting node sends its `Version` in a `Hello` before anything else and the listening node answers with its own,
+or with a rejection. Either side drops the connection if the other runs another chain or a protocol version it can't
+talk to, so nothing else a peer sends is processed until both sides have accepted each other. */
+
+use crate::error::HandshakeError;
+use crate::hash::Hash256;
+
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+
+/// Version of the peer protocol spoken by this node.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest protocol version this node still talks to.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// What a node tells a peer about itself when a connection opens.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Version {
+    pub protocol_version: u32,
+    /// Hash of the genesis block, or `None` for a node that doesn't have one yet and follows whichever chain it is given.
+    pub chain_id: Option<Hash256>,
+    /// Height of the node's canonical tip.
+    pub best_height: Option<u32>,
+    /// Random id picked at startup, so a node can tell when it has connected to itself.
+    pub node_id: u64,
+    /// Address the node accepts peer connections on.
+    pub listen_address: SocketAddr,
+}
+
+impl Version {
+    /// Checks that a node announcing `self` can talk to a peer announcing `peer`.
+    pub fn check(&self, peer: &Version) -> Result<(), HandshakeError> {
+        if peer.node_id == self.node_id {
+            return Err(HandshakeError::SelfConnection);
+        }
+
+        if peer.protocol_version < MIN_PROTOCOL_VERSION {
+            return Err(HandshakeError::UnsupportedVersion {
+                version: peer.protocol_version,
+                min: MIN_PROTOCOL_VERSION,
+            });
+        }
+
+        match (self.chain_id, peer.chain_id) {
+            (Some(expected), Some(found)) if expected != found => {
+                Err(HandshakeError::ChainMismatch { expected, found })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(node_id: u64, chain_id: Option<Hash256>) -> Version {
+        Version {
+            protocol_version: PROTOCOL_VERSION,
+            chain_id,
+            best_height: chain_id.map(|_| 0),
+            node_id,
+            listen_address: ([127, 0, 0, 1], 1729 + node_id as u16).into(),
+        }
+    }
+
+    #[test]
+    fn peers_on_the_same_chain_accept_each_other() {
+        let chain = Some(Hash256::digest(b"genesis"));
+        assert_eq!(version(1, chain).check(&version(2, chain)), Ok(()));
+
+        // A node without a chain yet follows whichever one it is given, in both directions.
+        assert_eq!(version(1, None).check(&version(2, chain)), Ok(()));
+        assert_eq!(version(1, chain).check(&version(2, None)), Ok(()));
+    }
+
+    #[test]
+    fn other_chains_old_protocols_and_self_are_rejected() {
+        let ours = version(1, Some(Hash256::digest(b"genesis")));
+
+        let other_chain = version(2, Some(Hash256::digest(b"other")));
+        assert_eq!(
+            ours.check(&other_chain),
+            Err(HandshakeError::ChainMismatch {
+                expected: Hash256::digest(b"genesis"),
+                found: Hash256::digest(b"other"),
+            })
+        );
+
+        let old = Version {
+            protocol_version: MIN_PROTOCOL_VERSION - 1,
+            ..version(2, ours.chain_id)
+        };
+        assert_eq!(
+            ours.check(&old),
+            Err(HandshakeError::UnsupportedVersion {
+                version: MIN_PROTOCOL_VERSION - 1,
+                min: MIN_PROTOCOL_VERSION,
+            })
+        );
+
+        assert_eq!(ours.check(&version(1, ours.chain_id)), Err(HandshakeError::SelfConnection));
+    }
+}
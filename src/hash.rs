@@ -0,0 +1,59 @@
+// 32-byte hashes identifying blocks, transactions and Merkle tree nodes
+use crate::error::HashError;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use std::str::FromStr;
+
+/// SHA-256 digest. Shown and parsed as 64 hex characters.
+#[derive(Clone, Copy, Default, Serialize, Deserialize, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Hash256([u8; 32]);
+
+impl Hash256 {
+    pub const ZERO: Hash256 = Hash256([0; 32]);
+
+    pub fn digest(data: &[u8]) -> Self {
+        Self(Sha256::digest(data).into())
+    }
+
+    /// SHA-256 applied twice, as used for block ids.
+    pub fn double_digest(data: &[u8]) -> Self {
+        Self(Sha256::digest(Sha256::digest(data)).into())
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    pub fn is_zero(&self) -> bool {
+        *self == Self::ZERO
+    }
+}
+
+impl From<[u8; 32]> for Hash256 {
+    fn from(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+}
+
+impl std::fmt::Display for Hash256 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl std::fmt::Debug for Hash256 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl FromStr for Hash256 {
+    type Err = HashError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = hex::decode(s).map_err(|_| HashError(s.to_string()))?;
+        let hash = bytes.try_into().map_err(|_| HashError(s.to_string()))?;
+        Ok(Self(hash))
+    }
+}
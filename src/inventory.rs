@@ -0,0 +1,57 @@
+// Hashes of blocks and transactions announced between peers, so only what a peer lacks is ever sent in full
+use crate::hash::Hash256;
+
+use serde::{Deserialize, Serialize};
+use std::collections::{HashSet, VecDeque};
+
+/// How many announced items a node remembers having received.
+pub const SEEN_HISTORY: usize = 10_000;
+
+/// Something a node can announce by hash and send on request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum InvItem {
+    Block(Hash256),
+    Txn(Hash256),
+    UtxoTxn(Hash256),
+}
+
+/// The most recent items, oldest forgotten first.
+/// Items a node has already accepted are neither requested nor relayed again, which ends relay loops. Invalid copies
+/// are not remembered, so they can't keep the real item out.
+#[derive(Debug, Clone, Default)]
+pub struct SeenSet {
+    items: HashSet<InvItem>,
+    order: VecDeque<InvItem>,
+}
+
+impl SeenSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn contains(&self, item: &InvItem) -> bool {
+        self.items.contains(item)
+    }
+
+    /// Remembers `item`. Returns false if it was already seen.
+    pub fn insert(&mut self, item: InvItem) -> bool {
+        if !self.items.insert(item) {
+            return false;
+        }
+        if self.order.len() >= SEEN_HISTORY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.items.remove(&oldest);
+            }
+        }
+        self.order.push_back(item);
+        true
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+}
@@ -0,0 +1,94 @@
+// The ledger a chain tracks, selected by `ChainParams::ledger`
+use crate::block::Block;
+use crate::error::ValidationError;
+use crate::hash::Hash256;
+use crate::params::{ChainParams, LedgerMode};
+use crate::state::{StateUndo, WorldState};
+use crate::transaction::Address;
+use crate::utxo::{UtxoSet, UtxoUndo};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Ledger {
+    Account(WorldState),
+    Utxo(UtxoSet),
+}
+
+/// Data needed to revert a block from the ledger.
+#[derive(Debug, Clone)]
+pub enum BlockUndo {
+    Account(StateUndo),
+    Utxo(UtxoUndo),
+}
+
+impl Ledger {
+    /// Empty ledger of the kind `params` selects.
+    pub fn new(params: &ChainParams) -> Self {
+        match params.ledger {
+            LedgerMode::Account => Ledger::Account(WorldState::new(params.coinbase_maturity)),
+            LedgerMode::Utxo => Ledger::Utxo(UtxoSet::new(params.coinbase_maturity)),
+        }
+    }
+
+    pub fn mode(&self) -> LedgerMode {
+        match self {
+            Ledger::Account(_) => LedgerMode::Account,
+            Ledger::Utxo(_) => LedgerMode::Utxo,
+        }
+    }
+
+    pub fn balance_of(&self, address: &Address) -> u64 {
+        match self {
+            Ledger::Account(state) => state.balance_of(address),
+            Ledger::Utxo(utxos) => utxos.balance_of(address),
+        }
+    }
+
+    /// Root committed to by the `state_root` of a block header.
+    /// A UTXO set has none: its coinbase outputs are keyed by the hash of the block carrying them.
+    pub fn state_root(&self) -> Hash256 {
+        match self {
+            Ledger::Account(state) => state.state_root(),
+            Ledger::Utxo(_) => Hash256::ZERO,
+        }
+    }
+
+    /// Applies a block whose coinbase may mint `subsidy` on top of the fees,
+    /// rejecting transactions of the kind this ledger doesn't track.
+    pub fn apply_block(&mut self, block: &Block, subsidy: u64) -> Result<BlockUndo, ValidationError> {
+        match self {
+            Ledger::Account(state) => {
+                if !block.body.utxo_txns.is_empty() {
+                    return Err(ValidationError::UnsupportedTransactions(LedgerMode::Utxo));
+                }
+                let undo = state
+                    .apply_block(block, subsidy)
+                    .map_err(ValidationError::InvalidStateTransition)?;
+                Ok(BlockUndo::Account(undo))
+            }
+            Ledger::Utxo(utxos) => {
+                if !block.body.txn_data.is_empty() {
+                    return Err(ValidationError::UnsupportedTransactions(LedgerMode::Account));
+                }
+                let undo = utxos
+                    .apply_block(block, subsidy)
+                    .map_err(ValidationError::InvalidUtxoTransition)?;
+                Ok(BlockUndo::Utxo(undo))
+            }
+        }
+    }
+
+    /// Reverts a block. Returns false if the undo data is for the other kind of ledger.
+    pub fn undo_block(&mut self, undo: &BlockUndo) -> bool {
+        match (self, undo) {
+            (Ledger::Account(state), BlockUndo::Account(undo)) => {
+                state.undo_block(undo);
+                true
+            }
+            (Ledger::Utxo(utxos), BlockUndo::Utxo(undo)) => {
+                utxos.undo_block(undo);
+                true
+            }
+            _ => false,
+        }
+    }
+}
@@ -1,7 +1,25 @@
+pub mod addrbook;
+pub mod ban;
 pub mod block;
 pub mod blockchain;
 pub mod node;
 pub mod transaction;
+pub mod utxo;
 pub mod receiver;
 pub mod sender;
+pub mod state;
 pub mod error;
+pub mod handshake;
+pub mod hash;
+pub mod inventory;
+pub mod ledger;
+pub mod mempool;
+pub mod merkle;
+pub mod miner;
+pub mod params;
+pub mod pow;
+pub mod smt;
+pub mod storage;
+pub mod sync;
+pub mod time;
+pub mod validation;
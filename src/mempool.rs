@@ -0,0 +1,718 @@
+// Pending transactions, ordered by fee rate so miners can fill blocks with the most profitable ones first
+use crate::error::MempoolError;
+use crate::hash::Hash256;
+use crate::state::WorldState;
+use crate::time::now;
+use crate::transaction::{Address, Txn};
+use crate::utxo::{OutPoint, UtxoSet, UtxoTxn};
+
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet, VecDeque};
+
+pub const DEFAULT_MEMPOOL_SIZE: usize = 5000;
+
+/// Default cap on the total serialized size of pending transactions, in bytes.
+pub const DEFAULT_MEMPOOL_BYTES: usize = 32 * 1024 * 1024;
+
+/// Default time a transaction may stay pending before it is dropped, in seconds.
+pub const DEFAULT_MEMPOOL_EXPIRY: u64 = 24 * 60 * 60;
+
+/// How many replaced transaction ids are remembered, so they aren't accepted and rebroadcast again.
+const REPLACED_HISTORY: usize = 1000;
+
+/// Fee per 1000 bytes of serialized transaction.
+pub type FeeRate = u64;
+
+pub fn fee_rate(fee: u64, size: usize) -> FeeRate {
+    fee.saturating_mul(1000) / size.max(1) as u64
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MempoolLimits {
+    /// Most transactions kept pending.
+    pub max_txns: usize,
+    /// Most bytes of serialized transactions kept pending.
+    pub max_bytes: usize,
+    /// Seconds after which a pending transaction is dropped.
+    pub expiry: u64,
+}
+
+impl Default for MempoolLimits {
+    fn default() -> Self {
+        Self {
+            max_txns: DEFAULT_MEMPOOL_SIZE,
+            max_bytes: DEFAULT_MEMPOOL_BYTES,
+            expiry: DEFAULT_MEMPOOL_EXPIRY,
+        }
+    }
+}
+
+/// Counts of transactions that entered and left a pool, by reason.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MempoolMetrics {
+    pub accepted: u64,
+    /// Outbid by a transaction with the same sender and nonce.
+    pub replaced: u64,
+    /// Dropped to make room for a better paying transaction.
+    pub evicted: u64,
+    /// Pending for longer than the expiry.
+    pub expired: u64,
+    /// Included in a block of the canonical chain.
+    pub included: u64,
+    /// No longer valid on top of the canonical chain.
+    pub invalidated: u64,
+}
+
+impl std::fmt::Display for MempoolMetrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "accepted {}, replaced {}, evicted {}, expired {}, included {}, invalidated {}",
+            self.accepted, self.replaced, self.evicted, self.expired, self.included, self.invalidated
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PoolEntry<T> {
+    pub txn: T,
+    pub fee: u64,
+    pub size: usize,
+    /// Unix time the transaction entered the pool.
+    pub added: u64,
+}
+
+impl<T> PoolEntry<T> {
+    fn new(txn: T, fee: u64, size: usize) -> Self {
+        Self {
+            txn,
+            fee,
+            size,
+            added: now(),
+        }
+    }
+
+    pub fn fee_rate(&self) -> FeeRate {
+        fee_rate(self.fee, self.size)
+    }
+}
+
+/// A pending transaction that left the pool to make room for another one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Displaced<T> {
+    /// Lowest paying transaction, dropped because the pool was full.
+    Evicted(T),
+    /// Transaction with the same sender and nonce, outbid by the new one.
+    Replaced(T),
+}
+
+/// Transactions keyed by id with an index ordered by fee rate and a running byte count.
+/// Limits are enforced by the pools built on top of it.
+#[derive(Debug, Clone)]
+struct FeePool<T> {
+    entries: HashMap<Hash256, PoolEntry<T>>,
+    by_fee_rate: BTreeSet<(FeeRate, Hash256)>,
+    bytes: usize,
+    limits: MempoolLimits,
+    metrics: MempoolMetrics,
+}
+
+impl<T> FeePool<T> {
+    fn new(limits: MempoolLimits) -> Self {
+        Self {
+            entries: HashMap::new(),
+            by_fee_rate: BTreeSet::new(),
+            bytes: 0,
+            limits,
+            metrics: MempoolMetrics::default(),
+        }
+    }
+
+    /// Whether adding a transaction of `size` bytes would break a limit.
+    fn is_full(&self, size: usize) -> bool {
+        self.entries.len() >= self.limits.max_txns || self.bytes + size > self.limits.max_bytes
+    }
+
+    fn insert(&mut self, id: Hash256, entry: PoolEntry<T>) {
+        self.bytes += entry.size;
+        self.by_fee_rate.insert((entry.fee_rate(), id));
+        self.entries.insert(id, entry);
+    }
+
+    fn remove(&mut self, id: &Hash256) -> Option<PoolEntry<T>> {
+        let entry = self.entries.remove(id)?;
+        self.bytes -= entry.size;
+        self.by_fee_rate.remove(&(entry.fee_rate(), *id));
+        Some(entry)
+    }
+
+    /// Removes every entry, from the highest fee rate to the lowest.
+    fn drain(&mut self) -> Vec<PoolEntry<T>> {
+        let ids = std::mem::take(&mut self.by_fee_rate);
+        let entries = ids
+            .into_iter()
+            .rev()
+            .filter_map(|(_, id)| self.entries.remove(&id))
+            .collect();
+        self.bytes = 0;
+        entries
+    }
+
+    /// Ids of transactions that have been pending for longer than the expiry.
+    fn expired(&self) -> Vec<Hash256> {
+        let now = now();
+        self.entries
+            .iter()
+            .filter(|(_, entry)| now.saturating_sub(entry.added) > self.limits.expiry)
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    /// Entries from the highest fee rate to the lowest.
+    fn iter(&self) -> impl Iterator<Item = &PoolEntry<T>> {
+        self.by_fee_rate
+            .iter()
+            .rev()
+            .map(|(_, id)| &self.entries[id])
+    }
+}
+
+/// Pool of pending account transactions, queued per sender in nonce order.
+///
+/// A sender's transactions are ready once every nonce between its account nonce and theirs is pending too.
+/// Transactions after a gap wait in the future queue and are never offered to miners until the gap is filled.
+/// Sending another transaction with the same nonce and a higher fee replaces the pending one.
+#[derive(Debug, Clone)]
+pub struct Mempool {
+    pool: FeePool<Txn>,
+    by_sender: HashMap<Address, BTreeMap<u64, Hash256>>,
+    replaced: HashSet<Hash256>,
+    replaced_order: VecDeque<Hash256>,
+}
+
+impl Mempool {
+    pub fn new(limits: MempoolLimits) -> Self {
+        Self {
+            pool: FeePool::new(limits),
+            by_sender: HashMap::new(),
+            replaced: HashSet::new(),
+            replaced_order: VecDeque::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.pool.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pool.entries.is_empty()
+    }
+
+    /// Total serialized size of the pending transactions.
+    pub fn bytes(&self) -> usize {
+        self.pool.bytes
+    }
+
+    pub fn metrics(&self) -> MempoolMetrics {
+        self.pool.metrics
+    }
+
+    pub fn contains(&self, id: &Hash256) -> bool {
+        self.pool.entries.contains_key(id)
+    }
+
+    pub fn get(&self, id: &Hash256) -> Option<&Txn> {
+        self.pool.entries.get(id).map(|entry| &entry.txn)
+    }
+
+    /// Adds a transaction, replacing a pending one with the same sender and nonce if it pays a higher fee,
+    /// and evicting the lowest paying ones while the pool is over its limits.
+    /// Nothing is removed if the transaction is rejected.
+    pub fn insert(&mut self, txn: Txn) -> Result<Vec<Displaced<Txn>>, MempoolError> {
+        if self.contains(&txn.id) {
+            return Err(MempoolError::AlreadyKnown(txn.id));
+        }
+        if self.replaced.contains(&txn.id) {
+            return Err(MempoolError::Replaced(txn.id));
+        }
+
+        let (fee, size) = (txn.fee as u64, txn.size());
+        let entry = PoolEntry::new(txn, fee, size);
+
+        let mut replaced = None;
+        if let Some(pending_id) = self.pending_id(&entry.txn.sender, entry.txn.nonce) {
+            let pending_fee = self.pool.entries[&pending_id].fee;
+            if entry.fee <= pending_fee {
+                return Err(MempoolError::ReplacementUnderpriced {
+                    pending: pending_id,
+                    fee: pending_fee,
+                });
+            }
+            replaced = self.remove_entry(&pending_id);
+        }
+
+        let mut evicted = Vec::new();
+        while self.pool.is_full(entry.size) {
+            match self.eviction_candidate(&entry) {
+                Some(id) => evicted.extend(self.remove_entry(&id)),
+                None => {
+                    for removed in evicted.into_iter().chain(replaced) {
+                        self.insert_entry(removed);
+                    }
+                    return Err(MempoolError::Full {
+                        fee_rate: entry.fee_rate(),
+                    });
+                }
+            }
+        }
+
+        let mut displaced = Vec::new();
+        if let Some(replaced) = replaced {
+            self.pool.metrics.replaced += 1;
+            self.remember_replaced(replaced.txn.id);
+            displaced.push(Displaced::Replaced(replaced.txn));
+        }
+        self.pool.metrics.evicted += evicted.len() as u64;
+        displaced.extend(evicted.into_iter().map(|entry| Displaced::Evicted(entry.txn)));
+
+        self.pool.metrics.accepted += 1;
+        self.insert_entry(entry);
+        Ok(displaced)
+    }
+
+    /// Removes transactions that made it into a block of the canonical chain.
+    pub fn remove_included(&mut self, txns: &[Txn]) {
+        for txn in txns {
+            if self.remove_entry(&txn.id).is_some() {
+                self.pool.metrics.included += 1;
+            }
+        }
+    }
+
+    /// Puts back transactions from blocks that left the canonical chain, oldest first,
+    /// then drops every transaction whose nonce has been used up on top of `state`.
+    pub fn revalidate(&mut self, state: &WorldState, restored: Vec<Txn>) {
+        for txn in restored {
+            // It was valid in a block, so an earlier replacement doesn't stop it from being pending again.
+            self.replaced.remove(&txn.id);
+            let _ = self.insert(txn);
+        }
+
+        let stale = self
+            .pool
+            .entries
+            .values()
+            .filter(|entry| entry.txn.nonce < state.account(&entry.txn.sender).nonce)
+            .map(|entry| entry.txn.id)
+            .collect::<Vec<_>>();
+
+        self.pool.metrics.invalidated += stale.len() as u64;
+        for id in stale {
+            self.remove_entry(&id);
+        }
+    }
+
+    /// Drops transactions pending for longer than the expiry. Later nonces of the same senders go back to the future queue.
+    pub fn expire(&mut self) -> Vec<Txn> {
+        let expired = self
+            .pool
+            .expired()
+            .into_iter()
+            .filter_map(|id| self.remove_entry(&id))
+            .map(|entry| entry.txn)
+            .collect::<Vec<_>>();
+
+        self.pool.metrics.expired += expired.len() as u64;
+        expired
+    }
+
+    /// Pending transactions from the highest fee rate to the lowest.
+    pub fn by_fee_rate(&self) -> impl Iterator<Item = &PoolEntry<Txn>> {
+        self.pool.iter()
+    }
+
+    pub fn txns(&self) -> Vec<Txn> {
+        self.pool.entries.values().map(|entry| entry.txn.clone()).collect()
+    }
+
+    /// First nonce of `sender` that is neither used in `state` nor pending.
+    /// A transaction with a higher nonce goes to the future queue.
+    pub fn next_nonce(&self, sender: &Address, state: &WorldState) -> u64 {
+        let mut nonce = state.account(sender).nonce;
+        if let Some(queue) = self.by_sender.get(sender) {
+            for pending in queue.range(nonce..).map(|(nonce, _)| *nonce) {
+                if pending != nonce {
+                    break;
+                }
+                nonce += 1;
+            }
+        }
+        nonce
+    }
+
+    /// Transactions that can't be mined yet because a nonce before them is missing.
+    pub fn future<'a>(&'a self, state: &'a WorldState) -> impl Iterator<Item = &'a Txn> + 'a {
+        self.by_sender.iter().flat_map(move |(sender, queue)| {
+            let next = self.next_nonce(sender, state);
+            queue
+                .range(next..)
+                .map(move |(_, id)| &self.pool.entries[id].txn)
+        })
+    }
+
+    /// Offers ready transactions to `take`, highest fee rate first but always in nonce order per sender.
+    /// Once `take` refuses one, the sender's later transactions are skipped.
+    pub fn select(&self, state: &WorldState, mut take: impl FnMut(&PoolEntry<Txn>) -> bool) {
+        let mut heads = BinaryHeap::new();
+        for (sender, queue) in &self.by_sender {
+            let nonce = state.account(sender).nonce;
+            if let Some(id) = queue.get(&nonce) {
+                heads.push((self.pool.entries[id].fee_rate(), id));
+            }
+        }
+
+        while let Some((_, id)) = heads.pop() {
+            let entry = &self.pool.entries[id];
+            if !take(entry) {
+                continue;
+            }
+
+            let next = entry.txn.nonce + 1;
+            if let Some(id) = self.by_sender[&entry.txn.sender].get(&next) {
+                heads.push((self.pool.entries[id].fee_rate(), id));
+            }
+        }
+    }
+
+    fn pending_id(&self, sender: &Address, nonce: u64) -> Option<Hash256> {
+        self.by_sender.get(sender)?.get(&nonce).copied()
+    }
+
+    fn insert_entry(&mut self, entry: PoolEntry<Txn>) {
+        self.by_sender
+            .entry(entry.txn.sender)
+            .or_default()
+            .insert(entry.txn.nonce, entry.txn.id);
+        self.pool.insert(entry.txn.id, entry);
+    }
+
+    fn remove_entry(&mut self, id: &Hash256) -> Option<PoolEntry<Txn>> {
+        let entry = self.pool.remove(id)?;
+        if let Some(queue) = self.by_sender.get_mut(&entry.txn.sender) {
+            queue.remove(&entry.txn.nonce);
+            if queue.is_empty() {
+                self.by_sender.remove(&entry.txn.sender);
+            }
+        }
+        Some(entry)
+    }
+
+    /// Lowest paying transaction among the last pending one of every sender, if `entry` pays more.
+    /// Evicting a sender's last transaction never leaves a gap in front of another pending one,
+    /// and an earlier nonce of the new transaction's own sender is never evicted for the same reason.
+    fn eviction_candidate(&self, entry: &PoolEntry<Txn>) -> Option<Hash256> {
+        self.by_sender
+            .values()
+            .filter_map(|queue| queue.values().next_back())
+            .map(|id| &self.pool.entries[id])
+            .filter(|victim| victim.txn.sender != entry.txn.sender || victim.txn.nonce > entry.txn.nonce)
+            .min_by_key(|victim| (victim.fee_rate(), &victim.txn.id))
+            .filter(|victim| entry.fee_rate() > victim.fee_rate())
+            .map(|victim| victim.txn.id)
+    }
+
+    fn remember_replaced(&mut self, id: Hash256) {
+        if self.replaced_order.len() >= REPLACED_HISTORY {
+            if let Some(oldest) = self.replaced_order.pop_front() {
+                self.replaced.remove(&oldest);
+            }
+        }
+        self.replaced.insert(id);
+        self.replaced_order.push_back(id);
+    }
+}
+
+/// Pool of pending UTXO transactions. No two of them may spend the same output.
+#[derive(Debug, Clone)]
+pub struct UtxoPool {
+    pool: FeePool<UtxoTxn>,
+    spends: HashMap<OutPoint, Hash256>,
+}
+
+impl UtxoPool {
+    pub fn new(limits: MempoolLimits) -> Self {
+        Self {
+            pool: FeePool::new(limits),
+            spends: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.pool.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pool.entries.is_empty()
+    }
+
+    /// Total serialized size of the pending transactions.
+    pub fn bytes(&self) -> usize {
+        self.pool.bytes
+    }
+
+    pub fn metrics(&self) -> MempoolMetrics {
+        self.pool.metrics
+    }
+
+    pub fn contains(&self, id: &Hash256) -> bool {
+        self.pool.entries.contains_key(id)
+    }
+
+    pub fn get(&self, id: &Hash256) -> Option<&UtxoTxn> {
+        self.pool.entries.get(id).map(|entry| &entry.txn)
+    }
+
+    /// Adds a transaction paying `fee`, unless it spends an output a pending transaction already spends.
+    /// Evicts the lowest paying transactions while the pool is over its limits, or nothing if it doesn't pay more than them.
+    pub fn insert(&mut self, txn: UtxoTxn, fee: u64) -> Result<Vec<UtxoTxn>, MempoolError> {
+        let size = txn.size();
+        self.insert_entry(PoolEntry::new(txn, fee, size))
+    }
+
+    /// Removes transactions that made it into a block of the canonical chain.
+    pub fn remove_included(&mut self, txns: &[UtxoTxn]) {
+        for txn in txns {
+            if self.remove_entry(&txn.id).is_some() {
+                self.pool.metrics.included += 1;
+            }
+        }
+    }
+
+    /// Puts back transactions from blocks that left the canonical chain, oldest first, then drops every
+    /// pending transaction that spends outputs `utxos` doesn't have, or that conflicts with one kept before it.
+    pub fn revalidate(&mut self, utxos: &UtxoSet, restored: Vec<UtxoTxn>) {
+        let pending = self.pool.drain();
+        self.spends.clear();
+
+        for txn in restored {
+            if let Ok(fee) = utxos.check_txn(&txn) {
+                let _ = self.insert(txn, fee);
+            }
+        }
+
+        for entry in pending {
+            let conflicts = entry
+                .txn
+                .inputs
+                .iter()
+                .any(|input| self.spends.contains_key(&input.previous_output));
+
+            if conflicts || utxos.check_txn(&entry.txn).is_err() || self.pool.is_full(entry.size) {
+                self.pool.metrics.invalidated += 1;
+                continue;
+            }
+            self.add_entry(entry);
+        }
+    }
+
+    /// Drops transactions pending for longer than the expiry.
+    pub fn expire(&mut self) -> Vec<UtxoTxn> {
+        let expired = self
+            .pool
+            .expired()
+            .into_iter()
+            .filter_map(|id| self.remove_entry(&id))
+            .map(|entry| entry.txn)
+            .collect::<Vec<_>>();
+
+        self.pool.metrics.expired += expired.len() as u64;
+        expired
+    }
+
+    pub fn by_fee_rate(&self) -> impl Iterator<Item = &PoolEntry<UtxoTxn>> {
+        self.pool.iter()
+    }
+
+    pub fn txns(&self) -> Vec<UtxoTxn> {
+        self.pool.entries.values().map(|entry| entry.txn.clone()).collect()
+    }
+
+    fn insert_entry(&mut self, entry: PoolEntry<UtxoTxn>) -> Result<Vec<UtxoTxn>, MempoolError> {
+        if self.contains(&entry.txn.id) {
+            return Err(MempoolError::AlreadyKnown(entry.txn.id));
+        }
+
+        for input in &entry.txn.inputs {
+            if let Some(pending) = self.spends.get(&input.previous_output) {
+                return Err(MempoolError::DoubleSpend {
+                    outpoint: input.previous_output.clone(),
+                    pending: *pending,
+                });
+            }
+        }
+
+        let mut evicted = Vec::new();
+        while self.pool.is_full(entry.size) {
+            let lowest = match self.pool.by_fee_rate.first() {
+                Some((lowest_rate, lowest_id)) if entry.fee_rate() > *lowest_rate => *lowest_id,
+                _ => {
+                    for removed in evicted {
+                        self.add_entry(removed);
+                    }
+                    return Err(MempoolError::Full {
+                        fee_rate: entry.fee_rate(),
+                    });
+                }
+            };
+            evicted.extend(self.remove_entry(&lowest));
+        }
+
+        self.pool.metrics.evicted += evicted.len() as u64;
+        self.pool.metrics.accepted += 1;
+        self.add_entry(entry);
+        Ok(evicted.into_iter().map(|entry| entry.txn).collect())
+    }
+
+    fn add_entry(&mut self, entry: PoolEntry<UtxoTxn>) {
+        for input in &entry.txn.inputs {
+            self.spends
+                .insert(input.previous_output.clone(), entry.txn.id);
+        }
+        self.pool.insert(entry.txn.id, entry);
+    }
+
+    fn remove_entry(&mut self, id: &Hash256) -> Option<PoolEntry<UtxoTxn>> {
+        let entry = self.pool.remove(id)?;
+        for input in &entry.txn.inputs {
+            if self.spends.get(&input.previous_output) == Some(&entry.txn.id) {
+                self.spends.remove(&input.previous_output);
+            }
+        }
+        Some(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::SigningKey;
+
+    fn txn(sender: u8, nonce: u64, fee: u32) -> Txn {
+        let key = SigningKey::from_bytes(&[sender; 32]);
+        Txn::new(&key.verifying_key(), Address::from([0xff; 20]), 10, fee, nonce)
+    }
+
+    fn pool_of(max_txns: usize) -> Mempool {
+        Mempool::new(MempoolLimits {
+            max_txns,
+            ..MempoolLimits::default()
+        })
+    }
+
+    #[test]
+    fn replacement_must_pay_a_higher_fee() {
+        let mut pool = pool_of(10);
+        let original = txn(1, 0, 10);
+        pool.insert(original.clone()).unwrap();
+
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let same_fee = Txn::new(&key.verifying_key(), Address::from([0xee; 20]), 10, 10, 0);
+        assert!(matches!(
+            pool.insert(same_fee),
+            Err(MempoolError::ReplacementUnderpriced { fee: 10, .. })
+        ));
+
+        let better = txn(1, 0, 11);
+        assert_eq!(pool.insert(better.clone()).unwrap(), vec![Displaced::Replaced(original.clone())]);
+        assert!(pool.contains(&better.id));
+        assert_eq!(pool.len(), 1);
+        assert_eq!(pool.metrics().replaced, 1);
+
+        // The outbid transaction isn't taken back when it comes round again.
+        assert!(matches!(pool.insert(original), Err(MempoolError::Replaced(_))));
+    }
+
+    #[test]
+    fn full_pool_evicts_the_lowest_fee_rate_for_a_better_one() {
+        let mut pool = pool_of(2);
+        let low = txn(1, 0, 1);
+        let high = txn(2, 0, 5);
+        pool.insert(low.clone()).unwrap();
+        pool.insert(high.clone()).unwrap();
+
+        let middle = txn(3, 0, 3);
+        assert_eq!(pool.insert(middle.clone()).unwrap(), vec![Displaced::Evicted(low.clone())]);
+        assert!(!pool.contains(&low.id));
+        assert_eq!(pool.metrics().evicted, 1);
+
+        // Nothing pays less than a transaction as cheap as the one just evicted.
+        assert!(matches!(pool.insert(txn(4, 0, 1)), Err(MempoolError::Full { .. })));
+        assert!(pool.contains(&high.id) && pool.contains(&middle.id));
+        assert_eq!(pool.len(), 2);
+    }
+
+    #[test]
+    fn eviction_takes_the_last_nonce_of_a_sender() {
+        let mut pool = pool_of(2);
+        let first = txn(1, 0, 1);
+        let second = txn(1, 1, 1);
+        pool.insert(first.clone()).unwrap();
+        pool.insert(second.clone()).unwrap();
+
+        let newcomer = txn(2, 0, 5);
+        assert_eq!(pool.insert(newcomer).unwrap(), vec![Displaced::Evicted(second)]);
+        assert!(pool.contains(&first.id));
+    }
+
+    #[test]
+    fn own_earlier_nonce_is_skipped_for_the_next_cheapest_sender() {
+        let mut pool = pool_of(2);
+        let own = txn(1, 0, 1);
+        let other = txn(2, 0, 3);
+        pool.insert(own.clone()).unwrap();
+        pool.insert(other.clone()).unwrap();
+
+        // Sender 1 pays the least, but evicting its nonce 0 would strand the newcomer behind a gap.
+        let newcomer = txn(1, 1, 5);
+        assert_eq!(pool.insert(newcomer.clone()).unwrap(), vec![Displaced::Evicted(other)]);
+        assert!(pool.contains(&own.id));
+        assert!(pool.contains(&newcomer.id));
+    }
+
+    #[test]
+    fn rejected_replacement_leaves_the_pool_as_it_was() {
+        let mut pool = pool_of(1);
+        let pending = txn(1, 0, 5);
+        pool.insert(pending.clone()).unwrap();
+
+        assert!(pool.insert(txn(1, 0, 4)).is_err());
+        assert!(pool.insert(txn(2, 0, 5)).is_err());
+        assert!(pool.contains(&pending.id));
+        assert_eq!(pool.len(), 1);
+        assert_eq!(pool.bytes(), pending.size());
+    }
+
+    #[test]
+    fn expired_transactions_leave_later_nonces_in_the_future_queue() {
+        let mut pool = Mempool::new(MempoolLimits {
+            expiry: 60,
+            ..MempoolLimits::default()
+        });
+        let (first, second, other) = (txn(1, 0, 5), txn(1, 1, 5), txn(2, 0, 5));
+        for txn in [&first, &second, &other] {
+            pool.insert(txn.clone()).unwrap();
+        }
+        let state = WorldState::new(0);
+        assert_eq!(pool.future(&state).count(), 0);
+
+        pool.pool.entries.get_mut(&first.id).unwrap().added -= 61;
+        pool.pool.entries.get_mut(&other.id).unwrap().added -= 30;
+
+        assert_eq!(pool.expire(), vec![first.clone()]);
+        assert!(!pool.contains(&first.id));
+        assert!(pool.contains(&other.id));
+        assert_eq!(pool.metrics().expired, 1);
+        assert_eq!(pool.future(&state).map(|txn| txn.id).collect::<Vec<_>>(), vec![second.id]);
+        assert_eq!(pool.next_nonce(&first.sender, &state), 0);
+        assert!(pool.expire().is_empty());
+    }
+}
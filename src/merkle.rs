@@ -0,0 +1,216 @@
+/* Merkle tree over the transactions of a block.
+Leaves and internal nodes are hashed with different prefixes, so a node can never pass for a leaf or the other way
+round. A level with an odd number of nodes moves its last node up unchanged instead of pairing it with a copy of
+itself, so no two different transaction lists share a root. Every level is kept, so an inclusion proof for any
+transaction can be read off the tree. */
+
+use crate::hash::Hash256;
+use crate::transaction::Txn;
+use crate::utxo::UtxoTxn;
+
+use serde::{Deserialize, Serialize};
+
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+/// Path from a leaf to the root of a `MerkleTree`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct MerkleProof {
+    /// Position of the leaf among all leaves.
+    pub index: u32,
+    /// Number of leaves in the tree, which tells which nodes on the path moved up without a sibling.
+    pub leaves: u32,
+    /// Sibling of every paired node on the path, starting at the leaf level.
+    pub siblings: Vec<Hash256>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MerkleTree {
+    /// Leaf hashes first, the root last. Empty for a tree without leaves.
+    levels: Vec<Vec<Hash256>>,
+}
+
+impl MerkleTree {
+    /// Tree over leaves already hashed with `MerkleLeaf::leaf_hash`.
+    pub fn new(leaves: Vec<Hash256>) -> Self {
+        if leaves.is_empty() {
+            return Self::default();
+        }
+
+        let mut levels = vec![leaves];
+        while levels[levels.len() - 1].len() > 1 {
+            let level = &levels[levels.len() - 1];
+            let parents = level
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => node_hash(left, right),
+                    _ => pair[0],
+                })
+                .collect();
+            levels.push(parents);
+        }
+        Self { levels }
+    }
+
+    /// Root of the tree, or zero if it has no leaves.
+    pub fn root(&self) -> Hash256 {
+        self.levels.last().map_or(Hash256::ZERO, |level| level[0])
+    }
+
+    pub fn leaves(&self) -> &[Hash256] {
+        self.levels.first().map_or(&[], Vec::as_slice)
+    }
+
+    /// Proof that the leaf at `index` is part of the tree.
+    pub fn proof(&self, index: usize) -> Option<MerkleProof> {
+        if index >= self.leaves().len() {
+            return None;
+        }
+
+        let mut position = index;
+        let mut siblings = Vec::new();
+        for level in &self.levels[..self.levels.len() - 1] {
+            if let Some(sibling) = level.get(position ^ 1) {
+                siblings.push(*sibling);
+            }
+            position /= 2;
+        }
+
+        Some(MerkleProof {
+            index: index as u32,
+            leaves: self.leaves().len() as u32,
+            siblings,
+        })
+    }
+}
+
+/// A transaction as a leaf of the tree. Leaves commit to the full encoding, signatures included.
+pub trait MerkleLeaf {
+    fn leaf_hash(&self) -> Hash256;
+}
+
+impl MerkleLeaf for Txn {
+    fn leaf_hash(&self) -> Hash256 {
+        leaf_hash(&self.encode())
+    }
+}
+
+impl MerkleLeaf for UtxoTxn {
+    fn leaf_hash(&self) -> Hash256 {
+        leaf_hash(&self.encode())
+    }
+}
+
+fn leaf_hash(data: &[u8]) -> Hash256 {
+    Hash256::digest(&[&[LEAF_PREFIX], data].concat())
+}
+
+fn node_hash(left: &Hash256, right: &Hash256) -> Hash256 {
+    let mut bytes = [0u8; 65];
+    bytes[0] = NODE_PREFIX;
+    bytes[1..33].copy_from_slice(left.as_bytes());
+    bytes[33..].copy_from_slice(right.as_bytes());
+    Hash256::digest(&bytes)
+}
+
+/// Checks that `proof` leads from `txn_hash`, the `MerkleLeaf::leaf_hash` of a transaction, to `root`.
+/// Needs nothing but the root from the block header and the hash, not the transaction or the block body.
+pub fn verify_proof(root: &Hash256, txn_hash: &Hash256, proof: &MerkleProof) -> bool {
+    if proof.index >= proof.leaves {
+        return false;
+    }
+
+    let mut position = proof.index;
+    let mut width = proof.leaves;
+    let mut hash = *txn_hash;
+    let mut siblings = proof.siblings.iter();
+
+    while width > 1 {
+        // The last node of an odd level has no sibling and moves up as it is.
+        if position != width - 1 || width.is_multiple_of(2) {
+            let Some(sibling) = siblings.next() else {
+                return false;
+            };
+            hash = if position.is_multiple_of(2) {
+                node_hash(&hash, sibling)
+            } else {
+                node_hash(sibling, &hash)
+            };
+        }
+        position /= 2;
+        width = width.div_ceil(2);
+    }
+
+    siblings.next().is_none() && hash == *root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{Block, Body, GENESIS_PREVIOUS_HASH};
+    use crate::transaction::Address;
+    use ed25519_dalek::SigningKey;
+
+    fn txn(nonce: u64) -> Txn {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let mut txn = Txn::new(&key.verifying_key(), Address::from([2; 20]), 10, 1, nonce);
+        txn.sign(&key);
+        txn
+    }
+
+    #[test]
+    fn proofs_round_trip_for_every_transaction() {
+        for count in 1..=9 {
+            let body = Body {
+                txn_data: (0..count).map(txn).collect(),
+                utxo_txns: Vec::new(),
+            };
+            let block = Block::new(1, GENESIS_PREVIOUS_HASH, body);
+            let root = block.body.merkle_tree().root();
+
+            for txn in &block.body.txn_data {
+                let proof = block.merkle_proof(&txn.id).unwrap();
+                assert!(verify_proof(&root, &txn.leaf_hash(), &proof), "{} of {}", proof.index, count);
+            }
+        }
+    }
+
+    #[test]
+    fn proof_fails_for_another_transaction_or_root() {
+        let body = Body {
+            txn_data: (0..5).map(txn).collect(),
+            utxo_txns: Vec::new(),
+        };
+        let block = Block::new(1, GENESIS_PREVIOUS_HASH, body);
+        let root = block.body.merkle_tree().root();
+        let proof = block.merkle_proof(&block.body.txn_data[2].id).unwrap();
+
+        let leaf = block.body.txn_data[2].leaf_hash();
+        assert!(verify_proof(&root, &leaf, &proof));
+        assert!(!verify_proof(&root, &block.body.txn_data[3].leaf_hash(), &proof));
+        assert!(!verify_proof(&root, &txn(99).leaf_hash(), &proof));
+        // The id leaves the signature out, so it isn't what the tree commits to.
+        assert!(!verify_proof(&root, &block.body.txn_data[2].id, &proof));
+        assert!(!verify_proof(&Hash256::digest(b"root"), &leaf, &proof));
+        assert!(block.merkle_proof(&txn(99).id).is_none());
+    }
+
+    #[test]
+    fn repeated_tail_changes_the_root() {
+        let leaves = (0..6u8).map(|i| Hash256::digest(&[i])).collect::<Vec<_>>();
+        let root = MerkleTree::new(leaves.clone()).root();
+        for tail in 1..=2 {
+            let mut repeated = leaves.clone();
+            repeated.extend_from_slice(&leaves[leaves.len() - tail..]);
+            assert_ne!(MerkleTree::new(repeated).root(), root, "last {} repeated", tail);
+        }
+    }
+
+    #[test]
+    fn odd_node_moves_up_unpaired() {
+        let leaves = (0..3u8).map(|i| Hash256::digest(&[i])).collect::<Vec<_>>();
+        let tree = MerkleTree::new(leaves.clone());
+        assert_eq!(tree.root(), node_hash(&node_hash(&leaves[0], &leaves[1]), &leaves[2]));
+        assert_eq!(MerkleTree::new(Vec::new()).root(), Hash256::ZERO);
+    }
+}
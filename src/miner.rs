@@ -0,0 +1,231 @@
+/* Proof of work search on a dedicated pool of OS threads, so mining never blocks the async runtime.
+Every worker scans its own slice of the nonce range. When a worker runs through its slice, it bumps the
+coinbase extra nonce, which changes the block hash, and scans the slice again. */
+
+use crate::block::Block;
+use crate::pow::U256;
+
+use log::{debug, info, warn};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc as std_mpsc, Arc};
+use std::thread;
+use std::time::Instant;
+use tokio::sync::mpsc;
+
+/// Hashes a worker computes between checks of the cancellation flag.
+const CANCEL_CHECK_INTERVAL: u64 = 4096;
+
+/// Work handed to one worker.
+struct Job {
+    template: Block,
+    target: U256,
+    /// First and last nonce of this worker's slice.
+    nonces: (u32, u32),
+    /// Set when any worker finds the block or a new job replaces this one.
+    cancel: Arc<AtomicBool>,
+}
+
+pub struct Miner {
+    workers: Vec<std_mpsc::Sender<Job>>,
+    cancel: Arc<AtomicBool>,
+    hashes: Arc<AtomicU64>,
+    last_report: (Instant, u64),
+    block_receiver: mpsc::Receiver<Block>,
+}
+
+impl Miner {
+    /// Starts `threads` idle workers. They run until the miner is dropped.
+    pub fn new(threads: usize) -> Self {
+        let (block_sender, block_receiver) = mpsc::channel::<Block>(500);
+        let hashes = Arc::new(AtomicU64::new(0));
+
+        let workers = (0..threads.max(1))
+            .map(|id| {
+                let (job_sender, job_receiver) = std_mpsc::channel::<Job>();
+                let block_sender = block_sender.clone();
+                let hashes = hashes.clone();
+
+                thread::Builder::new()
+                    .name(format!("miner-{id}"))
+                    .spawn(move || {
+                        while let Ok(job) = job_receiver.recv() {
+                            work(job, &hashes, &block_sender);
+                        }
+                    })
+                    .expect("failed to spawn miner thread");
+                job_sender
+            })
+            .collect();
+
+        Self {
+            workers,
+            cancel: Arc::new(AtomicBool::new(true)),
+            hashes,
+            last_report: (Instant::now(), 0),
+            block_receiver,
+        }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Cancels the current search and starts mining `template`, whose `bits` and everything but the
+    /// nonce and coinbase extra nonce must already be set.
+    pub fn start(&mut self, template: Block) {
+        self.stop();
+        self.cancel = Arc::new(AtomicBool::new(false));
+
+        let target = U256::from_compact(template.block_header.bits);
+        let threads = self.workers.len() as u64;
+        let slice = (u32::MAX as u64 + 1) / threads;
+
+        debug!("Mining block {} below {}", template.block_header.index, target);
+
+        for (i, worker) in self.workers.iter().enumerate() {
+            let first = i as u64 * slice;
+            let last = if i as u64 == threads - 1 {
+                u32::MAX as u64
+            } else {
+                first + slice - 1
+            };
+
+            let job = Job {
+                template: template.clone(),
+                target,
+                nonces: (first as u32, last as u32),
+                cancel: self.cancel.clone(),
+            };
+            if worker.send(job).is_err() {
+                warn!("Miner thread {} has stopped", i);
+            }
+        }
+    }
+
+    /// Cancels the current search. Workers notice within `CANCEL_CHECK_INTERVAL` hashes.
+    pub fn stop(&self) {
+        self.cancel.store(true, Ordering::Relaxed);
+    }
+
+    /// Next block found by the workers.
+    pub async fn next_block(&mut self) -> Option<Block> {
+        self.block_receiver.recv().await
+    }
+
+    /// Hashes per second since the last call.
+    pub fn hash_rate(&mut self) -> f64 {
+        let hashes = self.hashes.load(Ordering::Relaxed);
+        let (since, previous) = self.last_report;
+        self.last_report = (Instant::now(), hashes);
+
+        let elapsed = since.elapsed().as_secs_f64();
+        if elapsed == 0.0 {
+            return 0.0;
+        }
+        (hashes - previous) as f64 / elapsed
+    }
+}
+
+impl Drop for Miner {
+    fn drop(&mut self) {
+        // Dropping the job senders ends the worker loops once they finish the current job.
+        self.stop();
+    }
+}
+
+fn work(job: Job, hashes: &AtomicU64, block_sender: &mpsc::Sender<Block>) {
+    let Job {
+        template: mut block,
+        target,
+        nonces: (first, last),
+        cancel,
+    } = job;
+
+    block.block_header.nonce = first;
+    let mut batch = 0;
+
+    loop {
+        if batch == CANCEL_CHECK_INTERVAL {
+            hashes.fetch_add(batch, Ordering::Relaxed);
+            batch = 0;
+            if cancel.load(Ordering::Relaxed) {
+                return;
+            }
+        }
+
+        let block_hash = block.block_header.hash();
+        batch += 1;
+
+        if U256::from(block_hash) <= target {
+            hashes.fetch_add(batch, Ordering::Relaxed);
+            // Only the first worker to find a block for this job reports it.
+            if cancel.swap(true, Ordering::Relaxed) {
+                return;
+            }
+
+            info!("Mined!⚡️");
+            debug!("{}", block_hash);
+            block.block_header.current_hash = block_hash;
+            if let Err(e) = block_sender.blocking_send(block) {
+                warn!("Can't send mined block to receiver: {}", e);
+            }
+            return;
+        }
+
+        if block.block_header.nonce == last {
+            let coinbase = &mut block.block_header.coinbase_txn;
+            coinbase.extra_nonce = coinbase.extra_nonce.wrapping_add(1);
+            block.block_header.nonce = first;
+        } else {
+            block.block_header.nonce += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{Body, GENESIS_PREVIOUS_HASH};
+    use crate::pow::{meets_target, target_from_leading_zeros};
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    fn template(index: u32, difficulty: u8) -> Block {
+        let mut block = Block::new(index, GENESIS_PREVIOUS_HASH, Body::default());
+        block.block_header.bits = target_from_leading_zeros(difficulty).to_compact();
+        block
+    }
+
+    #[tokio::test]
+    async fn workers_find_a_hash_below_the_target() {
+        let mut miner = Miner::new(4);
+        miner.start(template(3, 12));
+
+        let block = timeout(Duration::from_secs(30), miner.next_block()).await.unwrap().unwrap();
+        let header = &block.block_header;
+        assert_eq!(header.index, 3);
+        assert_eq!(header.current_hash, header.hash());
+        assert!(meets_target(&header.current_hash, header.bits));
+    }
+
+    #[tokio::test]
+    async fn new_template_cancels_the_search_for_the_old_one() {
+        let mut miner = Miner::new(2);
+        // Nothing meets a target this hard, so only the second template can produce a block.
+        miner.start(template(1, 200));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        miner.start(template(2, 4));
+
+        let block = timeout(Duration::from_secs(30), miner.next_block()).await.unwrap().unwrap();
+        assert_eq!(block.block_header.index, 2);
+
+        // With the search stopped, the workers go idle and stop counting hashes.
+        miner.start(template(3, 200));
+        miner.stop();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let hashes = miner.hashes.load(Ordering::Relaxed);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(miner.hashes.load(Ordering::Relaxed), hashes);
+        assert!(miner.block_receiver.try_recv().is_err());
+    }
+}
@@ -1,282 +1,801 @@
+use crate::addrbook::{AddressBook, PeerAddress, MAX_ADDR_PER_MESSAGE};
+use crate::ban::{BanList, Offence, BAN_DURATION};
 use crate::block::*;
-use crate::blockchain::BlockChain;
-use crate::transaction::{CoinbaseTxn, Txn};
-use crate::sender::MessageSender;
+use crate::blockchain::{BlockChain, ChainUpdate};
+use crate::transaction::{Address, CoinbaseTxn, Txn};
+use crate::sender::{ConnectionInfo, ConnectionState, Greeting, MessageSender};
 use anyhow::{bail, Result};
-use log::{info, warn, debug};
-use crate::error::NetworkError;
+use log::{debug, info, warn};
+use crate::error::{ChainError, MempoolError, NetworkError, SyncError};
+use crate::handshake::{Version, PROTOCOL_VERSION};
+use crate::hash::Hash256;
+use crate::inventory::{InvItem, SeenSet};
+use crate::ledger::Ledger;
+use crate::mempool::{Displaced, Mempool, MempoolLimits, UtxoPool};
+use crate::miner::Miner;
+use crate::params::ChainParams;
+use crate::receiver::Incoming;
+use crate::storage::{ChainStore, FileStore, MemoryStore};
+use crate::sync::{BlockSync, SyncRequest, MAX_BLOCKS_INV, MAX_HEADERS};
+use crate::utxo::{UtxoTxn, UtxoUndo};
 use rand::{thread_rng, Rng as _};
 use serde::*;
-use sha2::{Digest as _, Sha256};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::net::SocketAddr;
-use tokio::net::TcpStream;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc;
-use tokio::sync::oneshot;
-use tokio::task::JoinHandle;
-use tokio::io::AsyncReadExt as _;
 
-const REWARD: u8 = 50;
+/// How often pending transactions are checked for expiry.
+const MEMPOOL_EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
 
-pub struct Mine {
-    // Why task joinhandle required?
-    // Because we need to have a control over the miner task.
-    // For example, when we need to abort the mining task because another peer has already mined,
-    // we need to abort the mining task running in the node.
-    task: JoinHandle<()>,
+/// How often pending transactions are persisted, if they changed.
+const MEMPOOL_PERSIST_INTERVAL: Duration = Duration::from_secs(30);
 
-    // Channel to send and receiver blocks.
-    // Having them as field so that it can be used anywhere and need not to pass it as a function argument.
-    block_sender: mpsc::Sender<Block>,
-    block_receiver: mpsc::Receiver<Block>,
+/// How often the miner hash rate is logged.
+const HASH_RATE_INTERVAL: Duration = Duration::from_secs(30);
 
-}
-
-impl Mine {
-    pub async fn mine(txns: Vec<Txn>, previous_block: Block) -> Block {
-        let merkle_root = MerkleRoot::from(txns.clone());
-        let mut block = Block::new(previous_block.block_header.current_hash.clone(), txns);
-        block.block_header.merkle_root = merkle_root;
-        block.block_header.nonce = thread_rng().gen::<u32>();
-
-        let difficulty = block.block_header.difficulty as usize;
-        let target: String = vec!["0"; difficulty].join("").into();
-
-        debug!(&target);
-
-        const YIELD_INTERVAL: u32 = 10000;
-        // max iter per session to yield back to the executor who will send abort signal if the current block has been mined.
-        // This will help us rerun miner task with new block and not infinitely work on mining already mined blocks.
+/// Room left in a block template for the header and length prefixes.
+const HEADER_ALLOWANCE: usize = 1024;
 
-        // This yield can be avoided by having the Node sending a mined signal using a mpsc channel.
-        // As receiver end cannot be sent outside the self(which is the struct Mine) due to uncertainty in lifetime,
-        // we occasionally make this thread yield occasionally to the executor.
-        loop {
-            if block.block_header.nonce % YIELD_INTERVAL == 0 {
-                tokio::task::yield_now().await;
-            }
-            let block_hash = BlockChain::hash_block(block.clone());
-
-            let hash_to_bits = block_hash.iter().fold(String::new(), |acc, byte| {
-                let bits = format!("{byte:0>8b}");
-                acc + bits.as_str()
-            });
+/// Most blocks kept while waiting for their parent to arrive.
+const MAX_ORPHAN_BLOCKS: usize = 100;
 
-            if hash_to_bits.starts_with(target.as_str()) {
-                dbg!(hash_to_bits);
-                info!("{}", format!("Mined!⚡️"));
-                block.block_header.coinbase_txn.amount = REWARD;
-                block.block_header.coinbase_txn.validator =
-                    format!("0x{}", thread_rng().gen::<u32>()); // TODO: Node network address should be added
+/// How often a running sync checks for timeouts and requests more blocks.
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
 
-                let mut hasher = Sha256::new();
-                hasher.update(&serde_json::to_string(&block).unwrap().as_bytes());
+/// How often the progress of a running sync is logged.
+const SYNC_PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
 
-                let hash = hex::encode(hasher.finalize().as_slice().to_owned());
+/// How often free outbound slots are filled from the address book, which is then persisted.
+const PEER_INTERVAL: Duration = Duration::from_secs(10);
 
-                block.block_header.current_hash = hash;
-
-                return block;
-            }
+/// Most connections a node opens to peers of its own choosing.
+const MAX_OUTBOUND: usize = 8;
 
-            block.block_header.nonce += 1;
-        }
-    }
+/// Time an outbound peer has to answer `GetAddr` before the connection counts as failed.
+const ADDR_TIMEOUT: Duration = Duration::from_secs(15);
 
-    pub fn mine_genesis() -> Block {
-        let nonce = thread_rng().gen::<u32>();
-        let block_header = BlockHeader {
-            timestamp: std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_secs(),
-            index: 0,
-            previous_hash: "00000".to_string(),
-            current_hash: String::new(),
-            coinbase_txn: CoinbaseTxn::new(),
-            merkle_root: MerkleRoot::new(),
-            nonce,
-            difficulty: DIFFICULTY,
-        };
-        let body = Body { txn_data: vec![] };
+/// Messages between nodes. Their `from` fields are only what the sender claims: answers go to the listen address
+/// given in the handshake of the connection a message came in on.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Message {
+    /// Opens every connection between nodes, see `handshake`.
+    Hello(Version),
 
-        let mut block = Block { block_header, body };
+    Txn(Txn),
 
-        let merkle_root = MerkleRoot::from(block.body.txn_data.clone());
+    UtxoTxn(UtxoTxn),
 
-        block.block_header.merkle_root = merkle_root;
+    /// Announces blocks and transactions by hash. Peers ask for the ones they lack with `GetData`.
+    Inv {
+        from: SocketAddr,
+        items: Vec<InvItem>,
+    },
 
-        let difficulty = block.block_header.difficulty as usize;
-        let target: String = vec!["0"; difficulty].join("").into();
+    /// Asks for the blocks and transactions behind announced hashes. Transactions are sent back as `Txn` and `UtxoTxn`.
+    GetData {
+        from: SocketAddr,
+        items: Vec<InvItem>,
+    },
 
-        loop {
-            let block_hash = BlockChain::hash_block(block.clone());
+    Block {
+        from: SocketAddr,
+        block: Block,
+    },
 
-            let hash_to_bits = block_hash.iter().fold(String::new(), |acc, byte| {
-                let bits = format!("{byte:0>8b}");
-                acc + bits.as_str()
-            });
+    /// Asks for the headers of the canonical chain after the first block of `locator` it has, up to `stop` if given.
+    GetHeaders {
+        from: SocketAddr,
+        locator: Vec<Hash256>,
+        stop: Option<Hash256>,
+    },
 
-            if hash_to_bits.starts_with(target.as_str()) {
-                info!("{}", format!("Mined genesis!👀🎉"));
-                block.block_header.coinbase_txn.amount = REWARD;
-                block.block_header.coinbase_txn.validator =
-                    format!("0x{}", thread_rng().gen::<u32>());
+    /// Up to `MAX_HEADERS` headers in chain order. Fewer means there are no more.
+    Headers {
+        from: SocketAddr,
+        headers: Vec<BlockHeader>,
+    },
 
-                let mut hasher = Sha256::new();
-                hasher.update(&serde_json::to_string(&block).unwrap().as_bytes());
+    /// Like `GetHeaders`, but answered with an `Inv` of up to `MAX_BLOCKS_INV` block hashes,
+    /// so the blocks a node lacks can be fetched with `GetData`.
+    GetBlocks {
+        from: SocketAddr,
+        locator: Vec<Hash256>,
+        stop: Option<Hash256>,
+    },
 
-                let hash = hex::encode(hasher.finalize().as_slice().to_owned());
+    /// Asks a peer for the addresses of other nodes. Sent first on every outbound connection.
+    GetAddr {
+        from: SocketAddr,
+    },
 
-                block.block_header.current_hash = hash;
+    /// Up to `MAX_ADDR_PER_MESSAGE` addresses of nodes the sender knows, most recently seen first.
+    Addr {
+        from: SocketAddr,
+        addresses: Vec<PeerAddress>,
+    },
 
-                return block;
-            }
+    /// Asks for an account as of a block, or the canonical tip if `None`. Only clients may send it.
+    GetAccount {
+        address: Address,
+        block: Option<Hash256>,
+    },
+}
 
-            block.block_header.nonce += 1;
-        }
+impl Message {
+    pub fn is_hello(&self) -> bool {
+        matches!(self, Message::Hello(_))
     }
 }
 
+/// Answer to a peer message, written back on the connection it came in on.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum Message {
-    Txn {
-        id: String,
-        sender: String,
-        receiver: String,
-        amount: u32,
-    },
+pub enum Reply {
+    Ack,
+    /// Accepts a `Hello` with the version of the answering node.
+    Version(Version),
+    /// Refuses a `Hello`. The connection is closed after this.
+    Reject(String),
+}
 
-    GetState {
-        receiver: SocketAddr,
-    },
+impl Reply {
+    pub fn is_accepted(&self) -> bool {
+        !matches!(self, Reply::Reject(_))
+    }
+}
 
-    ShareState {
-        from: SocketAddr,
-        peers: HashSet<SocketAddr>,
-        state: BlockChain,
-    },
+/// Transaction or account lookup sent by a client along with the channel to answer it on.
+pub type ClientRequest = Incoming<Message, Result<Option<String>, String>>;
+
+#[derive(Debug, Clone)]
+pub struct NodeConfig {
+    pub address: SocketAddr,
+    /// Nodes added to the address book at startup. The chain is synced from the first.
+    pub seeds: Vec<SocketAddr>,
+    /// Directory to persist the chain and mempool in. Nothing is persisted if `None`.
+    pub data_dir: Option<PathBuf>,
+    pub params: ChainParams,
+    /// Size and age limits for pending transactions.
+    pub mempool: MempoolLimits,
+    /// Number of threads searching for proof of work.
+    pub miner_threads: usize,
+    /// Address the coinbase of mined blocks pays to. A random one is generated if `None`.
+    pub miner_address: Option<Address>,
+    /// Whether peers on loopback addresses are never scored for misbehaving, for test networks on one host.
+    pub exempt_loopback: bool,
 }
 
 pub struct Node {
     address: SocketAddr,
     sender: MessageSender, // Receiver end of the channel is embedded in MessageSender.
+    /// Peers blocks and transactions are relayed to: outbound ones and those that connected to us.
     peers: HashSet<SocketAddr>,
-    mempool: HashSet<Txn>,
+    /// Listen address each peer gave in its handshake, by the address of the connection it came in on. Answers go
+    /// there rather than to the `from` of a message, which the sender is free to make up.
+    sessions: HashMap<SocketAddr, SocketAddr>,
+    /// Every node heard of, persisted along with the chain.
+    addresses: AddressBook,
+    /// Peers this node chose to connect to, with when they were asked for addresses if they haven't answered yet.
+    outbound: HashMap<SocketAddr, Option<Instant>>,
+    mempool: Mempool,
+    /// Whether the mempool changed since it was last persisted.
+    mempool_changed: bool,
+    utxo_pool: UtxoPool,
     state: BlockChain,
-    miner: Mine,
+    store: Box<dyn ChainStore>,
+    miner: Miner,
+    miner_address: Address,
+    /// Random id sent in the handshake to detect connections to self.
+    node_id: u64,
+    /// Blocks and transactions received lately, so they are neither requested nor relayed twice.
+    seen: SeenSet,
+    /// Blocks asked for lately. Peers sending any other block are misbehaving.
+    requested: SeenSet,
+    /// Misbehavior scores and bans of peers, shared with the receiver of peer connections.
+    bans: BanList,
+    /// Blocks whose parent hasn't arrived yet, by hash.
+    orphans: HashMap<Hash256, Block>,
+    /// Headers-first download of the chain of a peer that is ahead. Nothing is mined while it runs.
+    sync: BlockSync,
 }
 
 impl Node {
-    pub async fn new(address: SocketAddr, seed: Option<SocketAddr>) -> Result<Self> {      
-        let mut peers = HashSet::<SocketAddr>::with_capacity(10);
-
-        if let Some(seed) = seed {
-            peers.insert(seed);
-        }
-
-        let (block_sender, block_receiver) = mpsc::channel::<Block>(500);
-
-        match seed {
-            Some(node) => {
-                info!(
-                    "Syncing with latest state of Blockchain, Seed node: {}",
-                    node
-                );
-                let mut sender = MessageSender::new();
-
-                let get_latest_state = Message::GetState { receiver: address };
-
-                match bincode::serialize(&get_latest_state) {
-                    Ok(bytes) => {
-
-                        sender.send(node, bytes.into()).await;
-
-                        let mut node_connect = TcpStream::connect(node).await.unwrap();
-
-                        let mut response = Vec::new();
-
-                        node_connect.read_to_end(&mut response).await?;
-                        match bincode::deserialize(&response) {
-                            Ok(response) => match response {
-                                Message::ShareState { from, peers, state } => {
-                                    info!("Received State from {}", from);
-                                    return Ok(Self {
-                                        address,
-                                        sender,
-                                        peers,
-                                        mempool: HashSet::new(),
-                                        state,
-                                        miner: Mine {
-                                            task: tokio::spawn(async {}),
-                                            block_sender,
-                                            block_receiver,
-                                        },
-                                    });
-                                }
-                                Message::GetState { .. } | Message::Txn { .. } => {
-                                    return Err(NetworkError::BootNodeReceiveError(node).into());
-                                }
-                            },
-                            Err(_) => {
-                                return Err(NetworkError::BootNodeReceiveError(node).into());
-                            }
+    pub async fn new(config: NodeConfig) -> Result<Self> {
+        let NodeConfig {
+            address,
+            seeds,
+            data_dir,
+            params,
+            mempool,
+            miner_threads,
+            miner_address,
+            exempt_loopback,
+        } = config;
+
+        let miner_address = miner_address.unwrap_or_else(|| {
+            let address = Address::from(thread_rng().gen::<[u8; 20]>());
+            warn!("No miner address set, paying block rewards to {}", address);
+            address
+        });
+
+        let store: Box<dyn ChainStore> = match data_dir {
+            Some(dir) => Box::new(FileStore::open(dir)?),
+            None => Box::new(MemoryStore::new()),
+        };
+
+        let mut node = Self {
+            address,
+            sender: MessageSender::new(),
+            peers: HashSet::new(),
+            sessions: HashMap::new(),
+            addresses: AddressBook::new(),
+            outbound: HashMap::new(),
+            mempool: Mempool::new(mempool),
+            mempool_changed: false,
+            utxo_pool: UtxoPool::new(mempool),
+            state: BlockChain::with_params(params),
+            store,
+            miner: Miner::new(miner_threads),
+            miner_address,
+            node_id: thread_rng().gen(),
+            seen: SeenSet::new(),
+            requested: SeenSet::new(),
+            bans: BanList::new(),
+            orphans: HashMap::new(),
+            sync: BlockSync::new(),
+        };
+
+        node.load_from_store()?;
+        node.bans.set_exempt_loopback(exempt_loopback);
+        node.refresh_greeting();
+
+        for seed in &seeds {
+            node.addresses.mark_seen(*seed);
+        }
+        if let Some(seed) = seeds.first() {
+            info!("Syncing with seed node {}", seed);
+            node.sync.start(*seed);
+        }
+
+        Ok(node)
+    }
+
+    /// Rebuilds the block tree, mempool, address book and bans from what was persisted before the last shutdown.
+    fn load_from_store(&mut self) -> Result<()> {
+        for block in self.store.blocks()? {
+            if let Err(e) = self.state.add_block(block) {
+                warn!("Stopped loading stored chain: {}", e);
+                break;
+            }
+        }
+
+        let included = self
+            .state
+            .all_blocks_in_longest_chain()
+            .into_iter()
+            .flat_map(|block| block.body.txn_data)
+            .collect::<HashSet<_>>();
+
+        for txn in self.store.load_mempool()? {
+            if !included.contains(&txn) {
+                let _ = self.mempool.insert(txn);
+            }
+        }
+        if let Ledger::Account(ledger) = self.state.ledger() {
+            self.mempool.revalidate(ledger, Vec::new());
+        }
+
+        if let Some(height) = self.state.height() {
+            info!(
+                "Loaded chain at height {} with {} pending transactions",
+                height,
+                self.mempool.len()
+            );
+        }
+
+        self.addresses = AddressBook::from_entries(self.store.load_addresses()?);
+        if !self.addresses.is_empty() {
+            info!("Loaded {} peer addresses", self.addresses.len());
+        }
+
+        self.bans = BanList::from_bans(self.store.load_bans()?);
+        Ok(())
+    }
+
+    /// Bans of this node, for the receiver of its peer connections to enforce.
+    pub fn ban_list(&self) -> BanList {
+        self.bans.clone()
+    }
+
+    /// States of the connections this node opened to its peers.
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        self.sender.connections()
+    }
+
+    /// What this node announces about itself in handshakes.
+    pub fn version(&self) -> Version {
+        Version {
+            protocol_version: PROTOCOL_VERSION,
+            chain_id: self.state.canonical_hash_at(0),
+            best_height: self.state.height(),
+            node_id: self.node_id,
+            listen_address: self.address,
+        }
+    }
+
+    /// Greets new outgoing connections with the current version, which changes with the tip.
+    fn refresh_greeting(&mut self) {
+        let ours = self.version();
+        let hello = bincode::serialize(&Message::Hello(ours.clone()))
+            .expect("a version always serializes");
+
+        self.sender.set_greeting(Greeting {
+            hello: hello.into(),
+            accept: Arc::new(move |reply| match bincode::deserialize(reply) {
+                Ok(Reply::Version(peer)) => ours.check(&peer).map_err(|e| e.to_string()),
+                Ok(Reply::Reject(reason)) => Err(reason),
+                _ => Err("unexpected reply to handshake".to_string()),
+            }),
+        });
+    }
+
+    /// Answers the handshake of a peer that just connected.
+    fn accept_peer(&mut self, peer: Version, source: SocketAddr) -> Reply {
+        let ours = self.version();
+        if let Err(e) = ours.check(&peer) {
+            warn!("Rejected peer {}: {}", peer.listen_address, e);
+            return Reply::Reject(e.to_string());
+        }
+
+        info!(
+            "Accepted peer {} running protocol {} at height {:?}",
+            peer.listen_address, peer.protocol_version, peer.best_height
+        );
+        if peer.listen_address != self.address {
+            self.peers.insert(peer.listen_address);
+            self.addresses.mark_seen(peer.listen_address);
+            // A peer reconnecting replaces its earlier connection.
+            self.sessions.retain(|_, listen| *listen != peer.listen_address);
+            self.sessions.insert(source, peer.listen_address);
+        }
+
+        // A peer claiming to be more than a block ahead may have blocks gossip won't bring. The claim is only a
+        // reason to look at its headers: the sync fetches blocks, and mining stops, once they prove more work.
+        let behind = match (peer.best_height, ours.best_height) {
+            (Some(theirs), Some(height)) => theirs > height + 1,
+            (Some(_), None) => true,
+            (None, _) => false,
+        };
+        if behind && !self.sync.is_syncing() {
+            info!("Syncing with {}, which claims height {:?}", peer.listen_address, peer.best_height);
+            self.sync.start(peer.listen_address);
+        }
+        Reply::Version(ours)
+    }
+
+    /// Adds a block to the tree and persists it if it was not already known.
+    fn add_block(&mut self, block: Block) -> Result<ChainUpdate, ChainError> {
+        let stored = block.clone();
+        let update = self.state.add_block(block)?;
+
+        if let Err(e) = self.store.append_block(&stored) {
+            warn!("Failed to persist block {}: {}", stored.block_header.current_hash, e);
+        }
+        Ok(update)
+    }
+
+    /// Adds a block a peer sent on the connection from `source`, along with any orphans that were waiting for it,
+    /// and relays the blocks that join the canonical chain. The blocks between our chain and an orphan are requested
+    /// from `peer`, the listen address of that connection.
+    async fn receive_block(&mut self, peer: SocketAddr, source: SocketAddr, block: Block) {
+        let hash = block.block_header.current_hash;
+        if !self.requested.contains(&InvItem::Block(hash)) {
+            self.punish(source, Offence::UnsolicitedData);
+            return;
+        }
+
+        if self.sync.expects(&hash) {
+            self.sync.receive_block(block, source);
+            self.connect_synced_blocks().await;
+            self.drive_sync().await;
+            return;
+        }
+
+        // Only accepted blocks are remembered, so a corrupted copy can't stop the real block being fetched.
+        if self.has(&InvItem::Block(hash)) {
+            return;
+        }
+
+        let parent = block.block_header.previous_hash;
+        if block.block_header.index > 0 && !self.state.contains(&parent) {
+            self.add_orphan(block);
+            if !self.sync.is_ahead() {
+                info!("Block {} is an orphan, asking {} for the blocks before it", hash, peer);
+                let request = Message::GetBlocks {
+                    from: self.address,
+                    locator: self.state.locator(),
+                    stop: Some(hash),
+                };
+                self.send_to(peer, request).await;
+            }
+            return;
+        }
+
+        let old_tip = self.state.tip();
+        let received = hash;
+        let mut blocks = vec![block];
+        while let Some(block) = blocks.pop() {
+            let hash = block.block_header.current_hash;
+            match self.add_block(block) {
+                Ok(_) => {
+                    self.seen.insert(InvItem::Block(hash));
+                    blocks.extend(self.take_orphans(&hash));
+                }
+                Err(ChainError::AlreadyKnown(_)) => {}
+                Err(e) => {
+                    warn!("Rejected block {} from {}: {}", hash, peer, e);
+                    // Orphans waiting for the block may have come from other peers.
+                    if let (true, ChainError::InvalidBlock { source: error, .. }) = (hash == received, &e) {
+                        if let Some(offence) = Offence::for_invalid_block(error) {
+                            self.punish(source, offence);
                         }
                     }
+                }
+            }
+        }
+
+        let update = self.state.changes_since(old_tip.as_ref());
+        if update.tip_changed() {
+            self.update_state(update).await;
+        }
+    }
 
-                    Err(_) => {
-                        return Err(NetworkError::DeserializeError.into());
+    /// Adds the downloaded blocks that are next in line to the tree. An invalid one ends the sync and counts against
+    /// the peer that sent it.
+    async fn connect_synced_blocks(&mut self) {
+        let old_tip = self.state.tip();
+        while let Some((block, source)) = self.sync.next_block() {
+            let hash = block.block_header.current_hash;
+            match self.add_block(block) {
+                Ok(_) | Err(ChainError::AlreadyKnown(_)) => {}
+                Err(e) => {
+                    warn!("Stopped syncing, block {} from {} is invalid: {}", hash, source, e);
+                    if let ChainError::InvalidBlock { source: error, .. } = &e {
+                        if let Some(offence) = Offence::for_invalid_block(error) {
+                            self.punish(source, offence);
+                        }
                     }
+                    self.sync.abort();
+                    self.finish_sync();
+                    break;
                 }
             }
-            None => {
-                return Ok(Self {
-                    address,
-                    sender: MessageSender::new(),
-                    peers,
-                    mempool: HashSet::new(),
-                    state: BlockChain::new(),
-                    miner: Mine {
-                        task: tokio::spawn(async {}),
-                        block_sender,
-                        block_receiver,
+        }
+
+        let update = self.state.changes_since(old_tip.as_ref());
+        if update.tip_changed() {
+            self.update_state(update).await;
+        }
+    }
+
+    /// Sends the requests the sync needs next, and starts mining once it is done.
+    async fn drive_sync(&mut self) {
+        let was_syncing = self.sync.is_syncing();
+        let peers = self.peers.iter().copied().collect::<Vec<_>>();
+
+        let requests = match self.sync.poll(&self.state, &peers) {
+            Ok(requests) => requests,
+            Err(e) => {
+                warn!("Stopped syncing: {}", e);
+                if let (SyncError::BlockUnavailable { peer, .. }, Some(offence)) =
+                    (&e, Offence::for_sync_error(&e))
+                {
+                    self.punish(*peer, offence);
+                }
+                vec![]
+            }
+        };
+        for request in requests {
+            let (peer, message) = match request {
+                SyncRequest::Headers { peer, locator } => (
+                    peer,
+                    Message::GetHeaders {
+                        from: self.address,
+                        locator,
+                        stop: None,
                     },
-                });
+                ),
+                SyncRequest::Blocks { peer, hashes } => (
+                    peer,
+                    Message::GetData {
+                        from: self.address,
+                        items: hashes.into_iter().map(InvItem::Block).collect(),
+                    },
+                ),
+            };
+            self.send_to(peer, message).await;
+        }
+
+        if was_syncing && !self.sync.is_syncing() {
+            info!("Synced to height {:?}", self.state.height());
+            self.finish_sync();
+        }
+    }
+
+    /// Picks up where the node left off before the sync, however the sync ended.
+    fn finish_sync(&mut self) {
+        self.refresh_greeting();
+        self.run_miner();
+    }
+
+    fn log_sync_progress(&self) {
+        let progress = self.sync.progress();
+        info!(
+            "Syncing: height {:?}, {} of {} blocks downloaded, best header {:?}, {} blocks in flight",
+            self.state.height(),
+            progress.connected,
+            progress.headers,
+            progress.best_header,
+            progress.in_flight
+        );
+    }
+
+    fn add_orphan(&mut self, block: Block) {
+        if self.orphans.len() >= MAX_ORPHAN_BLOCKS {
+            if let Some(evicted) = self.orphans.keys().next().copied() {
+                self.orphans.remove(&evicted);
             }
         }
+        self.orphans.insert(block.block_header.current_hash, block);
+    }
+
+    /// Removes and returns the orphans whose parent is `parent`.
+    fn take_orphans(&mut self, parent: &Hash256) -> Vec<Block> {
+        let children = self
+            .orphans
+            .values()
+            .filter(|block| block.block_header.previous_hash == *parent)
+            .map(|block| block.block_header.current_hash)
+            .collect::<Vec<_>>();
+        children
+            .iter()
+            .filter_map(|hash| self.orphans.remove(hash))
+            .collect()
+    }
+
+    /// Whether an announced item was already received, so there is no need to ask for it.
+    fn has(&self, item: &InvItem) -> bool {
+        self.seen.contains(item)
+            || match item {
+                InvItem::Block(hash) => self.state.contains(hash) || self.orphans.contains_key(hash),
+                InvItem::Txn(id) => self.mempool.contains(id),
+                InvItem::UtxoTxn(id) => self.utxo_pool.contains(id),
+            }
+    }
+
+    /// Drops transactions that have been pending for longer than the mempool expiry.
+    fn expire_mempool(&mut self) {
+        let expired = self.mempool.expire().len() + self.utxo_pool.expire().len();
+        if expired > 0 {
+            info!("Expired {} pending transactions", expired);
+            self.mempool_changed = true;
+            self.log_mempool();
+        }
+    }
+
+    fn log_mempool(&self) {
+        info!(
+            "Mempool: {} transactions ({} bytes): {}",
+            self.mempool.len(),
+            self.mempool.bytes(),
+            self.mempool.metrics()
+        );
+        info!(
+            "UTXO pool: {} transactions ({} bytes): {}",
+            self.utxo_pool.len(),
+            self.utxo_pool.bytes(),
+            self.utxo_pool.metrics()
+        );
+    }
+
+    /// Saves the mempool if it changed since it was last saved.
+    fn persist_mempool(&mut self) {
+        if !self.mempool_changed {
+            return;
+        }
+        self.mempool_changed = false;
+
+        let txns = self.mempool.txns();
+        if let Err(e) = self.store.save_mempool(&txns) {
+            warn!("Failed to persist mempool: {}", e);
+        }
+    }
+
+    /// Saves the address book if it changed since it was last saved.
+    fn persist_addresses(&mut self) {
+        if !self.addresses.take_changed() {
+            return;
+        }
+
+        let entries = self.addresses.entries();
+        if let Err(e) = self.store.save_addresses(&entries) {
+            warn!("Failed to persist address book: {}", e);
+        }
+    }
+
+    /// Saves the bans if any were added or lifted since they were last saved, here or by the receiver.
+    fn persist_bans(&mut self) {
+        if !self.bans.take_changed() {
+            return;
+        }
+
+        let bans = self.bans.bans();
+        if let Err(e) = self.store.save_bans(&bans) {
+            warn!("Failed to persist bans: {}", e);
+        }
+    }
+
+    /// Counts `offence` against the peer on the connection from `source`. A peer that gets banned is dropped,
+    /// and the receiver closes its connections.
+    fn punish(&mut self, source: SocketAddr, offence: Offence) {
+        warn!("{}", NetworkError::Misbehaved(source, offence));
+        if !self.bans.punish(source.ip(), offence) {
+            return;
+        }
+
+        warn!("Banned {} for {} hours", source.ip(), BAN_DURATION.as_secs() / 3600);
+        let ip = source.ip();
+        self.peers.retain(|peer| peer.ip() != ip);
+        self.outbound.retain(|peer, _| peer.ip() != ip);
+        self.sessions.retain(|source, _| source.ip() != ip);
+        self.persist_bans();
+    }
+
+    fn log_connections(&self) {
+        let (mut open, mut connecting, mut waiting, mut queued) = (0, 0, 0, 0);
+        for connection in self.connections() {
+            match connection.state {
+                ConnectionState::Connected => open += 1,
+                ConnectionState::Connecting => connecting += 1,
+                ConnectionState::Backoff { .. } => waiting += 1,
+                ConnectionState::Closed => {}
+            }
+            queued += connection.queued;
+        }
+        info!(
+            "Connections: {} open, {} connecting, {} waiting to reconnect, {} messages queued",
+            open, connecting, waiting, queued
+        );
+    }
+
+    /// Drops peers whose connections were given up on, banned peers and outbound peers that never answered `GetAddr`,
+    /// connects to the best known addresses that aren't banned while outbound slots are free, and persists the
+    /// address book and the bans still running.
+    async fn fill_outbound(&mut self) {
+        self.log_connections();
+        for peer in self.sender.prune() {
+            info!("Dropping peer {}, which couldn't be reached", peer);
+            self.peers.remove(&peer);
+            if self.outbound.remove(&peer).is_some() {
+                self.addresses.record_failure(&peer);
+            }
+        }
+
+        let unanswered = self
+            .outbound
+            .iter()
+            .filter(|(_, asked)| asked.is_some_and(|asked| asked.elapsed() >= ADDR_TIMEOUT))
+            .map(|(peer, _)| *peer)
+            .collect::<Vec<_>>();
+        for peer in unanswered {
+            info!("Dropping outbound peer {}, which didn't answer", peer);
+            self.outbound.remove(&peer);
+            self.peers.remove(&peer);
+            self.addresses.record_failure(&peer);
+        }
+
+        // Peers the receiver banned are still listed here.
+        let bans = self.bans.clone();
+        self.peers.retain(|peer| !bans.is_banned(&peer.ip()));
+        self.outbound.retain(|peer, _| !bans.is_banned(&peer.ip()));
+
+        let mut connected = self.peers.clone();
+        connected.extend(self.outbound.keys());
+        connected.insert(self.address);
+        let free = MAX_OUTBOUND.saturating_sub(self.outbound.len());
+        let candidates = self
+            .addresses
+            .candidates(&connected, self.addresses.len())
+            .into_iter()
+            .filter(|peer| !self.bans.is_banned(&peer.ip()))
+            .take(free)
+            .collect::<Vec<_>>();
+        for peer in candidates {
+            info!("Connecting to {}", peer);
+            self.outbound.insert(peer, Some(Instant::now()));
+            self.peers.insert(peer);
+            let request = Message::GetAddr { from: self.address };
+            self.send_to(peer, request).await;
+        }
+
+        self.bans.expire();
+        self.persist_addresses();
+        self.persist_bans();
     }
 
     pub async fn run(
         &mut self,
-        mut peer_handle: mpsc::Receiver<(Message, oneshot::Sender<String>)>,
-        mut client_handle: mpsc::Receiver<(Txn, oneshot::Sender<Result<Option<String>, String>>)>,
-    ) -> JoinHandle<()> {
+        mut peer_handle: mpsc::Receiver<Incoming<Message, Reply>>,
+        mut client_handle: mpsc::Receiver<ClientRequest>,
+    ) {
+        self.fill_outbound().await;
+
         self.run_miner();
+        if self.sync.is_syncing() {
+            self.drive_sync().await;
+        }
 
-        let state_message = Message::GetState {
-            receiver: self.address,
-        };
-        self.broadcast(state_message).await;
+        let mut expiry_timer = tokio::time::interval(MEMPOOL_EXPIRY_INTERVAL);
+        let mut hash_rate_timer = tokio::time::interval(HASH_RATE_INTERVAL);
+        let mut sync_timer = tokio::time::interval(SYNC_INTERVAL);
+        let mut sync_progress_timer = tokio::time::interval(SYNC_PROGRESS_INTERVAL);
+        let mut persist_timer = tokio::time::interval(MEMPOOL_PERSIST_INTERVAL);
+        let mut peer_timer = tokio::time::interval(PEER_INTERVAL);
+        peer_timer.reset();
+
+        let shutdown = tokio::signal::ctrl_c();
+        tokio::pin!(shutdown);
 
         loop {
             tokio::select! {
-                // Receive block from miner thread
-                Some(block) = self.miner.block_receiver.recv() => {
-                    info!("Block received from Miner task: {:?}", block);
+                _ = &mut shutdown => {
+                    info!("Shutting down");
+                    self.shutdown();
+                    return;
+                }
+
+                _ = expiry_timer.tick() => self.expire_mempool(),
+
+                _ = persist_timer.tick() => self.persist_mempool(),
 
-                    if let Ok(new_state) = self.state.add_block(block) {
-                        info!("Updating state");
-                        self.update_state(new_state).await;
+                _ = sync_timer.tick() => self.drive_sync().await,
+
+                _ = sync_progress_timer.tick(), if self.sync.is_syncing() => self.log_sync_progress(),
+
+                _ = peer_timer.tick() => self.fill_outbound().await,
+
+                _ = hash_rate_timer.tick() => {
+                    info!("Hash rate: {:.0} H/s on {} threads", self.miner.hash_rate(), self.miner.threads());
+                }
+
+                // Receive block from miner threads
+                Some(block) = self.miner.next_block() => {
+                    info!("Block received from miner: {:?}", block);
+
+                    match self.add_block(block) {
+                        Ok(update) => {
+                            info!("Updating state");
+                            self.update_state(update).await;
+                        }
+                        Err(e) => warn!("Mined block rejected: {}", e),
                     }
                 }
 
                 // Receive transaction request from client
-                Some((client_request, node)) = client_handle.recv() => {
-                    info!("Received txn request from client: {:?}", client_request);
-                    let result = self
-                        .handle_message(client_request.clone().try_into().unwrap())
-                        .await
-                        .map_err(|e| e.to_string());
+                Some((client_request, client, node)) = client_handle.recv() => {
+                    info!("Received request from client: {:?}", client_request);
+                    let result = match client_request {
+                        Message::Txn(_) | Message::UtxoTxn(_) => self
+                            .handle_message(client_request, client)
+                            .await
+                            .map_err(|e| e.to_string()),
+                        Message::GetAccount { address, block } => {
+                            self.describe_account(&address, block).map(Some)
+                        }
+                        _ => Err("Clients may only submit transactions and look up accounts".to_string()),
+                    };
 
                     if let Err(e) = node.send(result) {
                         warn!("Failed to send response {:?}", e);
@@ -284,131 +803,471 @@ impl Node {
                 }
 
                 // Receive message from peer
-                Some((message, node)) = peer_handle.recv() => {
+                Some((message, source, node)) = peer_handle.recv() => {
                     info!("Received peer message {:?}", message);
-                    node.send("Acknowledged".to_string()).unwrap();
-                    self.handle_message(message.clone()).await.unwrap();
+                    match message {
+                        Message::Hello(version) => {
+                            let reply = self.accept_peer(version, source);
+                            if node.send(reply).is_err() {
+                                warn!("Failed to answer handshake");
+                            }
+                        }
+                        message => {
+                            if node.send(Reply::Ack).is_err() {
+                                warn!("Failed to acknowledge peer message");
+                            }
+                            if let Err(e) = self.handle_message(message, source).await {
+                                warn!("Failed to handle peer message: {}", e);
+                            }
+                        }
+                    }
                 }
             }
         }
     }
 
-    pub async fn handle_message(&mut self, message: Message) -> Result<Option<String>> {
+    /// Describes the account of `address` after `block`, or after the tip, once its proof checks out against the
+    /// state root in the header of that block.
+    fn describe_account(&self, address: &Address, block: Option<Hash256>) -> Result<String, String> {
+        let hash = block.or(self.state.tip()).ok_or("The chain is empty")?;
+        let header = match self.state.block(&hash) {
+            Some(block) => &block.block_header,
+            None => return Err(format!("Unknown block {}", hash)),
+        };
+        let proof = self
+            .state
+            .account_proof(address, &hash)
+            .ok_or("This chain uses the UTXO ledger")?;
+        if !proof.verify(&header.state_root) {
+            return Err(format!("Proof of {} doesn't match the state root of block {}", address, hash));
+        }
+
+        Ok(format!(
+            "{} at block {} (height {}, state root {}): balance {}, nonce {}",
+            address, hash, header.index, header.state_root, proof.account.balance, proof.account.nonce
+        ))
+    }
+
+    /// Stops the miner and saves what hasn't been persisted yet.
+    pub fn shutdown(&mut self) {
+        self.miner.stop();
+        self.persist_mempool();
+        self.persist_addresses();
+        self.persist_bans();
+    }
+
+    /// Listen address of the peer on the connection from `source`, as given in its handshake.
+    fn peer_of(&self, source: SocketAddr) -> Result<SocketAddr> {
+        match self.sessions.get(&source) {
+            Some(peer) => Ok(*peer),
+            None => Err(NetworkError::HandshakeRequired(source).into()),
+        }
+    }
+
+    /// Handles a message that came in on the connection from `source`.
+    pub async fn handle_message(&mut self, message: Message, source: SocketAddr) -> Result<Option<String>> {
         match message {
-            Message::GetState { receiver } => {
-                self.peers.insert(receiver);
-                let response = Message::ShareState {
+            Message::Hello(_) => bail!("Handshakes are only accepted when a connection opens"),
+
+            Message::GetAccount { .. } => bail!("Accounts are only looked up for clients"),
+
+            Message::GetAddr { .. } => {
+                let peer = self.peer_of(source)?;
+                let addr = Message::Addr {
                     from: self.address,
-                    peers: self.peers.clone(),
-                    state: self.state.clone(),
+                    addresses: self.addresses.recent(MAX_ADDR_PER_MESSAGE),
                 };
-                let data = match bincode::serialize(&response).map_err(|e| e.to_string()) {
-                    Ok(data) => data,
-                    Err(e) => {
-                        bail!(
-                            "Failed to serialize message: {:?}\nError: {:?}",
-                            response.clone(),
-                            e
-                        );
+                self.send_to(peer, addr).await;
+            }
+
+            Message::Addr { addresses, .. } => {
+                let peer = self.peer_of(source)?;
+                // An answer from an outbound peer completes its connection.
+                if let Some(asked) = self.outbound.get_mut(&peer) {
+                    if asked.take().is_some() {
+                        self.addresses.record_success(peer);
                     }
+                }
+                for entry in addresses.into_iter().take(MAX_ADDR_PER_MESSAGE) {
+                    if entry.address != self.address {
+                        self.addresses.add(entry);
+                    }
+                }
+            }
+
+            Message::Inv { items, .. } => {
+                let peer = self.peer_of(source)?;
+                // While downloading a chain, new blocks are left to the sync to fetch in order.
+                let syncing = self.sync.is_ahead();
+
+                let wanted = items
+                    .iter()
+                    .copied()
+                    .filter(|item| !(syncing && matches!(item, InvItem::Block(_))))
+                    .filter(|item| !self.has(item))
+                    .collect::<Vec<_>>();
+                if !wanted.is_empty() {
+                    let request = Message::GetData {
+                        from: self.address,
+                        items: wanted,
+                    };
+                    self.send_to(peer, request).await;
+                }
+
+                // A full answer to `GetBlocks` means the peer has more: ask for the blocks after the last one.
+                let blocks = items
+                    .iter()
+                    .filter_map(|item| match item {
+                        InvItem::Block(hash) => Some(*hash),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>();
+                if !syncing && blocks.len() >= MAX_BLOCKS_INV {
+                    let mut locator = self.state.locator();
+                    locator.insert(0, blocks[blocks.len() - 1]);
+                    let request = Message::GetBlocks {
+                        from: self.address,
+                        locator,
+                        stop: None,
+                    };
+                    self.send_to(peer, request).await;
+                }
+            }
+
+            Message::GetData { items, .. } => {
+                let peer = self.peer_of(source)?;
+                for item in items {
+                    let response = match item {
+                        InvItem::Block(hash) => self.state.block(&hash).map(|block| Message::Block {
+                            from: self.address,
+                            block: block.clone(),
+                        }),
+                        InvItem::Txn(id) => self.mempool.get(&id).cloned().map(Message::Txn),
+                        InvItem::UtxoTxn(id) => self.utxo_pool.get(&id).cloned().map(Message::UtxoTxn),
+                    };
+                    if let Some(response) = response {
+                        self.send_to(peer, response).await;
+                    }
+                }
+            }
+
+            Message::Block { block, .. } => {
+                let peer = self.peer_of(source)?;
+                self.receive_block(peer, source, block).await;
+            }
+
+            Message::GetHeaders { locator, stop, .. } => {
+                let peer = self.peer_of(source)?;
+                let headers = Message::Headers {
+                    from: self.address,
+                    headers: self.state.headers_after(&locator, stop.as_ref(), MAX_HEADERS),
                 };
-                self.sender.send(receiver, data.into()).await;
+                self.send_to(peer, headers).await;
             }
 
-            Message::ShareState { from, peers, state } => {
-                self.peers.insert(from);
-                self.peers.extend(peers);
-                self.peers.remove(&self.address);
-
-                if state.blocks.len() > self.state.blocks.len() {
-                    info!("Received longest chain from {}", from);
-
-                    let current_latest_block = self.state.blocks.last().unwrap();
-                    let new_block = state.blocks.last().unwrap();
-                    let new_block_root = new_block.block_header.merkle_root.clone();
-                    let verify_root = MerkleRoot::from(new_block.body.txn_data.clone());
-
-                    let new_block_check_passed = new_block_root == verify_root
-                        && new_block.block_header.index
-                            == current_latest_block.block_header.index + 1
-                        && current_latest_block.block_header.current_hash
-                            == new_block.block_header.previous_hash;
-
-                    if new_block_check_passed {
-                        self.update_state(state).await;
-                    } else {
-                        warn!("Not a valid state transition {}", state);
+            Message::GetBlocks { locator, stop, .. } => {
+                let peer = self.peer_of(source)?;
+                let items = self
+                    .state
+                    .hashes_after(&locator, stop.as_ref(), MAX_BLOCKS_INV)
+                    .into_iter()
+                    .map(InvItem::Block)
+                    .collect::<Vec<_>>();
+                if !items.is_empty() {
+                    let inv = Message::Inv {
+                        from: self.address,
+                        items,
+                    };
+                    self.send_to(peer, inv).await;
+                }
+            }
+
+            Message::Headers { headers, .. } => {
+                let peer = self.peer_of(source)?;
+                // Headers are only asked for while syncing, but an answer can arrive after the sync ended,
+                // so it is dropped rather than held against the peer.
+                if !self.sync.is_syncing() {
+                    debug!("Ignored headers from {}, no sync is running", peer);
+                    return Ok(None);
+                }
+
+                let count = headers.len();
+                let was_ahead = self.sync.is_ahead();
+                match self.sync.receive_headers(peer, headers, &self.state) {
+                    Ok(()) => info!("Received {} headers from {}", count, peer),
+                    Err(e) => {
+                        warn!("Rejected headers from {}: {}", peer, e);
+                        if let Some(offence) = Offence::for_sync_error(&e) {
+                            self.punish(source, offence);
+                        }
                     }
                 }
+
+                if !self.sync.is_syncing() {
+                    info!("Not syncing with {}, its chain has no more work than ours", peer);
+                    self.finish_sync();
+                } else if !was_ahead && self.sync.is_ahead() {
+                    info!("Headers from {} have more work than our chain, downloading its blocks", peer);
+                    self.miner.stop();
+                }
+                self.drive_sync().await;
             }
 
-            Message::Txn { .. } => {
-                let txn = message.clone();
-                if self.mempool.insert(Txn::try_from(txn).unwrap()) {
-                    self.broadcast(message).await;
-                    return Ok(Some("Transaction processed".to_string()));
+            Message::Txn(txn) => {
+                if let Err(e) = txn.verify() {
+                    bail!("Rejected transaction {}: {}", txn.id, e);
+                }
+
+                let Ledger::Account(ledger) = self.state.ledger() else {
+                    bail!("Rejected transaction {}: this chain uses the UTXO ledger", txn.id);
+                };
+
+                let account = ledger.account(&txn.sender);
+                if txn.nonce < account.nonce {
+                    bail!(
+                        "Rejected transaction {}: nonce {} already used by {}",
+                        txn.id,
+                        txn.nonce,
+                        txn.sender
+                    );
+                }
+                if txn.total_cost() > account.balance {
+                    bail!(
+                        "Rejected transaction {}: {} has a balance of {}",
+                        txn.id,
+                        txn.sender,
+                        account.balance
+                    );
+                }
+
+                let next_nonce = self.mempool.next_nonce(&txn.sender, ledger);
+
+                match self.mempool.insert(txn.clone()) {
+                    Ok(displaced) => {
+                        self.seen.insert(InvItem::Txn(txn.id));
+                        for displaced in displaced {
+                            match displaced {
+                                Displaced::Evicted(evicted) => {
+                                    info!("Evicted transaction {} with the lowest fee rate", evicted.id)
+                                }
+                                Displaced::Replaced(replaced) => {
+                                    info!("Transaction {} replaced {} with a higher fee", txn.id, replaced.id)
+                                }
+                            }
+                        }
+                        self.mempool_changed = true;
+                        self.announce(vec![InvItem::Txn(txn.id)]).await;
+
+                        if txn.nonce > next_nonce {
+                            return Ok(Some(format!(
+                                "Transaction queued until nonce {} is pending",
+                                next_nonce
+                            )));
+                        }
+                        return Ok(Some("Transaction processed".to_string()));
+                    }
+                    // A replaced transaction coming back from a peer is dropped without being relayed again.
+                    Err(MempoolError::AlreadyKnown(_)) | Err(MempoolError::Replaced(_)) => {
+                        self.seen.insert(InvItem::Txn(txn.id));
+                    }
+                    Err(e) => bail!("Rejected transaction {}: {}", txn.id, e),
                 }
             }
+
+            Message::UtxoTxn(txn) => {
+                if self.utxo_pool.contains(&txn.id) {
+                    return Ok(None);
+                }
+
+                if let Err(e) = txn.verify() {
+                    bail!("Rejected transaction {}: {}", txn.id, e);
+                }
+
+                let Ledger::Utxo(utxos) = self.state.ledger() else {
+                    bail!("Rejected transaction {}: this chain uses the account ledger", txn.id);
+                };
+
+                let fee = match utxos.check_txn(&txn) {
+                    Ok(fee) => fee,
+                    Err(e) => bail!("Rejected transaction {}: {}", txn.id, e),
+                };
+
+                match self.utxo_pool.insert(txn.clone(), fee) {
+                    Ok(evicted) => {
+                        self.seen.insert(InvItem::UtxoTxn(txn.id));
+                        for evicted in evicted {
+                            info!("Evicted transaction {} with the lowest fee rate", evicted.id);
+                        }
+                    }
+                    Err(e) => bail!("Rejected transaction {}: {}", txn.id, e),
+                }
+                self.announce(vec![InvItem::UtxoTxn(txn.id)]).await;
+                return Ok(Some("Transaction processed".to_string()));
+            }
         }
 
         Ok(None)
     }
 
-    async fn update_state(&mut self, new_state: BlockChain) {
-        self.state = new_state;
+    async fn update_state(&mut self, update: ChainUpdate) {
+        if update.is_reorg() {
+            info!(
+                "Reorganized chain: {} blocks disconnected, {} connected",
+                update.disconnected.len(),
+                update.connected.len()
+            );
+        }
 
-        self.mempool.retain(|txn| {
-            !self
-                .state
-                .blocks
-                .last()
-                .unwrap()
-                .body
-                .txn_data
-                .contains(txn)
-        });
+        // Roll the mempool forward: anything included in any of the new canonical blocks is no longer pending.
+        let mut included = HashSet::new();
+        for block in &update.connected {
+            self.mempool.remove_included(&block.body.txn_data);
+            self.utxo_pool.remove_included(&block.body.utxo_txns);
+            included.extend(block.body.txn_data.iter().map(|txn| txn.id));
+            included.extend(block.body.utxo_txns.iter().map(|txn| txn.id));
+        }
 
-        let state = Message::ShareState {
-            from: self.address,
-            peers: self.peers.clone(),
-            state: self.state.clone(),
-        };
+        // Roll it back: transactions from blocks that left the canonical chain are pending again,
+        // unless the new chain includes them too. Disconnected blocks come newest first.
+        let mut restored_txns = Vec::new();
+        let mut restored_utxo_txns = Vec::new();
+        for block in update.disconnected.iter().rev() {
+            restored_txns.extend(
+                block.body.txn_data.iter().filter(|txn| !included.contains(&txn.id)).cloned(),
+            );
+            restored_utxo_txns.extend(
+                block.body.utxo_txns.iter().filter(|txn| !included.contains(&txn.id)).cloned(),
+            );
+        }
 
-        self.broadcast(state).await;
-        self.stop_and_restart().await;
-    }
+        match self.state.ledger() {
+            Ledger::Account(ledger) => self.mempool.revalidate(ledger, restored_txns),
+            Ledger::Utxo(utxos) => self.utxo_pool.revalidate(utxos, restored_utxo_txns),
+        }
 
-    async fn stop_and_restart(&mut self) {
-        self.miner.task.abort();
+        self.mempool_changed = true;
+        self.log_mempool();
+        self.refresh_greeting();
+
+        // Peers only fetch the blocks they don't have yet, which is usually just the new tip.
+        // Blocks a sync brings in are old news to the peers they came from.
+        if !self.sync.is_ahead() {
+            let connected = update
+                .connected
+                .iter()
+                .map(|block| InvItem::Block(block.block_header.current_hash))
+                .collect();
+            self.announce(connected).await;
+        }
         self.run_miner();
     }
 
+    /// Starts mining a block on top of the current tip, or the genesis block if there is none.
+    /// Any block the miner was working on is abandoned. Only stops the miner while a sync is downloading a chain
+    /// with more work, or while a node without a chain waits for the headers of the one it syncs with.
     fn run_miner(&mut self) {
-        match self.state.blocks.last() {
-            Some(block) => {
-                info!("Restarting miner thread...");
-                let block = block.clone();
-                let txns = self.mempool.clone().into_iter().collect();
-                let block_sender = self.miner.block_sender.clone();
-
-                self.miner.task = tokio::spawn(async move {
-                    let new_block = Mine::mine(txns, block).await;
-                    if let Err(e) = block_sender.send(new_block).await {
-                        warn!("Can't send mined block to receiver: {}", e);
-                    }
-                });
-            }
+        if self.sync.is_ahead() || (self.sync.is_syncing() && self.state.tip().is_none()) {
+            self.miner.stop();
+            return;
+        }
 
+        let (index, previous_hash) = match self.state.latest_block() {
+            Some(block) => (
+                block.block_header.index + 1,
+                block.block_header.current_hash,
+            ),
             None => {
                 info!("Mining genesis block!");
-                let block_sender = self.miner.block_sender.clone();
-                self.miner.task = tokio::spawn(async move {
-                    let new_block = Mine::mine_genesis();
-                    if let Err(e) = block_sender.send(new_block).await {
-                        warn!("Can't send mined block to receiver: {}", e);
+                (0, GENESIS_PREVIOUS_HASH)
+            }
+        };
+
+        let (body, fees) = self.block_template();
+        let mut template = Block::new(index, previous_hash, body);
+        template.block_header.merkle_root = MerkleRoot::from_body(&template.body);
+        template.block_header.coinbase_txn = CoinbaseTxn {
+            amount: self.state.params().subsidy(index) + fees,
+            validator: self.miner_address,
+            extra_nonce: 0,
+        };
+        let parent = (index > 0).then_some(&template.block_header.previous_hash);
+        template.block_header.bits = self.state.next_bits(parent);
+
+        // The state root doesn't depend on the nonces, so it is fixed before the search starts.
+        let mut ledger = self.state.ledger().clone();
+        if let Err(e) = ledger.apply_block(&template, self.state.params().subsidy(index)) {
+            warn!("Block template doesn't apply to the tip: {}", e);
+            return;
+        }
+        template.block_header.state_root = ledger.state_root();
+
+        info!("Mining block {} on {} threads", index, self.miner.threads());
+        self.miner.start(template);
+    }
+
+    /// Fills a block with the highest fee rate transactions that apply cleanly on top of the current tip,
+    /// up to the block limits. Returns the body along with the fees it pays.
+    fn block_template(&self) -> (Body, u64) {
+        let mut body = Body::default();
+        let mut fees = 0u64;
+        let mut size = 0;
+        let fits = |body: &Body, size: usize, txn_size: usize| {
+            body.len() < MAX_BLOCK_TXNS && size + txn_size <= MAX_BLOCK_SIZE - HEADER_ALLOWANCE
+        };
+
+        match self.state.ledger().clone() {
+            Ledger::Account(mut ledger) => {
+                let state = ledger.clone();
+                self.mempool.select(&state, |entry| {
+                    if !fits(&body, size, entry.size) || ledger.apply_txn(&entry.txn).is_err() {
+                        return false;
                     }
+                    body.txn_data.push(entry.txn.clone());
+                    size += entry.size;
+                    fees += entry.fee;
+                    true
                 });
             }
+            Ledger::Utxo(mut utxos) => {
+                // Pool transactions only spend confirmed outputs and never the same one, so none waits for another.
+                let mut undo = UtxoUndo::default();
+                for entry in self.utxo_pool.by_fee_rate() {
+                    if !fits(&body, size, entry.size) {
+                        continue;
+                    }
+                    if let Ok(fee) = utxos.apply_txn(&entry.txn, &mut undo) {
+                        body.utxo_txns.push(entry.txn.clone());
+                        size += entry.size;
+                        fees += fee;
+                    }
+                }
+            }
+        }
+        (body, fees)
+    }
+
+    /// Tells every peer about new blocks or transactions.
+    async fn announce(&mut self, items: Vec<InvItem>) {
+        if items.is_empty() {
+            return;
+        }
+        let inv = Message::Inv {
+            from: self.address,
+            items,
+        };
+        self.broadcast(inv).await;
+    }
+
+    async fn send_to(&mut self, peer: SocketAddr, message: Message) {
+        if let Message::GetData { items, .. } = &message {
+            for item in items.iter().filter(|item| matches!(item, InvItem::Block(_))) {
+                self.requested.insert(*item);
+            }
+        }
+
+        match bincode::serialize(&message) {
+            Ok(data) => self.sender.send(peer, data.into()).await,
+            Err(e) => warn!("Failed to serialize the message: {:?}", e),
         }
     }
 
@@ -429,28 +1288,8 @@ impl Node {
     }
 }
 
-impl TryFrom<Txn> for Message {
-    type Error = String;
-    fn try_from(value: Txn) -> __private::Result<Self, Self::Error> {
-        Ok(Message::Txn {
-            id: value.id,
-            sender: value.sender,
-            receiver: value.receiver,
-            amount: value.amount,
-        })
-    }
-}
-
-impl Into<Txn> for Message {
-    fn into(self) -> Txn {
-        match self {
-            Message::Txn {
-                id,
-                sender,
-                receiver,
-                amount,
-            } => Txn::with_id(id, sender, receiver, amount),
-            _ => unreachable!(),
-        }
+impl From<Txn> for Message {
+    fn from(txn: Txn) -> Self {
+        Message::Txn(txn)
     }
 }
@@ -0,0 +1,228 @@
+// Consensus parameters every node on a network must agree on
+use crate::block::{BlockHeader, DIFFICULTY, REWARD};
+use crate::pow::{target_from_leading_zeros, U256};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+/// How balances are tracked.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize, ValueEnum)]
+pub enum LedgerMode {
+    /// Balances and nonces per address, moved by `Txn`s.
+    #[default]
+    Account,
+    /// Unspent outputs, spent and created by `UtxoTxn`s.
+    Utxo,
+}
+
+impl std::fmt::Display for LedgerMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LedgerMode::Account => write!(f, "account"),
+            LedgerMode::Utxo => write!(f, "utxo"),
+        }
+    }
+}
+
+/// Default number of seconds the network aims to spend on each block.
+pub const DEFAULT_TARGET_BLOCK_TIME: u64 = 10;
+
+/// Default number of blocks between difficulty adjustments.
+pub const DEFAULT_RETARGET_INTERVAL: u32 = 10;
+
+/// Default number of blocks between halvings of the block subsidy.
+pub const DEFAULT_HALVING_INTERVAL: u32 = 10_000;
+
+/// Default number of blocks before a coinbase can be spent.
+pub const DEFAULT_COINBASE_MATURITY: u32 = 100;
+
+/// Largest change of a single adjustment. Blocks get at most 4 times harder or easier.
+pub const MAX_RETARGET_FACTOR: u64 = 4;
+
+/// Leading zero bits of the easiest allowed target.
+pub const MIN_DIFFICULTY: u8 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChainParams {
+    pub ledger: LedgerMode,
+    /// Difficulty of the genesis block and of every block before the first adjustment, in leading zero bits.
+    pub initial_difficulty: u8,
+    /// Seconds the network aims to spend on each block.
+    pub target_block_time: u64,
+    /// Number of blocks between difficulty adjustments.
+    pub retarget_interval: u32,
+    /// Number of blocks between halvings of the block subsidy.
+    pub halving_interval: u32,
+    /// Number of blocks after a coinbase before what it pays can be spent, so that reorgs
+    /// which drop the coinbase can't invalidate transactions built on it.
+    pub coinbase_maturity: u32,
+}
+
+impl Default for ChainParams {
+    fn default() -> Self {
+        Self {
+            ledger: LedgerMode::default(),
+            initial_difficulty: DIFFICULTY,
+            target_block_time: DEFAULT_TARGET_BLOCK_TIME,
+            retarget_interval: DEFAULT_RETARGET_INTERVAL,
+            halving_interval: DEFAULT_HALVING_INTERVAL,
+            coinbase_maturity: DEFAULT_COINBASE_MATURITY,
+        }
+    }
+}
+
+impl ChainParams {
+    /// Easiest target any block may have.
+    pub fn pow_limit(&self) -> U256 {
+        target_from_leading_zeros(MIN_DIFFICULTY)
+    }
+
+    /// Compact target of the genesis block.
+    pub fn initial_bits(&self) -> u32 {
+        target_from_leading_zeros(self.initial_difficulty.max(MIN_DIFFICULTY)).to_compact()
+    }
+
+    /// New coins a block at `height` may mint. Starts at `REWARD` and halves every `halving_interval` blocks.
+    pub fn subsidy(&self, height: u32) -> u64 {
+        let halvings = height / self.halving_interval.max(1);
+        REWARD.checked_shr(halvings).unwrap_or(0)
+    }
+
+    /// Compact target required of the block after `ancestors`, which run from its parent back towards genesis.
+    /// It changes every `retarget_interval` blocks, based on how long the blocks since the last change took.
+    pub fn next_bits<'a>(&self, ancestors: impl IntoIterator<Item = &'a BlockHeader>) -> u32 {
+        let interval = self.retarget_interval.max(1);
+        let mut ancestors = ancestors.into_iter().take(interval as usize + 1).peekable();
+        let Some(parent) = ancestors.peek() else {
+            return self.initial_bits();
+        };
+
+        let parent_bits = parent.bits;
+        if (parent.index + 1) % interval != 0 {
+            return parent_bits;
+        }
+
+        let window = ancestors.map(|header| header.timestamp).collect::<Vec<_>>();
+        if window.len() < 2 {
+            return parent_bits;
+        }
+
+        let timespan = window[0].saturating_sub(window[window.len() - 1]);
+        self.retarget(parent_bits, timespan, window.len() as u32 - 1)
+    }
+
+    /// Target that brings the time taken by the last blocks back towards the target block time.
+    /// `blocks` is the number of block intervals that took `timespan` seconds.
+    pub fn retarget(&self, bits: u32, timespan: u64, blocks: u32) -> u32 {
+        let expected = (self.target_block_time * blocks as u64).max(1);
+        let timespan = timespan.clamp(
+            (expected / MAX_RETARGET_FACTOR).max(1),
+            expected * MAX_RETARGET_FACTOR,
+        );
+
+        // Scale the target by how much longer or shorter the blocks took than expected.
+        let target = U256::from_compact(bits);
+        let target = match target.checked_mul_u64(timespan) {
+            Some(scaled) => scaled / U256::from(expected),
+            None => (target / U256::from(expected))
+                .checked_mul_u64(timespan)
+                .unwrap_or(U256::MAX),
+        };
+
+        target.min(self.pow_limit()).to_compact()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{Block, Body, GENESIS_PREVIOUS_HASH};
+
+    /// Headers at heights 0 to `tip`, `spacing` seconds apart, from the tip back to genesis.
+    fn ancestors(tip: u32, spacing: u64, bits: u32) -> Vec<BlockHeader> {
+        (0..=tip)
+            .rev()
+            .map(|index| BlockHeader {
+                index,
+                timestamp: 1_000 + index as u64 * spacing,
+                bits,
+                ..Block::new(index, GENESIS_PREVIOUS_HASH, Body::default()).block_header
+            })
+            .collect()
+    }
+
+    #[test]
+    fn subsidy_halves_every_interval_until_nothing_is_left() {
+        let params = ChainParams {
+            halving_interval: 100,
+            ..ChainParams::default()
+        };
+        assert_eq!(params.subsidy(0), REWARD);
+        assert_eq!(params.subsidy(99), REWARD);
+        assert_eq!(params.subsidy(100), REWARD / 2);
+        assert_eq!(params.subsidy(199), REWARD / 2);
+        assert_eq!(params.subsidy(200), REWARD / 4);
+
+        // The reward is down to one coin after five halvings and gone after six. Shifts past 63 bits don't wrap around.
+        assert_eq!(params.subsidy(500), REWARD >> 5);
+        assert_eq!(params.subsidy(600), 0);
+        assert_eq!(params.subsidy(64 * 100), 0);
+        assert_eq!(params.subsidy(u32::MAX), 0);
+    }
+
+    #[test]
+    fn retarget_scales_with_the_timespan_within_the_clamp() {
+        let params = ChainParams::default();
+        let bits = target_from_leading_zeros(20).to_compact();
+        let target = U256::from_compact(bits);
+        let expected = params.target_block_time * 10;
+
+        assert_eq!(params.retarget(bits, expected, 10), bits);
+        assert_eq!(params.retarget(bits, expected * 2, 10), (target << 1).to_compact());
+        assert_eq!(params.retarget(bits, expected / 2, 10), (target >> 1).to_compact());
+
+        // However far off the blocks were, one adjustment moves the target by at most `MAX_RETARGET_FACTOR`.
+        assert_eq!(params.retarget(bits, 0, 10), (target >> 2).to_compact());
+        assert_eq!(params.retarget(bits, expected * 100, 10), (target << 2).to_compact());
+    }
+
+    #[test]
+    fn retarget_never_goes_past_the_pow_limit() {
+        let params = ChainParams::default();
+        let limit = params.pow_limit().to_compact();
+        assert_eq!(params.retarget(limit, u64::MAX, 10), limit);
+
+        let easy = target_from_leading_zeros(MIN_DIFFICULTY + 1).to_compact();
+        assert_eq!(params.retarget(easy, u64::MAX, 10), limit);
+    }
+
+    #[test]
+    fn adjusted_bits_survive_a_compact_round_trip() {
+        let params = ChainParams::default();
+        let mut bits = params.initial_bits();
+        for timespan in [1, 37, 99, 100, 101, 333, 4_000] {
+            bits = params.retarget(bits, timespan, 10);
+            assert_eq!(U256::from_compact(bits).to_compact(), bits);
+        }
+    }
+
+    #[test]
+    fn next_bits_only_changes_at_interval_boundaries() {
+        let params = ChainParams::default();
+        let bits = target_from_leading_zeros(20).to_compact();
+        assert_eq!(params.next_bits(&[]), params.initial_bits());
+
+        // The block at height 9 is the last of the first interval, so the block after height 8 keeps the bits.
+        assert_eq!(params.next_bits(&ancestors(8, 5, bits)), bits);
+
+        // Blocks took half the target time: nine intervals of 5 seconds at the first boundary,
+        // ten at the next one, where the window reaches back to the block before the interval.
+        assert_eq!(params.next_bits(&ancestors(9, 5, bits)), params.retarget(bits, 45, 9));
+        assert_eq!(params.next_bits(&ancestors(19, 5, bits)), params.retarget(bits, 50, 10));
+        assert_eq!(
+            params.next_bits(&ancestors(19, 5, bits)),
+            (U256::from_compact(bits) >> 1).to_compact()
+        );
+        assert_eq!(params.next_bits(&ancestors(20, 5, bits)), bits);
+    }
+}
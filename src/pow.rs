@@ -0,0 +1,336 @@
+/* Proof of work targets.
+A block is valid when its hash, read as a 256-bit big-endian number, is at most the target.
+Headers carry the target in Bitcoin's compact "nBits" form: one exponent byte followed by a 3-byte mantissa. */
+
+use crate::hash::Hash256;
+
+use std::cmp::Ordering;
+use std::ops::{Add, Div, Not, Shl, Shr, Sub};
+
+/// Unsigned 256-bit integer, stored as four 64-bit limbs, least significant first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct U256([u64; 4]);
+
+impl U256 {
+    pub const ZERO: U256 = U256([0; 4]);
+    pub const ONE: U256 = U256([1, 0, 0, 0]);
+    pub const MAX: U256 = U256([u64::MAX; 4]);
+
+    pub fn from_be_bytes(bytes: [u8; 32]) -> Self {
+        let mut limbs = [0u64; 4];
+        for (i, chunk) in bytes.chunks_exact(8).enumerate() {
+            limbs[3 - i] = u64::from_be_bytes(chunk.try_into().unwrap());
+        }
+        Self(limbs)
+    }
+
+    pub fn to_be_bytes(self) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        for (i, chunk) in bytes.chunks_exact_mut(8).enumerate() {
+            chunk.copy_from_slice(&self.0[3 - i].to_be_bytes());
+        }
+        bytes
+    }
+
+    pub fn low_u64(&self) -> u64 {
+        self.0[0]
+    }
+
+    pub fn is_zero(&self) -> bool {
+        *self == Self::ZERO
+    }
+
+    /// Number of significant bits, 0 for zero.
+    pub fn bits(&self) -> u32 {
+        for i in (0..4).rev() {
+            if self.0[i] != 0 {
+                return 64 * i as u32 + 64 - self.0[i].leading_zeros();
+            }
+        }
+        0
+    }
+
+    pub fn leading_zeros(&self) -> u32 {
+        256 - self.bits()
+    }
+
+    pub fn checked_add(self, other: U256) -> Option<U256> {
+        let mut limbs = [0u64; 4];
+        let mut carry = false;
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            let (sum, overflow_a) = self.0[i].overflowing_add(other.0[i]);
+            let (sum, overflow_b) = sum.overflowing_add(carry as u64);
+            *limb = sum;
+            carry = overflow_a || overflow_b;
+        }
+        (!carry).then_some(U256(limbs))
+    }
+
+    pub fn saturating_add(self, other: U256) -> U256 {
+        self.checked_add(other).unwrap_or(U256::MAX)
+    }
+
+    pub fn checked_mul_u64(self, other: u64) -> Option<U256> {
+        let mut limbs = [0u64; 4];
+        let mut carry = 0u128;
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            let product = self.0[i] as u128 * other as u128 + carry;
+            *limb = product as u64;
+            carry = product >> 64;
+        }
+        (carry == 0).then_some(U256(limbs))
+    }
+
+    /// Target encoded in compact form. Negative or overflowing encodings decode to zero and `MAX`.
+    pub fn from_compact(bits: u32) -> U256 {
+        let exponent = bits >> 24;
+        let mantissa = bits & 0x007f_ffff;
+
+        if bits & 0x0080_0000 != 0 && mantissa != 0 {
+            return U256::ZERO;
+        }
+        if exponent <= 3 {
+            return U256::from(mantissa as u64 >> (8 * (3 - exponent)));
+        }
+
+        let shift = 8 * (exponent - 3);
+        let value = U256::from(mantissa as u64);
+        if mantissa != 0 && value.bits() + shift > 256 {
+            return U256::MAX;
+        }
+        value << shift
+    }
+
+    /// Compact form of the target, rounded down to the 3 most significant bytes.
+    pub fn to_compact(&self) -> u32 {
+        let mut exponent = self.bits().div_ceil(8);
+        let mut mantissa = if exponent <= 3 {
+            (self.low_u64() << (8 * (3 - exponent))) as u32
+        } else {
+            (*self >> (8 * (exponent - 3))).low_u64() as u32
+        };
+
+        // The top mantissa bit is a sign bit, so move a set one into the exponent.
+        if mantissa & 0x0080_0000 != 0 {
+            mantissa >>= 8;
+            exponent += 1;
+        }
+        mantissa | (exponent << 24)
+    }
+}
+
+impl From<u64> for U256 {
+    fn from(value: u64) -> Self {
+        U256([value, 0, 0, 0])
+    }
+}
+
+/// Reads a hash as a big-endian number.
+impl From<Hash256> for U256 {
+    fn from(hash: Hash256) -> Self {
+        U256::from_be_bytes(*hash.as_bytes())
+    }
+}
+
+impl Ord for U256 {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.iter().rev().cmp(other.0.iter().rev())
+    }
+}
+
+impl PartialOrd for U256 {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Not for U256 {
+    type Output = U256;
+
+    fn not(self) -> U256 {
+        U256(self.0.map(|limb| !limb))
+    }
+}
+
+impl Add for U256 {
+    type Output = U256;
+
+    fn add(self, other: U256) -> U256 {
+        self.checked_add(other).expect("U256 addition overflowed")
+    }
+}
+
+impl Sub for U256 {
+    type Output = U256;
+
+    fn sub(self, other: U256) -> U256 {
+        assert!(self >= other, "U256 subtraction underflowed");
+        let mut limbs = [0u64; 4];
+        let mut borrow = false;
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            let (difference, borrow_a) = self.0[i].overflowing_sub(other.0[i]);
+            let (difference, borrow_b) = difference.overflowing_sub(borrow as u64);
+            *limb = difference;
+            borrow = borrow_a || borrow_b;
+        }
+        U256(limbs)
+    }
+}
+
+impl Shl<u32> for U256 {
+    type Output = U256;
+
+    fn shl(self, shift: u32) -> U256 {
+        if shift >= 256 {
+            return U256::ZERO;
+        }
+        let (limb_shift, bit_shift) = ((shift / 64) as usize, shift % 64);
+        let mut limbs = [0u64; 4];
+        for (i, limb) in limbs.iter_mut().enumerate().skip(limb_shift) {
+            *limb = self.0[i - limb_shift] << bit_shift;
+            if bit_shift > 0 && i > limb_shift {
+                *limb |= self.0[i - limb_shift - 1] >> (64 - bit_shift);
+            }
+        }
+        U256(limbs)
+    }
+}
+
+impl Shr<u32> for U256 {
+    type Output = U256;
+
+    fn shr(self, shift: u32) -> U256 {
+        if shift >= 256 {
+            return U256::ZERO;
+        }
+        let (limb_shift, bit_shift) = ((shift / 64) as usize, shift % 64);
+        let mut limbs = [0u64; 4];
+        for (i, limb) in limbs.iter_mut().enumerate().take(4 - limb_shift) {
+            *limb = self.0[i + limb_shift] >> bit_shift;
+            if bit_shift > 0 && i + limb_shift + 1 < 4 {
+                *limb |= self.0[i + limb_shift + 1] << (64 - bit_shift);
+            }
+        }
+        U256(limbs)
+    }
+}
+
+impl Div for U256 {
+    type Output = U256;
+
+    /// Shift-and-subtract long division.
+    fn div(self, divisor: U256) -> U256 {
+        assert!(!divisor.is_zero(), "U256 division by zero");
+        if self < divisor {
+            return U256::ZERO;
+        }
+
+        let mut quotient = U256::ZERO;
+        let mut remainder = self;
+        let shift = self.bits() - divisor.bits();
+        let mut divisor = divisor << shift;
+        for i in (0..=shift).rev() {
+            if remainder >= divisor {
+                remainder = remainder - divisor;
+                quotient.0[(i / 64) as usize] |= 1 << (i % 64);
+            }
+            divisor = divisor >> 1;
+        }
+        quotient
+    }
+}
+
+impl std::fmt::Display for U256 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "0x{}", hex::encode(self.to_be_bytes()))
+    }
+}
+
+/// Target whose hashes need at least `zeros` leading zero bits.
+pub fn target_from_leading_zeros(zeros: u8) -> U256 {
+    U256::MAX >> zeros as u32
+}
+
+/// Whether a block hash is at or below the target encoded in `bits`.
+pub fn meets_target(hash: &Hash256, bits: u32) -> bool {
+    U256::from(*hash) <= U256::from_compact(bits)
+}
+
+/// Expected number of hashes needed to find a block at the target encoded in `bits`: 2^256 / (target + 1).
+/// A zero or negative target, which no real hash meets, counts as no work.
+pub fn work_from_compact(bits: u32) -> U256 {
+    let target = U256::from_compact(bits);
+    if target.is_zero() {
+        return U256::ZERO;
+    }
+    if target == U256::MAX {
+        return U256::ONE;
+    }
+    // 2^256 doesn't fit, but 2^256 / (target + 1) == (2^256 - target - 1) / (target + 1) + 1.
+    (!target / (target + U256::ONE)) + U256::ONE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compact_bits_round_trip() {
+        let target = U256::from(0xffff) << 208;
+        assert_eq!(U256::from_compact(0x1d00_ffff), target);
+        assert_eq!(target.to_compact(), 0x1d00_ffff);
+
+        assert_eq!(U256::from_compact(0x0112_3456), U256::from(0x12));
+        assert_eq!(U256::from_compact(0x0200_8000), U256::from(0x80));
+        // 0x80 would read as negative with a one-byte mantissa, so it takes a zero byte in front.
+        assert_eq!(U256::from(0x80).to_compact(), 0x0200_8000);
+        assert_eq!(U256::ZERO.to_compact(), 0);
+
+        for zeros in [1, 8, 10, 20, 255] {
+            let target = target_from_leading_zeros(zeros);
+            let rounded = U256::from_compact(target.to_compact());
+            assert!(rounded <= target);
+            assert_eq!(rounded.to_compact(), target.to_compact());
+        }
+    }
+
+    #[test]
+    fn negative_and_overflowing_bits_decode_to_the_bounds() {
+        assert_eq!(U256::from_compact(0x01fe_dcba), U256::ZERO);
+        assert_eq!(U256::from_compact(0x2300_0001), U256::MAX);
+        assert_eq!(U256::from_compact(0xff12_3456), U256::MAX);
+        // A zero mantissa is zero whatever the exponent.
+        assert_eq!(U256::from_compact(0xff00_0000), U256::ZERO);
+    }
+
+    #[test]
+    fn arithmetic_carries_across_limbs() {
+        let low = U256::from(u64::MAX);
+        assert_eq!(low + U256::ONE, U256::ONE << 64);
+        assert_eq!((U256::ONE << 64) - U256::ONE, low);
+        assert_eq!((U256::ONE << 200) >> 136, U256::ONE << 64);
+        assert_eq!((U256::ONE << 130) / (U256::ONE << 65), U256::ONE << 65);
+        assert_eq!(U256::MAX.checked_add(U256::ONE), None);
+        assert_eq!(U256::MAX.saturating_add(U256::ONE), U256::MAX);
+        assert_eq!(low.checked_mul_u64(2), Some((U256::ONE << 65) - U256::from(2)));
+        assert_eq!(U256::MAX.checked_mul_u64(2), None);
+        assert_eq!(U256::from_be_bytes(U256::MAX.to_be_bytes()), U256::MAX);
+        assert_eq!((U256::ONE << 255).bits(), 256);
+        assert_eq!(target_from_leading_zeros(10).leading_zeros(), 10);
+    }
+
+    #[test]
+    fn zero_and_negative_targets_have_no_work() {
+        assert_eq!(work_from_compact(0), U256::ZERO);
+        assert_eq!(work_from_compact(0x0480_0001), U256::ZERO);
+    }
+
+    #[test]
+    fn work_is_two_to_the_256_over_target_plus_one() {
+        assert_eq!(work_from_compact(U256::MAX.to_compact()), U256::ONE);
+        // Target 2^255 - 1 rounds down in compact form, which only makes the work larger.
+        let half = target_from_leading_zeros(1);
+        assert!(work_from_compact(half.to_compact()) >= U256::from(2));
+        assert_eq!(work_from_compact(0x0300_ffff), U256::MAX / U256::from(0x1_0000) + U256::ONE);
+    }
+}
@@ -1,4 +1,5 @@
 // Abstract implementation of Receiver end of the channel
+use crate::ban::{BanList, Offence};
 use crate::error::NetworkError::*;
 use anyhow::Result;
 use bytes::Bytes;
@@ -11,13 +12,37 @@ use tokio::{
     sync::{mpsc, oneshot},
 };
 
-use tokio_util::codec::{Framed, LengthDelimitedCodec};
+use tokio_util::codec::{Framed, LengthDelimitedCodec, LengthDelimitedCodecError};
+
+/// Longest frame a connection may send. Blocks are at most `MAX_BLOCK_SIZE`, so this leaves ample room.
+pub const MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+
+/// A request, the address of the connection it came in on and the channel to answer it on.
+pub type Incoming<Request, Response> = (Request, SocketAddr, oneshot::Sender<Response>);
+
+/// Tells which request opens a connection and whether the response to it accepted the connection.
+pub struct Handshake<Request, Response> {
+    pub is_hello: fn(&Request) -> bool,
+    pub is_accepted: fn(&Response) -> bool,
+}
+
+impl<Request, Response> Clone for Handshake<Request, Response> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<Request, Response> Copy for Handshake<Request, Response> {}
 
 /// Request receiver
 pub struct MessageReceiver<Request, Response> {
     address: SocketAddr,
-    sender: mpsc::Sender<(Request, oneshot::Sender<Response>)>,
+    sender: mpsc::Sender<Incoming<Request, Response>>,
     receiver_type: String,
+    /// If set, a connection is closed unless its first request is an accepted handshake.
+    handshake: Option<Handshake<Request, Response>>,
+    /// If set, connections from banned addresses are refused and malformed or oversized frames count against them.
+    bans: Option<BanList>,
 }
 
 impl<Request, Response> MessageReceiver<Request, Response>
@@ -28,18 +53,36 @@ where
     pub fn new(
         addr: SocketAddr,
         receiver_type: &str,
-    ) -> (Self, mpsc::Receiver<(Request, oneshot::Sender<Response>)>) {
+    ) -> (Self, mpsc::Receiver<Incoming<Request, Response>>) {
         let (sender, receiver) = mpsc::channel(500);
         (
             Self {
                 address: addr,
                 sender,
                 receiver_type: receiver_type.to_owned(),
+                handshake: None,
+                bans: None,
             },
             receiver,
         )
     }
 
+    /// Receiver whose connections must open with a handshake before any other request is passed on.
+    pub fn with_handshake(
+        addr: SocketAddr,
+        receiver_type: &str,
+        handshake: Handshake<Request, Response>,
+    ) -> (Self, mpsc::Receiver<Incoming<Request, Response>>) {
+        let (mut receiver, requests) = Self::new(addr, receiver_type);
+        receiver.handshake = Some(handshake);
+        (receiver, requests)
+    }
+
+    /// Checks connections against `bans`, and scores the frames they send.
+    pub fn set_ban_list(&mut self, bans: BanList) {
+        self.bans = Some(bans);
+    }
+
     pub async fn run(&self) {
         let listener = TcpListener::bind(self.address).await.unwrap();
         
@@ -54,32 +97,80 @@ where
                 }
             };
 
+            if is_banned(&self.bans, &sender) {
+                info!("{}", Banned(sender));
+                continue;
+            }
+
             info!("Incoming connection established with {}", sender);
-            Self::spawn(stream, sender, self.sender.clone()).await;
+            Self::spawn(stream, sender, self.sender.clone(), self.handshake, self.bans.clone()).await;
         }
     }
 
     async fn spawn(
         stream: TcpStream,
         sender: SocketAddr,
-        channel: mpsc::Sender<(Request, oneshot::Sender<Response>)>,
+        channel: mpsc::Sender<Incoming<Request, Response>>,
+        handshake: Option<Handshake<Request, Response>>,
+        bans: Option<BanList>,
     ) {
         tokio::spawn(async move {
-            let (mut writer, mut reader) = Framed::new(stream, LengthDelimitedCodec::new()).split();
+            let codec = LengthDelimitedCodec::builder()
+                .max_frame_length(MAX_MESSAGE_SIZE)
+                .new_codec();
+            let (mut writer, mut reader) = Framed::new(stream, codec).split();
+            let mut pending_handshake = handshake;
 
             while let Some(message) = reader.next().await {
-                info!("looping");
-                match message.map_err(|e| FailedToReceive(sender, e)) {
+                // The node may have banned the peer over an earlier request.
+                if is_banned(&bans, &sender) {
+                    warn!("{}", Banned(sender));
+                    return;
+                }
+
+                match message {
                     Ok(message) => {
-                        info!("Reached here");
-                        let msg = message.into();
-                        if let Err(e) = Self::dispatch(&mut writer, channel.clone(), msg).await {
-                            warn!("Failed to dispatch message {}", e);
+                        let request = match bincode::deserialize::<Request>(&message) {
+                            Ok(request) => request,
+                            Err(e) => {
+                                warn!("Failed to dispatch message {}", e);
+                                if punish(&bans, sender, Offence::MalformedMessage) {
+                                    return;
+                                }
+                                continue;
+                            }
+                        };
+
+                        if let Some(handshake) = pending_handshake {
+                            if !(handshake.is_hello)(&request) {
+                                warn!("{}", HandshakeRequired(sender));
+                                return;
+                            }
+                        }
+
+                        match Self::dispatch(&mut writer, channel.clone(), request, sender).await {
+                            Ok(response) => {
+                                if let Some(handshake) = pending_handshake.take() {
+                                    if !(handshake.is_accepted)(&response) {
+                                        warn!("{}", HandshakeRejected(sender));
+                                        return;
+                                    }
+                                }
+                            }
+                            Err(e) => warn!("Failed to dispatch message {}", e),
                         }
                     }
 
                     Err(e) => {
-                        warn!("{}", e);
+                        // The codec can't skip a frame it refused, so the connection ends either way.
+                        let oversized = e
+                            .get_ref()
+                            .is_some_and(|e| e.is::<LengthDelimitedCodecError>());
+                        if oversized {
+                            punish(&bans, sender, Offence::OversizedMessage);
+                        } else {
+                            warn!("{}", FailedToReceive(sender, e));
+                        }
                         return;
                     }
                 }
@@ -89,21 +180,30 @@ where
 
     async fn dispatch(
         writer: &mut SplitSink<Framed<TcpStream, LengthDelimitedCodec>, Bytes>,
-        sender: mpsc::Sender<(Request, oneshot::Sender<Response>)>,
-        message: Bytes,
-    ) -> Result<()> {
-        let request = bincode::deserialize(&message)?;
-
+        sender: mpsc::Sender<Incoming<Request, Response>>,
+        request: Request,
+        peer: SocketAddr,
+    ) -> Result<Response> {
         let (response_sender, response_receiver) = oneshot::channel();
 
-        sender.send((request, response_sender)).await?;
+        sender.send((request, peer, response_sender)).await?;
 
         let response = response_receiver.await?;
 
-        let response = bincode::serialize(&response)?;
+        let bytes = bincode::serialize(&response)?;
 
-        writer.send(response.into()).await?;
+        writer.send(bytes.into()).await?;
 
-        Ok(())
+        Ok(response)
     }
 }
+
+fn is_banned(bans: &Option<BanList>, peer: &SocketAddr) -> bool {
+    bans.as_ref().is_some_and(|bans| bans.is_banned(&peer.ip()))
+}
+
+/// Counts `offence` against `peer`. Returns true if it got the peer banned.
+fn punish(bans: &Option<BanList>, peer: SocketAddr, offence: Offence) -> bool {
+    warn!("{}", Misbehaved(peer, offence));
+    bans.as_ref().is_some_and(|bans| bans.punish(peer.ip(), offence))
+}
@@ -1,45 +1,95 @@
 /* Abstract implementation of Sender end of the channel.
-Also includes receiver connection because sender end creates receiver on demand */
+Also includes receiver connection because sender end creates receiver on demand.
+Each connection reconnects with exponential backoff when the peer can't be reached or drops it, and gives up after
+`MAX_RECONNECTS` failed attempts in a row. Connections given up on are replaced on the next send to the peer. */
+
+use crate::error::NetworkError;
 
 use bytes::Bytes;
 use futures::sink::SinkExt as _;
-use futures::stream::StreamExt as _;
+use futures::stream::{SplitSink, SplitStream, StreamExt as _};
 use log::{info, warn};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::{collections::HashMap, net::SocketAddr};
 use tokio::net::TcpStream;
-use tokio::sync::mpsc::{self, *};
+use tokio::sync::mpsc::{self, error::TrySendError, *};
 use tokio_util::codec::{Framed, LengthDelimitedCodec};
 
-use thiserror::Error;
+/// Most messages queued for a peer. Messages sent to a peer whose queue is full are dropped,
+/// so a slow or unreachable peer can't hold up the node.
+pub const MAX_QUEUED_MESSAGES: usize = 1000;
+
+/// Wait before the first reconnection attempt, doubled after every failed one.
+pub const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Longest wait between reconnection attempts.
+pub const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Failed connection attempts in a row after which a peer is given up on.
+pub const MAX_RECONNECTS: u32 = 6;
+
+/// How long a connection without a greeting must stay up before the failed attempts before it are forgotten.
+/// A greeted connection is trusted as soon as the peer accepts the greeting.
+pub const STABLE_CONNECTION: Duration = Duration::from_secs(60);
 
-#[derive(Error, Debug)]
-pub enum NetworkError {
-    #[error("Failed to connect to {0}\n{1}")]
-    FailedToConnect(SocketAddr, std::io::Error),
+type Writer = SplitSink<Framed<TcpStream, LengthDelimitedCodec>, Bytes>;
+type Reader = SplitStream<Framed<TcpStream, LengthDelimitedCodec>>;
 
-    #[error("Failed to send message to {0}.\n{1}")]
-    FailedToSend(SocketAddr, std::io::Error),
+/// Checks the reply to a greeting, returning why the peer is refused if it is.
+pub type AcceptReply = Arc<dyn Fn(&[u8]) -> Result<(), String> + Send + Sync>;
 
-    #[error("Failed to receive message from {0}")]
-    FailedToReceive(SocketAddr, std::io::Error),
+/// Opening exchange of outgoing connections: `hello` is sent first, and nothing else is sent
+/// unless `accept` approves the reply to it.
+#[derive(Clone)]
+pub struct Greeting {
+    pub hello: Bytes,
+    pub accept: AcceptReply,
+}
 
-    #[error("Failed to receive ACK from {0}.")]
-    NoACKReceipt(SocketAddr),
+/// Where a connection to a peer stands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Connecting to the peer or waiting for it to accept the greeting. Messages queue up meanwhile.
+    Connecting,
+    Connected,
+    /// Waiting to reconnect after `failures` failed attempts in a row.
+    Backoff { failures: u32 },
+    /// Given up on, because the peer refused the greeting or couldn't be reached after `MAX_RECONNECTS` attempts.
+    Closed,
+}
+
+/// State of the connection to a peer, as listed by `MessageSender::connections`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    pub address: SocketAddr,
+    pub state: ConnectionState,
+    /// Messages waiting to be written to the peer.
+    pub queued: usize,
+}
 
-    #[error("Received unexpected ACK from {0}.")]
-    UnexpectedACK(SocketAddr),
+#[derive(Clone)]
+struct Connection {
+    sender: Sender<Bytes>,
+    state: Arc<Mutex<ConnectionState>>,
+}
 
-    #[error("Failed to receive state from boot node {0}")]
-    BootNodeReceiveError(SocketAddr),
+impl Connection {
+    fn state(&self) -> ConnectionState {
+        *self.state.lock().unwrap()
+    }
 
-    #[error("Failed to deserialize message")]
-    DeserializeError,
+    fn is_closed(&self) -> bool {
+        self.sender.is_closed() || self.state() == ConnectionState::Closed
+    }
 }
 
 /// Each peer connection is given a separate thread
 #[derive(Clone)]
 pub struct MessageSender {
-    connections: HashMap<SocketAddr, Sender<Bytes>>,
+    connections: HashMap<SocketAddr, Connection>,
+    /// Shared with every connection, which reads it each time it connects.
+    greeting: Arc<Mutex<Option<Greeting>>>,
 }
 
 impl std::default::Default for MessageSender {
@@ -52,29 +102,36 @@ impl MessageSender {
     pub fn new() -> Self {
         Self {
             connections: HashMap::new(),
+            greeting: Arc::new(Mutex::new(None)),
         }
     }
 
-    pub fn spawn_sender(addr: SocketAddr) -> Sender<Bytes> {
-        let (sender, receiver) = mpsc::channel::<Bytes>(500);
-        ReceiverConnection::spawn(addr, receiver);
-        sender
+    /// Greets every peer connected to from now on with `greeting`, including on reconnects of existing connections.
+    /// Connections that are already open are kept as they are.
+    pub fn set_greeting(&mut self, greeting: Greeting) {
+        *self.greeting.lock().unwrap() = Some(greeting);
+    }
+
+    fn spawn_connection(addr: SocketAddr, greeting: Arc<Mutex<Option<Greeting>>>) -> Connection {
+        let (sender, receiver) = mpsc::channel::<Bytes>(MAX_QUEUED_MESSAGES);
+        let state = Arc::new(Mutex::new(ConnectionState::Connecting));
+        ReceiverConnection::spawn(addr, receiver, greeting, state.clone());
+        Connection { sender, state }
     }
 
+    /// Queues `data` for `addr`, connecting to it first unless a connection is open or being retried.
+    /// The message is dropped if the queue of the peer is full.
     pub async fn send(&mut self, addr: SocketAddr, data: Bytes) {
-        if let Some(sender) = self.connections.get(&addr) {
-            if let Err(_) = sender.send(data.clone()).await {
-                warn!("Failed to send message to {}", addr);
-                return;
-            }
-        } else {
-            let sender = Self::spawn_sender(addr);
-            self.connections.insert(addr, sender.clone());
+        if self.connections.get(&addr).is_none_or(Connection::is_closed) {
+            let connection = Self::spawn_connection(addr, self.greeting.clone());
+            self.connections.insert(addr, connection);
+        }
 
-            if let Err(_) = sender.send(data.clone()).await {
-                warn!("Failed to send message to {}", addr);
-                return;
-            }
+        let connection = &self.connections[&addr];
+        match connection.sender.try_send(data) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => warn!("{}", NetworkError::QueueFull(addr)),
+            Err(TrySendError::Closed(_)) => warn!("{}", NetworkError::ConnectionClosed(addr)),
         }
     }
 
@@ -83,46 +140,283 @@ impl MessageSender {
             self.send(address, data.clone()).await;
         }
     }
+
+    /// States of the connections to every peer, including those given up on that haven't been pruned yet.
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        self.connections
+            .iter()
+            .map(|(address, connection)| ConnectionInfo {
+                address: *address,
+                state: connection.state(),
+                queued: MAX_QUEUED_MESSAGES - connection.sender.capacity(),
+            })
+            .collect()
+    }
+
+    /// Forgets the connections given up on. Returns the addresses of their peers.
+    pub fn prune(&mut self) -> Vec<SocketAddr> {
+        let closed = self
+            .connections
+            .iter()
+            .filter(|(_, connection)| connection.is_closed())
+            .map(|(address, _)| *address)
+            .collect::<Vec<_>>();
+        for address in &closed {
+            self.connections.remove(address);
+        }
+        closed
+    }
+}
+
+/// Why a connection attempt failed.
+enum ConnectFailure {
+    /// The peer couldn't be reached or didn't answer the greeting. Worth another attempt.
+    Unreachable,
+    /// The peer refused the greeting, so it would refuse another attempt too.
+    Refused,
+}
+
+/// How an open connection ended.
+enum Disconnect {
+    /// The peer closed the connection or it broke.
+    Lost,
+    /// The `MessageSender` dropped the connection, so nothing will be sent anymore.
+    Dropped,
 }
 
 struct ReceiverConnection {
     address: SocketAddr,
     receiver: Receiver<Bytes>,
+    greeting: Arc<Mutex<Option<Greeting>>>,
+    state: Arc<Mutex<ConnectionState>>,
+    /// Message whose write failed, sent first once reconnected.
+    pending: Option<Bytes>,
 }
 
 impl ReceiverConnection {
-    pub fn spawn(address: SocketAddr, receiver: Receiver<Bytes>) {
+    pub fn spawn(
+        address: SocketAddr,
+        receiver: Receiver<Bytes>,
+        greeting: Arc<Mutex<Option<Greeting>>>,
+        state: Arc<Mutex<ConnectionState>>,
+    ) {
         tokio::spawn(async move {
-            Self { address, receiver }.run().await;
+            Self {
+                address,
+                receiver,
+                greeting,
+                state,
+                pending: None,
+            }
+            .run()
+            .await;
         });
     }
 
     pub async fn run(&mut self) {
+        let mut failures = 0;
+        loop {
+            self.set_state(ConnectionState::Connecting);
+            // The greeting is taken afresh on every attempt, so a reconnect doesn't repeat an outdated one.
+            let greeting = self.greeting.lock().unwrap().clone();
+            let greeted = greeting.is_some();
+            match self.connect(greeting).await {
+                Ok((writer, reader)) => {
+                    // A peer that accepted the greeting is back. Without a greeting the connection has to stay up
+                    // for a while to show that, so a peer that drops every connection straight away is given up on.
+                    if greeted {
+                        failures = 0;
+                    }
+                    let since = Instant::now();
+                    self.set_state(ConnectionState::Connected);
+                    match self.serve(writer, reader).await {
+                        Disconnect::Lost if since.elapsed() >= STABLE_CONNECTION => failures = 1,
+                        Disconnect::Lost => failures += 1,
+                        Disconnect::Dropped => break,
+                    }
+                }
+                Err(ConnectFailure::Unreachable) => failures += 1,
+                Err(ConnectFailure::Refused) => break,
+            }
+
+            if failures >= MAX_RECONNECTS {
+                warn!("Giving up on {} after {} failed attempts", self.address, failures);
+                break;
+            }
+            self.set_state(ConnectionState::Backoff { failures });
+            tokio::time::sleep(backoff(failures)).await;
+        }
+
+        self.set_state(ConnectionState::Closed);
+    }
+
+    async fn connect(&mut self, greeting: Option<Greeting>) -> Result<(Writer, Reader), ConnectFailure> {
         let (mut writer, mut reader) = match TcpStream::connect(self.address).await {
             Ok(stream) => Framed::new(stream, LengthDelimitedCodec::new()).split(),
             Err(e) => {
                 warn!("{}", NetworkError::FailedToConnect(self.address, e));
-                return;
+                return Err(ConnectFailure::Unreachable);
             }
         };
 
+        // Messages queue up in the channel until the peer has accepted the handshake.
+        if let Some(greeting) = greeting {
+            if let Err(e) = writer.send(greeting.hello.clone()).await {
+                warn!("{:#?}", NetworkError::FailedToSend(self.address, e));
+                return Err(ConnectFailure::Unreachable);
+            }
+            match reader.next().await {
+                Some(Ok(reply)) => {
+                    if let Err(reason) = (greeting.accept)(&reply) {
+                        warn!("{}", NetworkError::HandshakeFailed(self.address, reason));
+                        return Err(ConnectFailure::Refused);
+                    }
+                    info!("Completed handshake with {}", self.address);
+                }
+                _ => {
+                    warn!("{}", NetworkError::NoACKReceipt(self.address));
+                    return Err(ConnectFailure::Unreachable);
+                }
+            }
+        }
+
+        Ok((writer, reader))
+    }
+
+    /// Writes queued messages to the peer until either side closes the connection.
+    async fn serve(&mut self, mut writer: Writer, mut reader: Reader) -> Disconnect {
+        if let Some(data) = self.pending.take() {
+            if let Err(e) = writer.send(data.clone()).await {
+                warn!("{:#?}", NetworkError::FailedToSend(self.address, e));
+                self.pending = Some(data);
+                return Disconnect::Lost;
+            }
+        }
+
         loop {
             tokio::select! {
-                Some(data) = self.receiver.recv() => {
-                    if let Err(e) = writer.send(data).await {
+                data = self.receiver.recv() => {
+                    let Some(data) = data else {
+                        return Disconnect::Dropped;
+                    };
+                    if let Err(e) = writer.send(data.clone()).await {
                         warn!("{:#?}", NetworkError::FailedToSend(self.address, e));
+                        self.pending = Some(data);
+                        return Disconnect::Lost;
                     }
                 }
-    
-                Some(response) = reader.next() => {
+
+                response = reader.next() => {
                     match response {
-                        Ok(_) => info!("Received ACK from {}", self.address),
-                        Err(e) => { 
-                            warn!("{}", NetworkError::NoACKReceipt(self.address));
+                        Some(Ok(_)) => info!("Received ACK from {}", self.address),
+                        Some(Err(e)) => {
+                            warn!("{}", NetworkError::FailedToReceive(self.address, e));
+                            return Disconnect::Lost;
+                        }
+                        None => {
+                            warn!("{}", NetworkError::ConnectionClosed(self.address));
+                            return Disconnect::Lost;
                         }
                     }
                 }
             }
         }
     }
+
+    fn set_state(&self, state: ConnectionState) {
+        *self.state.lock().unwrap() = state;
+    }
+}
+
+/// Wait before reconnecting after `failures` failed attempts in a row.
+fn backoff(failures: u32) -> Duration {
+    INITIAL_BACKOFF
+        .saturating_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+        .min(MAX_BACKOFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+    use tokio::time::timeout;
+
+    /// Address nothing listens on, until a test binds it again.
+    fn free_address() -> SocketAddr {
+        std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+    }
+
+    /// Waits until the connection to `address` is in a state `done` accepts.
+    async fn wait_for(
+        sender: &MessageSender,
+        address: SocketAddr,
+        done: impl Fn(&ConnectionInfo) -> bool,
+    ) -> ConnectionInfo {
+        timeout(Duration::from_secs(10), async {
+            loop {
+                let info = sender.connections().into_iter().find(|info| info.address == address);
+                if let Some(info) = info.filter(|info| done(info)) {
+                    return info;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn accept(listener: &TcpListener) -> Framed<TcpStream, LengthDelimitedCodec> {
+        let (stream, _) = timeout(Duration::from_secs(10), listener.accept()).await.unwrap().unwrap();
+        Framed::new(stream, LengthDelimitedCodec::new())
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        assert_eq!(backoff(1), INITIAL_BACKOFF);
+        assert_eq!(backoff(2), INITIAL_BACKOFF * 2);
+        assert_eq!(backoff(4), INITIAL_BACKOFF * 8);
+        assert_eq!(backoff(7), MAX_BACKOFF);
+        assert_eq!(backoff(u32::MAX), MAX_BACKOFF);
+    }
+
+    #[tokio::test]
+    async fn queued_messages_reach_a_peer_that_comes_up_later() {
+        let address = free_address();
+        let mut sender = MessageSender::new();
+        sender.send(address, Bytes::from_static(b"first")).await;
+        sender.send(address, Bytes::from_static(b"second")).await;
+
+        let info = wait_for(&sender, address, |info| matches!(info.state, ConnectionState::Backoff { .. })).await;
+        assert_eq!(info.queued, 2);
+
+        let listener = TcpListener::bind(address).await.unwrap();
+        let mut peer = accept(&listener).await;
+        assert_eq!(&peer.next().await.unwrap().unwrap()[..], b"first");
+        assert_eq!(&peer.next().await.unwrap().unwrap()[..], b"second");
+        let info = wait_for(&sender, address, |info| info.state == ConnectionState::Connected).await;
+        assert_eq!(info.queued, 0);
+    }
+
+    #[tokio::test]
+    async fn refused_greeting_closes_the_connection_for_pruning() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut sender = MessageSender::new();
+        sender.set_greeting(Greeting {
+            hello: Bytes::from_static(b"hello"),
+            accept: Arc::new(|reply| match reply {
+                b"welcome" => Ok(()),
+                _ => Err("not welcome".to_string()),
+            }),
+        });
+        sender.send(address, Bytes::from_static(b"data")).await;
+
+        let mut peer = accept(&listener).await;
+        assert_eq!(&peer.next().await.unwrap().unwrap()[..], b"hello");
+        peer.send(Bytes::from_static(b"go away")).await.unwrap();
+
+        wait_for(&sender, address, |info| info.state == ConnectionState::Closed).await;
+        assert_eq!(sender.prune(), vec![address]);
+        assert!(sender.connections().is_empty());
+    }
 }
@@ -1,8 +1,18 @@
-use blockchain::receiver::MessageReceiver;
-use blockchain::node::Node;
+use blockchain::receiver::{Handshake, MessageReceiver};
+use blockchain::mempool::{
+    MempoolLimits, DEFAULT_MEMPOOL_BYTES, DEFAULT_MEMPOOL_EXPIRY, DEFAULT_MEMPOOL_SIZE,
+};
+use blockchain::node::{Message, Node, NodeConfig, Reply};
+use blockchain::params::{
+    ChainParams, LedgerMode, DEFAULT_COINBASE_MATURITY, DEFAULT_HALVING_INTERVAL,
+    DEFAULT_RETARGET_INTERVAL, DEFAULT_TARGET_BLOCK_TIME,
+};
+use blockchain::transaction::Address;
+use blockchain::block::DIFFICULTY;
 
 use clap::Parser;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
 use tokio::task::JoinHandle;
 
 #[derive(Parser)]
@@ -21,8 +31,61 @@ struct Cli {
     #[clap(short, long, value_parser, value_name="NUM", default_value_t=IpAddr::V4(Ipv4Addr::LOCALHOST))]
     address: IpAddr,
 
-    #[clap(long, short, value_name = "ADDRESS")]
-    boot_node: Option<SocketAddr>,
+    /// Node to learn peers and the chain from at startup. Can be given more than once, the chain is synced from the first.
+    #[clap(long, short, value_name = "ADDRESS", value_delimiter = ',')]
+    boot_node: Vec<SocketAddr>,
+
+    /// Directory to persist the chain and mempool in. Runs in memory only if not set.
+    #[clap(long, value_name = "DIR")]
+    data_dir: Option<PathBuf>,
+
+    /// How balances are tracked. All nodes of a network must use the same ledger.
+    #[clap(long, value_enum, default_value_t = LedgerMode::Account)]
+    ledger: LedgerMode,
+
+    /// Seconds the network aims to spend on each block. Lower it for quick local test networks.
+    #[clap(long, value_name = "SECS", default_value_t = DEFAULT_TARGET_BLOCK_TIME)]
+    block_time: u64,
+
+    /// Number of blocks between difficulty adjustments.
+    #[clap(long, value_name = "NUM", default_value_t = DEFAULT_RETARGET_INTERVAL)]
+    retarget_interval: u32,
+
+    /// Number of blocks between halvings of the block subsidy.
+    #[clap(long, value_name = "NUM", default_value_t = DEFAULT_HALVING_INTERVAL)]
+    halving_interval: u32,
+
+    /// Number of blocks after a coinbase before its reward can be spent.
+    #[clap(long, value_name = "NUM", default_value_t = DEFAULT_COINBASE_MATURITY)]
+    coinbase_maturity: u32,
+
+    /// Difficulty of the genesis block, in leading zero bits of the block hash.
+    #[clap(long, value_name = "BITS", default_value_t = DIFFICULTY)]
+    initial_difficulty: u8,
+
+    /// Most transactions kept pending. The lowest fee rate ones are evicted when it is full.
+    #[clap(long, value_name = "NUM", default_value_t = DEFAULT_MEMPOOL_SIZE)]
+    mempool_size: usize,
+
+    /// Most bytes of pending transactions kept. The lowest fee rate ones are evicted beyond this.
+    #[clap(long, value_name = "BYTES", default_value_t = DEFAULT_MEMPOOL_BYTES)]
+    mempool_bytes: usize,
+
+    /// Seconds a transaction may stay pending before it is dropped.
+    #[clap(long, value_name = "SECS", default_value_t = DEFAULT_MEMPOOL_EXPIRY)]
+    mempool_expiry: u64,
+
+    /// Number of threads searching for proof of work. Defaults to one per CPU core.
+    #[clap(long, value_name = "NUM")]
+    miner_threads: Option<usize>,
+
+    /// Address the coinbase of mined blocks pays to. A random one is used if not set.
+    #[clap(long, value_name = "ADDRESS")]
+    miner_address: Option<Address>,
+
+    /// Never score peers on loopback addresses, so nodes running on one host for testing can't ban each other.
+    #[clap(long)]
+    exempt_loopback: bool,
 }
 
 #[tokio::main]
@@ -35,32 +98,61 @@ async fn main() {
 
     let server_address = SocketAddr::new(cli.address, cli.server_port);
     let client_address = SocketAddr::new(cli.address, cli.client_port);
-    let boot_node = cli.boot_node;
     dbg!(server_address);
-    let (server, network_handle, client) =
-        init_node(server_address, client_address, boot_node).await;
 
-    server.await.unwrap();
-    network_handle.await.unwrap();
-    client.await.unwrap();
+    let config = NodeConfig {
+        address: server_address,
+        seeds: cli.boot_node,
+        data_dir: cli.data_dir,
+        params: ChainParams {
+            ledger: cli.ledger,
+            initial_difficulty: cli.initial_difficulty,
+            target_block_time: cli.block_time,
+            retarget_interval: cli.retarget_interval,
+            halving_interval: cli.halving_interval,
+            coinbase_maturity: cli.coinbase_maturity,
+        },
+        mempool: MempoolLimits {
+            max_txns: cli.mempool_size,
+            max_bytes: cli.mempool_bytes,
+            expiry: cli.mempool_expiry,
+        },
+        miner_threads: cli
+            .miner_threads
+            .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get())),
+        miner_address: cli.miner_address,
+        exempt_loopback: cli.exempt_loopback,
+    };
+
+    // The listeners run until the node shuts down.
+    let (_server, _client, node) = init_node(config, client_address).await;
+
+    node.await.unwrap();
 }
 
 async fn init_node(
-    server: SocketAddr,
+    config: NodeConfig,
     client: SocketAddr,
-    boot_node: Option<SocketAddr>,
 ) -> (JoinHandle<()>, JoinHandle<()>, JoinHandle<()>) {
-    let (server_config, server_request_handle) = MessageReceiver::new(server, "Server");
+    let handshake = Handshake {
+        is_hello: Message::is_hello,
+        is_accepted: Reply::is_accepted,
+    };
+    let (mut server_config, server_request_handle) =
+        MessageReceiver::with_handshake(config.address, "Server", handshake);
+    let (client_config, client_request_handle) = MessageReceiver::new(client, "Client");
+
+    let mut node = Node::new(config).await.unwrap();
+    server_config.set_ban_list(node.ban_list());
+
     let server_handle = tokio::spawn(async move {
         server_config.run().await;
     });
 
-    let (client_config, client_request_handle) = MessageReceiver::new(client, "Client");
     let client_handle = tokio::spawn(async move {
         client_config.run().await;
     });
 
-    let mut node = Node::new(server, boot_node).await.unwrap();
     let node_handle = tokio::spawn(async move {
         node.run(server_request_handle, client_request_handle).await;
     });
@@ -0,0 +1,242 @@
+/* Sparse Merkle tree over 256-bit keys.
+A key is a path from the root, one bit per level, most significant bit first. Empty subtrees hash to zero and a
+subtree holding a single leaf hashes to that leaf, so only the levels where keys diverge cost a hash.
+Leaves commit to their key, so a proof that ends at another key's leaf shows the requested key is absent. */
+
+use crate::hash::Hash256;
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+/// Key and value hash of a leaf.
+type Leaf = (Hash256, Hash256);
+
+/// Path from the root towards a key.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SparseMerkleProof {
+    /// Key and value hash of the leaf the path ends at: the key's own leaf, the only leaf of the subtree
+    /// the key would be in if it is absent, or `None` if that subtree is empty.
+    pub leaf: Option<Leaf>,
+    /// Sibling of every node on the path, starting below the root.
+    pub siblings: Vec<Hash256>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SparseMerkleTree {
+    /// Value hash of every key, sorted by key, which is also the order of the leaves.
+    leaves: BTreeMap<Hash256, Hash256>,
+}
+
+impl SparseMerkleTree {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, key: Hash256, value: &[u8]) {
+        self.leaves.insert(key, Hash256::digest(value));
+    }
+
+    pub fn remove(&mut self, key: &Hash256) {
+        self.leaves.remove(key);
+    }
+
+    /// Root of the tree, or zero if it is empty.
+    pub fn root(&self) -> Hash256 {
+        subtree_root(&self.sorted_leaves(), 0)
+    }
+
+    /// Proof of the value of `key`, or of its absence.
+    pub fn proof(&self, key: &Hash256) -> SparseMerkleProof {
+        let leaves = self.sorted_leaves();
+        let mut subtree = leaves.as_slice();
+        let mut siblings = Vec::new();
+        let mut depth = 0;
+
+        while subtree.len() > 1 {
+            let (left, right) = split(subtree, depth);
+            let (next, sibling) = if bit(key, depth) {
+                (right, left)
+            } else {
+                (left, right)
+            };
+            siblings.push(subtree_root(sibling, depth + 1));
+            subtree = next;
+            depth += 1;
+        }
+
+        SparseMerkleProof {
+            leaf: subtree.first().copied(),
+            siblings,
+        }
+    }
+
+    fn sorted_leaves(&self) -> Vec<Leaf> {
+        self.leaves
+            .iter()
+            .map(|(key, value_hash)| (*key, *value_hash))
+            .collect()
+    }
+}
+
+/// Checks `proof` against `root`: that `key` holds `value`, or that it is absent if `value` is `None`.
+pub fn verify_proof(
+    root: &Hash256,
+    key: &Hash256,
+    value: Option<&[u8]>,
+    proof: &SparseMerkleProof,
+) -> bool {
+    let depth = proof.siblings.len();
+    if depth > 256 {
+        return false;
+    }
+
+    match (value, proof.leaf) {
+        (Some(value), Some((leaf_key, value_hash))) => {
+            if leaf_key != *key || value_hash != Hash256::digest(value) {
+                return false;
+            }
+        }
+        (Some(_), None) => return false,
+        // Another key's leaf only proves absence if it sits where `key` would be.
+        (None, Some((leaf_key, _))) => {
+            if leaf_key == *key || (0..depth).any(|level| bit(&leaf_key, level) != bit(key, level))
+            {
+                return false;
+            }
+        }
+        (None, None) => {}
+    }
+
+    let mut hash = proof.leaf.map_or(Hash256::ZERO, |(key, value_hash)| {
+        leaf_hash(&key, &value_hash)
+    });
+    for (level, sibling) in proof.siblings.iter().enumerate().rev() {
+        hash = if bit(key, level) {
+            node_hash(sibling, &hash)
+        } else {
+            node_hash(&hash, sibling)
+        };
+    }
+    hash == *root
+}
+
+/// Root of the subtree holding `leaves`, all of which share their first `depth` bits.
+fn subtree_root(leaves: &[Leaf], depth: usize) -> Hash256 {
+    match leaves {
+        [] => Hash256::ZERO,
+        [(key, value_hash)] => leaf_hash(key, value_hash),
+        _ => {
+            let (left, right) = split(leaves, depth);
+            node_hash(
+                &subtree_root(left, depth + 1),
+                &subtree_root(right, depth + 1),
+            )
+        }
+    }
+}
+
+/// Splits sorted leaves into those going left and right at `depth`.
+fn split(leaves: &[Leaf], depth: usize) -> (&[Leaf], &[Leaf]) {
+    leaves.split_at(leaves.partition_point(|(key, _)| !bit(key, depth)))
+}
+
+fn bit(key: &Hash256, level: usize) -> bool {
+    key.as_bytes()[level / 8] & (0x80 >> (level % 8)) != 0
+}
+
+fn leaf_hash(key: &Hash256, value_hash: &Hash256) -> Hash256 {
+    let mut bytes = [0u8; 65];
+    bytes[0] = LEAF_PREFIX;
+    bytes[1..33].copy_from_slice(key.as_bytes());
+    bytes[33..].copy_from_slice(value_hash.as_bytes());
+    Hash256::digest(&bytes)
+}
+
+fn node_hash(left: &Hash256, right: &Hash256) -> Hash256 {
+    let mut bytes = [0u8; 65];
+    bytes[0] = NODE_PREFIX;
+    bytes[1..33].copy_from_slice(left.as_bytes());
+    bytes[33..].copy_from_slice(right.as_bytes());
+    Hash256::digest(&bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(n: u8) -> Hash256 {
+        Hash256::digest(&[n])
+    }
+
+    #[test]
+    fn proofs_show_values_and_absences() {
+        let mut tree = SparseMerkleTree::new();
+        assert_eq!(tree.root(), Hash256::ZERO);
+        assert!(verify_proof(&tree.root(), &key(0), None, &tree.proof(&key(0))));
+
+        for n in 0..20 {
+            tree.insert(key(n), &[n, n]);
+        }
+        let root = tree.root();
+
+        for n in 0..20 {
+            let proof = tree.proof(&key(n));
+            assert!(verify_proof(&root, &key(n), Some(&[n, n]), &proof));
+            assert!(!verify_proof(&root, &key(n), Some(&[n]), &proof));
+            assert!(!verify_proof(&root, &key(n), None, &proof));
+        }
+        for n in 20..40 {
+            let proof = tree.proof(&key(n));
+            assert!(verify_proof(&root, &key(n), None, &proof));
+            assert!(!verify_proof(&root, &key(n), Some(&[n, n]), &proof));
+        }
+    }
+
+    #[test]
+    fn proof_of_another_key_does_not_transfer() {
+        let mut tree = SparseMerkleTree::new();
+        tree.insert(key(1), b"one");
+        tree.insert(key(2), b"two");
+        let root = tree.root();
+
+        let proof = tree.proof(&key(1));
+        assert!(!verify_proof(&root, &key(2), Some(b"one"), &proof));
+        assert!(!verify_proof(&root, &key(2), None, &proof));
+    }
+
+    #[test]
+    fn keys_differing_in_the_last_bit_prove_at_full_depth() {
+        let low = Hash256::ZERO;
+        let mut bytes = [0u8; 32];
+        bytes[31] = 1;
+        let high = Hash256::from(bytes);
+
+        let mut tree = SparseMerkleTree::new();
+        tree.insert(low, b"low");
+        tree.insert(high, b"high");
+        let root = tree.root();
+
+        let proof = tree.proof(&high);
+        assert_eq!(proof.siblings.len(), 256);
+        assert!(verify_proof(&root, &high, Some(b"high"), &proof));
+        assert!(verify_proof(&root, &low, Some(b"low"), &tree.proof(&low)));
+    }
+
+    #[test]
+    fn root_follows_inserts_and_removals() {
+        let mut tree = SparseMerkleTree::new();
+        tree.insert(key(1), b"one");
+        let before = tree.root();
+        let stale = tree.proof(&key(1));
+
+        tree.insert(key(2), b"two");
+        assert_ne!(tree.root(), before);
+        assert!(!verify_proof(&tree.root(), &key(1), Some(b"one"), &stale));
+
+        tree.remove(&key(2));
+        assert_eq!(tree.root(), before);
+    }
+}
@@ -0,0 +1,352 @@
+// Account balances and nonces, built by applying blocks in chain order
+use crate::block::Block;
+use crate::error::StateError;
+use crate::hash::Hash256;
+use crate::smt::{verify_proof, SparseMerkleProof, SparseMerkleTree};
+use crate::transaction::{Address, Txn};
+
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Account {
+    pub balance: u64,
+    /// Number of transactions sent from this account, which is also the nonce its next transaction must use.
+    pub nonce: u64,
+}
+
+impl Account {
+    /// Value committed to by the state root: balance (u64 BE) || nonce (u64 BE).
+    pub fn encode(&self) -> [u8; 16] {
+        let mut bytes = [0u8; 16];
+        bytes[..8].copy_from_slice(&self.balance.to_be_bytes());
+        bytes[8..].copy_from_slice(&self.nonce.to_be_bytes());
+        bytes
+    }
+}
+
+/// An account as of some state root, with the proof that ties the two together.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct AccountProof {
+    pub address: Address,
+    pub account: Account,
+    pub proof: SparseMerkleProof,
+}
+
+impl AccountProof {
+    /// Checks the account against a state root from a block header.
+    /// An account the state doesn't hold must be the empty account, with a proof of absence.
+    pub fn verify(&self, state_root: &Hash256) -> bool {
+        let key = account_key(&self.address);
+        match self.proof.leaf {
+            Some((leaf_key, _)) if leaf_key == key => {
+                verify_proof(state_root, &key, Some(&self.account.encode()), &self.proof)
+            }
+            _ => {
+                self.account == Account::default()
+                    && verify_proof(state_root, &key, None, &self.proof)
+            }
+        }
+    }
+}
+
+/// Position of an account in the state tree.
+fn account_key(address: &Address) -> Hash256 {
+    Hash256::digest(address.as_bytes())
+}
+
+/// Coinbase payment that can't be spent yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ImmatureReward {
+    height: u32,
+    address: Address,
+    amount: u64,
+}
+
+/// What a block changed in the world state, so it can be reverted on reorg.
+#[derive(Debug, Clone, Default)]
+pub struct StateUndo {
+    /// Accounts the block touched, as they were before it. `None` for accounts it created.
+    accounts: Vec<(Address, Option<Account>)>,
+    /// Coinbase payments the block made spendable.
+    released: Vec<ImmatureReward>,
+    height: u32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WorldState {
+    accounts: HashMap<Address, Account>,
+    /// Height of the next block to apply.
+    next_height: u32,
+    /// Blocks before a coinbase payment can be spent.
+    coinbase_maturity: u32,
+    /// Coinbase payments of the last `coinbase_maturity` blocks, oldest first.
+    immature: VecDeque<ImmatureReward>,
+}
+
+impl WorldState {
+    pub fn new(coinbase_maturity: u32) -> Self {
+        Self {
+            coinbase_maturity,
+            ..Self::default()
+        }
+    }
+
+    pub fn account(&self, address: &Address) -> Account {
+        self.accounts.get(address).copied().unwrap_or_default()
+    }
+
+    pub fn balance_of(&self, address: &Address) -> u64 {
+        self.account(address).balance
+    }
+
+    /// Part of the balance that is still locked in coinbase payments younger than the maturity.
+    pub fn immature_balance_of(&self, address: &Address) -> u64 {
+        self.immature
+            .iter()
+            .filter(|reward| reward.address == *address)
+            .map(|reward| reward.amount)
+            .sum()
+    }
+
+    /// Balance a transaction in the next block may spend.
+    pub fn spendable_balance_of(&self, address: &Address) -> u64 {
+        self.balance_of(address)
+            .saturating_sub(self.immature_balance_of(address))
+    }
+
+    /// Sparse Merkle tree over every account.
+    pub fn state_tree(&self) -> SparseMerkleTree {
+        let mut tree = SparseMerkleTree::new();
+        for (address, account) in &self.accounts {
+            tree.insert(account_key(address), &account.encode());
+        }
+        tree
+    }
+
+    pub fn state_root(&self) -> Hash256 {
+        self.state_tree().root()
+    }
+
+    pub fn account_proof(&self, address: &Address) -> AccountProof {
+        AccountProof {
+            address: *address,
+            account: self.account(address),
+            proof: self.state_tree().proof(&account_key(address)),
+        }
+    }
+
+    pub fn accounts(&self) -> impl Iterator<Item = (&Address, &Account)> {
+        self.accounts.iter()
+    }
+
+    /// Checks that `txn` could be applied right now without changing anything.
+    pub fn check_txn(&self, txn: &Txn) -> Result<(), StateError> {
+        let sender = self.account(&txn.sender);
+
+        if txn.nonce != sender.nonce {
+            return Err(StateError::NonceMismatch {
+                address: txn.sender,
+                expected: sender.nonce,
+                found: txn.nonce,
+            });
+        }
+
+        if sender.balance < txn.total_cost() {
+            return Err(StateError::InsufficientBalance {
+                address: txn.sender,
+                balance: sender.balance,
+                amount: txn.total_cost(),
+            });
+        }
+
+        let spendable = self.spendable_balance_of(&txn.sender);
+        if spendable < txn.total_cost() {
+            return Err(StateError::ImmatureBalance {
+                address: txn.sender,
+                spendable,
+                amount: txn.total_cost(),
+            });
+        }
+        Ok(())
+    }
+
+    pub fn apply_txn(&mut self, txn: &Txn) -> Result<(), StateError> {
+        self.check_txn(txn)?;
+
+        let sender = self.accounts.entry(txn.sender).or_default();
+        sender.balance -= txn.total_cost();
+        sender.nonce += 1;
+
+        self.credit(txn.receiver, txn.amount as u64)
+    }
+
+    /// Applies every transaction of the block and then pays the coinbase, which must claim exactly
+    /// `subsidy` plus the fees. Leaves the state untouched if any of it fails.
+    /// The payment stays locked for `coinbase_maturity` blocks.
+    pub fn apply_block(&mut self, block: &Block, subsidy: u64) -> Result<StateUndo, StateError> {
+        let mut next = self.clone();
+        let height = block.block_header.index;
+        let mut undo = StateUndo {
+            height: self.next_height,
+            ..StateUndo::default()
+        };
+        let mut touched = block
+            .body
+            .txn_data
+            .iter()
+            .flat_map(|txn| [txn.sender, txn.receiver])
+            .chain([block.block_header.coinbase_txn.validator])
+            .collect::<Vec<_>>();
+        touched.sort();
+        touched.dedup();
+        undo.accounts = touched
+            .into_iter()
+            .map(|address| (address, self.accounts.get(&address).copied()))
+            .collect();
+
+        for txn in &block.body.txn_data {
+            next.apply_txn(txn)?;
+        }
+
+        let fees = block.body.txn_data.iter().map(|txn| txn.fee as u64).sum::<u64>();
+        let coinbase = &block.block_header.coinbase_txn;
+        let expected = subsidy.saturating_add(fees);
+        if coinbase.amount != expected {
+            return Err(StateError::InvalidCoinbaseAmount {
+                expected,
+                found: coinbase.amount,
+            });
+        }
+        next.credit(coinbase.validator, coinbase.amount)?;
+        next.immature.push_back(ImmatureReward {
+            height,
+            address: coinbase.validator,
+            amount: coinbase.amount,
+        });
+
+        next.next_height = height + 1;
+        undo.released = next.release_matured();
+        *self = next;
+        Ok(undo)
+    }
+
+    /// Reverts a block applied with `apply_block`.
+    /// Released payments go back in front of the queue before the block's own payment is dropped from its end,
+    /// since with no maturity the block may have released its own payment.
+    pub fn undo_block(&mut self, undo: &StateUndo) {
+        for (address, account) in &undo.accounts {
+            match account {
+                Some(account) => self.accounts.insert(*address, *account),
+                None => self.accounts.remove(address),
+            };
+        }
+        for reward in undo.released.iter().rev() {
+            self.immature.push_front(*reward);
+        }
+        self.immature.pop_back();
+        self.next_height = undo.height;
+    }
+
+    /// Unlocks the coinbase payments a transaction in the next block may spend and returns them.
+    fn release_matured(&mut self) -> Vec<ImmatureReward> {
+        let mut released = Vec::new();
+        while let Some(reward) = self.immature.front() {
+            if reward.height.saturating_add(self.coinbase_maturity) > self.next_height {
+                break;
+            }
+            released.extend(self.immature.pop_front());
+        }
+        released
+    }
+
+    fn credit(&mut self, address: Address, amount: u64) -> Result<(), StateError> {
+        let account = self.accounts.entry(address).or_default();
+        account.balance = account
+            .balance
+            .checked_add(amount)
+            .ok_or(StateError::BalanceOverflow(address))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{Body, GENESIS_PREVIOUS_HASH};
+    use ed25519_dalek::SigningKey;
+
+    fn state_paying(address: Address, amount: u64) -> WorldState {
+        let mut block = Block::new(0, GENESIS_PREVIOUS_HASH, Body::default());
+        block.block_header.coinbase_txn.validator = address;
+        block.block_header.coinbase_txn.amount = amount;
+
+        let mut state = WorldState::new(0);
+        state.apply_block(&block, amount).unwrap();
+        state
+    }
+
+    #[test]
+    fn account_proof_checks_against_the_state_root() {
+        let miner = Address::from([1; 20]);
+        let state = state_paying(miner, 50);
+        let root = state.state_root();
+
+        let proof = state.account_proof(&miner);
+        assert_eq!(proof.account.balance, 50);
+        assert!(proof.verify(&root));
+        assert!(!proof.verify(&Hash256::digest(b"another root")));
+
+        let mut forged = proof.clone();
+        forged.account.balance = 51;
+        assert!(!forged.verify(&root));
+    }
+
+    #[test]
+    fn missing_account_is_proven_empty() {
+        let state = state_paying(Address::from([1; 20]), 50);
+        let root = state.state_root();
+
+        let proof = state.account_proof(&Address::from([2; 20]));
+        assert_eq!(proof.account, Account::default());
+        assert!(proof.verify(&root));
+
+        let mut forged = proof.clone();
+        forged.account.balance = 1;
+        assert!(!forged.verify(&root));
+    }
+
+    #[test]
+    fn undo_restores_the_state_before_each_block() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let sender = Address::from_public_key(&key.verifying_key());
+        let miner = Address::from([1; 20]);
+
+        for maturity in [0, 2] {
+            let mut state = WorldState::new(maturity);
+            for index in 0..4 {
+                let mut body = Body::default();
+                if index == 3 {
+                    let mut txn = Txn::new(&key.verifying_key(), Address::from([2; 20]), 10, 1, 0);
+                    txn.sign(&key);
+                    body.txn_data.push(txn);
+                }
+                let mut block = Block::new(index, GENESIS_PREVIOUS_HASH, body);
+                let fees = block.body.txn_data.iter().map(|txn| txn.fee as u64).sum::<u64>();
+                block.block_header.coinbase_txn.validator = if index == 0 { sender } else { miner };
+                block.block_header.coinbase_txn.amount = 50 + fees;
+
+                let before = state.clone();
+                let undo = state.apply_block(&block, 50).unwrap();
+                let after = state.clone();
+
+                state.undo_block(&undo);
+                assert_eq!(state, before, "maturity {maturity}, block {index}");
+                assert_eq!(state.state_root(), before.state_root());
+
+                state = after;
+            }
+            assert_eq!(state.balance_of(&sender), 39);
+        }
+    }
+}
@@ -0,0 +1,486 @@
+/* Persistent chain storage.
+Blocks are kept in an append-only log and indexed by hash and height when the store is opened.
+A crash in the middle of an append leaves a partial record at the end of the log, which is cut off on the next open. */
+
+use crate::addrbook::AddressEntry;
+use crate::ban::Ban;
+use crate::block::Block;
+use crate::error::StorageError;
+use crate::hash::Hash256;
+use crate::transaction::Txn;
+
+use anyhow::Result;
+use log::{info, warn};
+use sha2::{Digest as _, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read as _, Seek as _, SeekFrom, Write as _};
+use std::path::{Path, PathBuf};
+
+const BLOCK_LOG: &str = "blocks.log";
+const MEMPOOL_FILE: &str = "mempool.bin";
+const ADDRESS_FILE: &str = "peers.bin";
+const BAN_FILE: &str = "bans.bin";
+
+/// Length prefix plus truncated SHA-256 checksum in front of every record in the block log.
+const RECORD_HEADER_LEN: usize = 8;
+
+pub trait ChainStore: Send {
+    /// Appends a block. Blocks must be appended after their parent.
+    fn append_block(&mut self, block: &Block) -> Result<()>;
+
+    /// All stored blocks, in the order they were appended.
+    fn blocks(&self) -> Result<Vec<Block>>;
+
+    fn block(&self, hash: &Hash256) -> Result<Option<Block>>;
+
+    /// Hashes of all stored blocks at the given height, across branches.
+    fn hashes_at_height(&self, height: u32) -> Vec<Hash256>;
+
+    fn save_mempool(&mut self, txns: &[Txn]) -> Result<()>;
+
+    fn load_mempool(&self) -> Result<Vec<Txn>>;
+
+    fn save_addresses(&mut self, entries: &[AddressEntry]) -> Result<()>;
+
+    fn load_addresses(&self) -> Result<Vec<AddressEntry>>;
+
+    fn save_bans(&mut self, bans: &[Ban]) -> Result<()>;
+
+    fn load_bans(&self) -> Result<Vec<Ban>>;
+}
+
+/// Hash and height index over the stored blocks.
+#[derive(Debug, Default)]
+struct BlockIndex {
+    by_hash: HashMap<Hash256, usize>,
+    by_height: BTreeMap<u32, Vec<Hash256>>,
+}
+
+impl BlockIndex {
+    fn insert(&mut self, block: &Block, position: usize) {
+        let hash = block.block_header.current_hash;
+        self.by_height
+            .entry(block.block_header.index)
+            .or_default()
+            .push(hash);
+        self.by_hash.insert(hash, position);
+    }
+
+    fn hashes_at_height(&self, height: u32) -> Vec<Hash256> {
+        self.by_height.get(&height).cloned().unwrap_or_default()
+    }
+}
+
+/// Store used when the node runs without a data directory.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    blocks: Vec<Block>,
+    index: BlockIndex,
+    mempool: Vec<Txn>,
+    addresses: Vec<AddressEntry>,
+    bans: Vec<Ban>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl ChainStore for MemoryStore {
+    fn append_block(&mut self, block: &Block) -> Result<()> {
+        self.index.insert(block, self.blocks.len());
+        self.blocks.push(block.clone());
+        Ok(())
+    }
+
+    fn blocks(&self) -> Result<Vec<Block>> {
+        Ok(self.blocks.clone())
+    }
+
+    fn block(&self, hash: &Hash256) -> Result<Option<Block>> {
+        Ok(self
+            .index
+            .by_hash
+            .get(hash)
+            .map(|position| self.blocks[*position].clone()))
+    }
+
+    fn hashes_at_height(&self, height: u32) -> Vec<Hash256> {
+        self.index.hashes_at_height(height)
+    }
+
+    fn save_mempool(&mut self, txns: &[Txn]) -> Result<()> {
+        self.mempool = txns.to_vec();
+        Ok(())
+    }
+
+    fn load_mempool(&self) -> Result<Vec<Txn>> {
+        Ok(self.mempool.clone())
+    }
+
+    fn save_addresses(&mut self, entries: &[AddressEntry]) -> Result<()> {
+        self.addresses = entries.to_vec();
+        Ok(())
+    }
+
+    fn load_addresses(&self) -> Result<Vec<AddressEntry>> {
+        Ok(self.addresses.clone())
+    }
+
+    fn save_bans(&mut self, bans: &[Ban]) -> Result<()> {
+        self.bans = bans.to_vec();
+        Ok(())
+    }
+
+    fn load_bans(&self) -> Result<Vec<Ban>> {
+        Ok(self.bans.clone())
+    }
+}
+
+/// Store backed by files in a data directory.
+#[derive(Debug)]
+pub struct FileStore {
+    dir: PathBuf,
+    log: File,
+    // Byte offset of each record in the log, in append order.
+    offsets: Vec<u64>,
+    index: BlockIndex,
+}
+
+impl FileStore {
+    /// Opens the store in `dir`, creating it if needed, and truncates a partially written last record.
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut log = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(dir.join(BLOCK_LOG))?;
+
+        let mut contents = Vec::new();
+        log.read_to_end(&mut contents)?;
+
+        let mut offsets = Vec::new();
+        let mut index = BlockIndex::default();
+        let mut offset = 0;
+
+        while offset < contents.len() {
+            match Self::decode_record(&contents[offset..]) {
+                Ok((block, len)) => {
+                    index.insert(&block, offsets.len());
+                    offsets.push(offset as u64);
+                    offset += len;
+                }
+                Err(e) => {
+                    warn!(
+                        "Truncating block log at byte {} of {}: {}",
+                        offset,
+                        contents.len(),
+                        e
+                    );
+                    log.set_len(offset as u64)?;
+                    log.sync_all()?;
+                    break;
+                }
+            }
+        }
+
+        info!("Opened block log with {} blocks in {:?}", offsets.len(), dir);
+
+        Ok(Self {
+            dir,
+            log,
+            offsets,
+            index,
+        })
+    }
+
+    /// Writes to a temporary file first so a crash never leaves a half-written file behind.
+    fn write_file(&self, name: &str, contents: &[u8]) -> Result<()> {
+        let path = self.dir.join(name);
+        let tmp = path.with_extension("tmp");
+
+        let mut file = File::create(&tmp)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    /// Reads a file written by `write_file`. A missing file reads as `None`, and so does an unreadable one,
+    /// which is discarded with a warning naming `what` it held.
+    fn read_file<T: serde::de::DeserializeOwned>(&self, name: &str, what: &str) -> Result<Option<T>> {
+        let path = self.dir.join(name);
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        match bincode::deserialize(&fs::read(path)?) {
+            Ok(value) => Ok(Some(value)),
+            Err(e) => {
+                warn!("Discarding unreadable {} file: {}", what, e);
+                Ok(None)
+            }
+        }
+    }
+
+    fn encode_record(block: &Block) -> Result<Vec<u8>> {
+        let payload = bincode::serialize(block)?;
+        let mut record = Vec::with_capacity(RECORD_HEADER_LEN + payload.len());
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&Self::checksum(&payload));
+        record.extend_from_slice(&payload);
+        Ok(record)
+    }
+
+    /// Decodes the record at the start of `bytes` and returns it with its total length.
+    fn decode_record(bytes: &[u8]) -> Result<(Block, usize), StorageError> {
+        if bytes.len() < RECORD_HEADER_LEN {
+            return Err(StorageError::TruncatedRecord);
+        }
+
+        let len = u32::from_le_bytes(bytes[0..4].try_into().unwrap()) as usize;
+        let end = RECORD_HEADER_LEN + len;
+        if bytes.len() < end {
+            return Err(StorageError::TruncatedRecord);
+        }
+
+        let payload = &bytes[RECORD_HEADER_LEN..end];
+        if bytes[4..8] != Self::checksum(payload) {
+            return Err(StorageError::ChecksumMismatch);
+        }
+
+        let block = bincode::deserialize(payload).map_err(|_| StorageError::CorruptRecord)?;
+        Ok((block, end))
+    }
+
+    fn checksum(payload: &[u8]) -> [u8; 4] {
+        let hash = Sha256::digest(payload);
+        [hash[0], hash[1], hash[2], hash[3]]
+    }
+
+    fn read_record(&self, position: usize) -> Result<Block> {
+        let mut log = File::open(self.dir.join(BLOCK_LOG))?;
+        log.seek(SeekFrom::Start(self.offsets[position]))?;
+
+        let mut header = [0u8; RECORD_HEADER_LEN];
+        log.read_exact(&mut header)?;
+        let len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+
+        let mut record = header.to_vec();
+        record.resize(RECORD_HEADER_LEN + len, 0);
+        log.read_exact(&mut record[RECORD_HEADER_LEN..])?;
+
+        Ok(Self::decode_record(&record)?.0)
+    }
+}
+
+impl ChainStore for FileStore {
+    fn append_block(&mut self, block: &Block) -> Result<()> {
+        let record = Self::encode_record(block)?;
+        let offset = self.log.metadata()?.len();
+
+        if let Err(e) = self.log.write_all(&record).and_then(|_| self.log.sync_data()) {
+            // Drop whatever part of the record made it to disk so the next append starts clean.
+            self.log.set_len(offset)?;
+            return Err(e.into());
+        }
+
+        self.index.insert(block, self.offsets.len());
+        self.offsets.push(offset);
+        Ok(())
+    }
+
+    fn blocks(&self) -> Result<Vec<Block>> {
+        let contents = fs::read(self.dir.join(BLOCK_LOG))?;
+        let mut blocks = Vec::with_capacity(self.offsets.len());
+        let mut offset = 0;
+
+        while offset < contents.len() {
+            let (block, len) = Self::decode_record(&contents[offset..])?;
+            blocks.push(block);
+            offset += len;
+        }
+        Ok(blocks)
+    }
+
+    fn block(&self, hash: &Hash256) -> Result<Option<Block>> {
+        match self.index.by_hash.get(hash) {
+            Some(position) => Ok(Some(self.read_record(*position)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn hashes_at_height(&self, height: u32) -> Vec<Hash256> {
+        self.index.hashes_at_height(height)
+    }
+
+    fn save_mempool(&mut self, txns: &[Txn]) -> Result<()> {
+        self.write_file(MEMPOOL_FILE, &bincode::serialize(txns)?)
+    }
+
+    fn load_mempool(&self) -> Result<Vec<Txn>> {
+        Ok(self.read_file(MEMPOOL_FILE, "mempool")?.unwrap_or_default())
+    }
+
+    fn save_addresses(&mut self, entries: &[AddressEntry]) -> Result<()> {
+        self.write_file(ADDRESS_FILE, &bincode::serialize(entries)?)
+    }
+
+    fn load_addresses(&self) -> Result<Vec<AddressEntry>> {
+        Ok(self.read_file(ADDRESS_FILE, "address book")?.unwrap_or_default())
+    }
+
+    fn save_bans(&mut self, bans: &[Ban]) -> Result<()> {
+        self.write_file(BAN_FILE, &bincode::serialize(bans)?)
+    }
+
+    fn load_bans(&self) -> Result<Vec<Ban>> {
+        Ok(self.read_file(BAN_FILE, "ban list")?.unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{Body, GENESIS_PREVIOUS_HASH};
+
+    /// Empty directory of its own for every test, removed when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("blockchain-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn block_at(index: u32) -> Block {
+        let mut block = Block::new(index, GENESIS_PREVIOUS_HASH, Body::default());
+        block.block_header.current_hash = block.block_header.hash();
+        block
+    }
+
+    /// Writes `count` blocks at heights 0.. and returns their hashes.
+    fn store_with_blocks(dir: &TempDir, count: u32) -> Vec<Hash256> {
+        let mut store = FileStore::open(&dir.0).unwrap();
+        (0..count)
+            .map(|index| {
+                let block = block_at(index);
+                store.append_block(&block).unwrap();
+                block.block_header.current_hash
+            })
+            .collect()
+    }
+
+    fn indices(blocks: &[Block]) -> Vec<u32> {
+        blocks.iter().map(|block| block.block_header.index).collect()
+    }
+
+    #[test]
+    fn partial_last_record_is_cut_off() {
+        let dir = TempDir::new("partial-record");
+        store_with_blocks(&dir, 3);
+
+        let log = dir.0.join(BLOCK_LOG);
+        let len = fs::metadata(&log).unwrap().len();
+        OpenOptions::new().write(true).open(&log).unwrap().set_len(len - 5).unwrap();
+
+        let mut store = FileStore::open(&dir.0).unwrap();
+        assert_eq!(indices(&store.blocks().unwrap()), vec![0, 1]);
+
+        // Appends after recovery start on a record boundary.
+        store.append_block(&Block::new(2, GENESIS_PREVIOUS_HASH, Body::default())).unwrap();
+        let reopened = FileStore::open(&dir.0).unwrap();
+        assert_eq!(indices(&reopened.blocks().unwrap()), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn index_only_holds_blocks_kept_by_recovery() {
+        let dir = TempDir::new("index-recovery");
+        let hashes = store_with_blocks(&dir, 3);
+
+        let log = dir.0.join(BLOCK_LOG);
+        let len = fs::metadata(&log).unwrap().len();
+        OpenOptions::new().write(true).open(&log).unwrap().set_len(len - 5).unwrap();
+
+        let mut store = FileStore::open(&dir.0).unwrap();
+        assert_eq!(store.hashes_at_height(1), vec![hashes[1]]);
+        assert_eq!(store.block(&hashes[1]).unwrap().unwrap().block_header.index, 1);
+        assert!(store.hashes_at_height(2).is_empty());
+        assert!(store.block(&hashes[2]).unwrap().is_none());
+
+        // A block appended after recovery is found at the offset it was written to.
+        let replacement = block_at(2);
+        let hash = replacement.block_header.current_hash;
+        store.append_block(&replacement).unwrap();
+        assert_eq!(store.hashes_at_height(2), vec![hash]);
+        assert_eq!(store.block(&hash).unwrap().unwrap().block_header.current_hash, hash);
+    }
+
+    #[test]
+    fn memory_store_indexes_blocks_by_hash_and_height() {
+        let mut store = MemoryStore::new();
+        let (first, second) = (block_at(1), block_at(1));
+        store.append_block(&first).unwrap();
+        store.append_block(&second).unwrap();
+
+        let hashes = vec![first.block_header.current_hash, second.block_header.current_hash];
+        assert_eq!(store.hashes_at_height(1), hashes);
+        assert!(store.hashes_at_height(0).is_empty());
+        assert_eq!(store.block(&hashes[1]).unwrap().unwrap().block_header.nonce, second.block_header.nonce);
+        assert!(store.block(&Hash256::ZERO).unwrap().is_none());
+    }
+
+    #[test]
+    fn record_failing_its_checksum_is_cut_off() {
+        let dir = TempDir::new("checksum");
+        store_with_blocks(&dir, 3);
+
+        let log = dir.0.join(BLOCK_LOG);
+        let mut contents = fs::read(&log).unwrap();
+        let last = contents.len() - 1;
+        contents[last] ^= 0xff;
+        fs::write(&log, contents).unwrap();
+
+        let store = FileStore::open(&dir.0).unwrap();
+        assert_eq!(indices(&store.blocks().unwrap()), vec![0, 1]);
+    }
+
+    #[test]
+    fn address_book_survives_a_restart() {
+        let dir = TempDir::new("addresses");
+        let entry = AddressEntry {
+            address: ([10, 0, 0, 1], 1729).into(),
+            last_seen: 1_000,
+            successes: 2,
+            failures: 1,
+        };
+        FileStore::open(&dir.0).unwrap().save_addresses(&[entry]).unwrap();
+        assert_eq!(FileStore::open(&dir.0).unwrap().load_addresses().unwrap(), vec![entry]);
+    }
+
+    #[test]
+    fn unreadable_files_are_discarded() {
+        let dir = TempDir::new("unreadable");
+        let mut store = FileStore::open(&dir.0).unwrap();
+        assert!(store.load_mempool().unwrap().is_empty());
+
+        store.save_bans(&[Ban { ip: [10, 0, 0, 1].into(), until: 1 }]).unwrap();
+        assert_eq!(store.load_bans().unwrap().len(), 1);
+
+        fs::write(dir.0.join(BAN_FILE), [0xff; 3]).unwrap();
+        assert!(store.load_bans().unwrap().is_empty());
+    }
+}
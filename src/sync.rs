@@ -0,0 +1,610 @@
+/* Headers-first block download.
+The node first asks one peer for the headers of its chain, in batches that start from the fork point a locator of
+our own chain points at, and checks them the way a block's header is checked: they must link up, carry the target
+the retargeting rules expect, meet it, and have sane timestamps. Only once they add up to more work than our chain
+are the blocks themselves fetched, from every peer at once: blocks are requested in chain order within a window ahead of the last
+connected one, and a request that goes unanswered for too long is handed to another peer. Blocks are connected
+strictly in header order, so a slow peer can stall the window but never make the node skip a block. A block that
+can't be had after `MAX_BLOCK_REQUESTS` requests ends the sync, and the peer that announced it is held to account. */
+
+use crate::block::{Block, BlockHeader, BLOCK_VERSION, GENESIS_PREVIOUS_HASH};
+use crate::blockchain::BlockChain;
+use crate::error::{SyncError, ValidationError};
+use crate::hash::Hash256;
+use crate::params::ChainParams;
+use crate::pow::{meets_target, work_from_compact, U256};
+use crate::validation::{check_timestamp, median_time_past, MEDIAN_TIME_SPAN};
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Most headers sent in answer to a single `GetHeaders`.
+pub const MAX_HEADERS: usize = 2000;
+
+/// Most block hashes announced in answer to a single `GetBlocks`.
+pub const MAX_BLOCKS_INV: usize = 500;
+
+/// Most headers kept whose blocks haven't been connected yet. No more are asked for until blocks catch up.
+pub const MAX_PENDING_HEADERS: usize = 10 * MAX_HEADERS;
+
+/// How many times a block is requested before the sync gives up on the chain it belongs to.
+pub const MAX_BLOCK_REQUESTS: u32 = 4;
+
+/// How far past the last connected block bodies may be requested.
+pub const DOWNLOAD_WINDOW: usize = 64;
+
+/// Most blocks requested from a single peer at once.
+pub const MAX_BLOCKS_PER_PEER: usize = 16;
+
+/// How long a peer has to answer a request for headers before another peer is asked.
+pub const HEADERS_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// How long a peer has to deliver a requested block before it is asked of another peer.
+pub const BLOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Message the node should send to move the sync along.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncRequest {
+    Headers {
+        peer: SocketAddr,
+        locator: Vec<Hash256>,
+    },
+    Blocks {
+        peer: SocketAddr,
+        hashes: Vec<Hash256>,
+    },
+}
+
+/// How far a sync has got.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyncProgress {
+    /// Blocks connected since the sync started.
+    pub connected: usize,
+    /// Headers received past the chain we had, all of which are connected once the sync is done.
+    pub headers: usize,
+    /// Height of the last header received.
+    pub best_header: Option<u32>,
+    pub in_flight: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct InFlight {
+    peer: SocketAddr,
+    since: Instant,
+}
+
+#[derive(Debug, Default)]
+pub struct BlockSync {
+    active: bool,
+    /// Whether the headers carry more work than our chain, which is when their blocks are worth fetching.
+    ahead: bool,
+    /// Checked headers of blocks that weren't in the tree when they arrived, in chain order.
+    /// Connected ones are dropped from the front now and then, once they are in the tree.
+    headers: Vec<BlockHeader>,
+    /// Number of connected headers dropped from `headers`.
+    drained: usize,
+    /// Number of `headers` whose blocks have been connected.
+    connected: usize,
+    /// Number of `headers` whose blocks have been requested at least once.
+    requested: usize,
+    in_flight: HashMap<Hash256, InFlight>,
+    /// Blocks that arrived ahead of the next one to connect, along with the connection they came on.
+    downloaded: HashMap<Hash256, (Block, SocketAddr)>,
+    /// Blocks to request again, along with the peer that failed to deliver them.
+    retry: VecDeque<(Hash256, SocketAddr)>,
+    /// How many times each block not yet connected has been requested.
+    attempts: HashMap<Hash256, u32>,
+    /// Peer whose headers announced each block not yet connected.
+    announced_by: HashMap<Hash256, SocketAddr>,
+    /// Peer the next batch of headers comes from, and when it was asked for it.
+    header_peer: Option<(SocketAddr, Option<Instant>)>,
+    /// Last header of the last batch, where the next batch starts even if we already had that block.
+    last_header: Option<Hash256>,
+    headers_done: bool,
+    /// Peers that failed to answer a request for headers, or sent invalid ones.
+    failed: HashSet<SocketAddr>,
+}
+
+impl BlockSync {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_syncing(&self) -> bool {
+        self.active
+    }
+
+    /// Whether the sync is downloading a chain with more work than ours.
+    pub fn is_ahead(&self) -> bool {
+        self.active && self.ahead
+    }
+
+    /// Starts syncing with the headers of `peer`. Does nothing if a sync is already running.
+    pub fn start(&mut self, peer: SocketAddr) {
+        if self.active {
+            return;
+        }
+        *self = Self {
+            active: true,
+            header_peer: Some((peer, None)),
+            ..Self::default()
+        };
+    }
+
+    /// Gives up on the sync, keeping whatever was connected.
+    pub fn abort(&mut self) {
+        *self = Self::default();
+    }
+
+    pub fn progress(&self) -> SyncProgress {
+        SyncProgress {
+            connected: self.drained + self.connected,
+            headers: self.drained + self.headers.len(),
+            best_header: self.headers.last().map(|header| header.index),
+            in_flight: self.in_flight.len(),
+        }
+    }
+
+    /// Checks and stores headers sent by `from`, the listen address the peer gave in its handshake.
+    /// Headers nobody asked `from` for are ignored.
+    /// A peer sending invalid headers isn't asked for headers again. A peer whose chain ends without
+    /// more work than ours ends the sync.
+    pub fn receive_headers(
+        &mut self,
+        from: SocketAddr,
+        headers: Vec<BlockHeader>,
+        chain: &BlockChain,
+    ) -> Result<(), SyncError> {
+        match self.header_peer {
+            Some((peer, Some(_))) if peer == from => {}
+            _ => return Ok(()),
+        }
+
+        let appended = if headers.len() > MAX_HEADERS {
+            Err(SyncError::TooManyHeaders {
+                count: headers.len(),
+                max: MAX_HEADERS,
+            })
+        } else {
+            self.append_headers(from, &headers, chain)
+                .map_err(SyncError::from)
+        };
+        if let Err(e) = appended {
+            self.failed.insert(from);
+            self.header_peer = None;
+            return Err(e);
+        }
+
+        if let Some(last) = headers.last() {
+            self.last_header = Some(last.current_hash);
+        }
+
+        let ours = chain.total_work();
+        let theirs = self.header_work(chain);
+        if theirs.is_some_and(|theirs| theirs > ours) {
+            self.ahead = true;
+        }
+
+        if headers.len() < MAX_HEADERS {
+            self.headers_done = true;
+            self.header_peer = None;
+            if !self.ahead {
+                // The peer's chain ends without overtaking ours, so none of its blocks are worth having.
+                self.abort();
+                return match theirs {
+                    Some(theirs) if theirs < ours => Err(SyncError::LessWork { ours, theirs }),
+                    _ => Ok(()),
+                };
+            }
+        } else {
+            // Ask the same peer for the next batch.
+            self.header_peer = Some((from, None));
+        }
+        Ok(())
+    }
+
+    fn append_headers(
+        &mut self,
+        from: SocketAddr,
+        headers: &[BlockHeader],
+        chain: &BlockChain,
+    ) -> Result<(), ValidationError> {
+        // Enough ancestors for both the retarget window and the median time past.
+        let span = (chain.params().retarget_interval.max(1) as usize + 1).max(MEDIAN_TIME_SPAN);
+
+        for header in headers {
+            // The first headers of the first batch may be blocks we already have.
+            if self.headers.is_empty() && chain.contains(&header.current_hash) {
+                continue;
+            }
+
+            let ancestors = self.ancestors(header, chain, span);
+            match ancestors.first() {
+                Some(parent) if parent.current_hash == header.previous_hash => {}
+                None if self.headers.is_empty() && header.index == 0 => {}
+                _ => return Err(ValidationError::UnconnectedHeader(header.current_hash)),
+            }
+
+            check_header(header, &ancestors, chain.params())?;
+            self.announced_by.insert(header.current_hash, from);
+            self.headers.push(header.clone());
+        }
+        Ok(())
+    }
+
+    /// Up to `count` headers before `header`, newest first: the headers waiting here, then the blocks of the tree
+    /// they fork from.
+    fn ancestors<'a>(
+        &'a self,
+        header: &BlockHeader,
+        chain: &'a BlockChain,
+        count: usize,
+    ) -> Vec<&'a BlockHeader> {
+        let fork = self
+            .headers
+            .first()
+            .map_or(header.previous_hash, |first| first.previous_hash);
+        self.headers
+            .iter()
+            .rev()
+            .chain(chain.ancestors(&fork).map(|block| &block.block_header))
+            .take(count)
+            .collect()
+    }
+
+    /// Total work of the chain the headers lead to, if there are any.
+    fn header_work(&self, chain: &BlockChain) -> Option<U256> {
+        let first = self.headers.first()?;
+        let fork = chain.work_of(&first.previous_hash).unwrap_or(U256::ZERO);
+        Some(
+            self.headers
+                .iter()
+                .fold(fork, |work, header| work.saturating_add(work_from_compact(header.bits))),
+        )
+    }
+
+    /// Whether `hash` is a block this sync is waiting for.
+    pub fn expects(&self, hash: &Hash256) -> bool {
+        self.in_flight.contains_key(hash) || self.retry.iter().any(|(retry, _)| retry == hash)
+    }
+
+    /// Stores a block this sync was waiting for, sent on the connection from `source`, until it is its turn to be
+    /// connected.
+    pub fn receive_block(&mut self, block: Block, source: SocketAddr) {
+        let hash = block.block_header.current_hash;
+        self.in_flight.remove(&hash);
+        self.retry.retain(|(retry, _)| *retry != hash);
+        self.downloaded.insert(hash, (block, source));
+    }
+
+    /// Next block to add to the tree, if it has arrived, along with the connection it came on.
+    pub fn next_block(&mut self) -> Option<(Block, SocketAddr)> {
+        let hash = self.headers.get(self.connected)?.current_hash;
+        let block = self.downloaded.remove(&hash)?;
+        self.connected += 1;
+        self.attempts.remove(&hash);
+        self.announced_by.remove(&hash);
+
+        // Connected headers are in the tree, which is where later headers find their ancestors from then on.
+        if self.connected >= MAX_HEADERS {
+            self.headers.drain(..self.connected);
+            self.requested -= self.connected;
+            self.drained += self.connected;
+            self.connected = 0;
+        }
+        Some(block)
+    }
+
+    /// Handles timeouts and fills the download window. Returns the requests to send, spread over `peers`.
+    /// The sync ends once every header is in and every block behind them is connected, or once it is clear the
+    /// headers won't get us a chain with more work. A block nobody delivers ends it with an error naming the peer
+    /// that announced it.
+    pub fn poll(
+        &mut self,
+        chain: &BlockChain,
+        peers: &[SocketAddr],
+    ) -> Result<Vec<SyncRequest>, SyncError> {
+        if !self.active {
+            return Ok(vec![]);
+        }
+
+        // Headers that haven't overtaken our chain by now aren't worth keeping more of.
+        if !self.ahead && (self.headers_done || self.headers.len() >= MAX_PENDING_HEADERS) {
+            self.abort();
+            return Ok(vec![]);
+        }
+
+        let now = Instant::now();
+        let mut requests = Vec::new();
+
+        if let Some(request) = self.poll_headers(chain, peers, now) {
+            requests.push(request);
+        }
+
+        let timed_out = self
+            .in_flight
+            .iter()
+            .filter(|(_, request)| now.duration_since(request.since) > BLOCK_TIMEOUT)
+            .map(|(hash, request)| (*hash, request.peer))
+            .collect::<Vec<_>>();
+        for (hash, peer) in timed_out {
+            self.in_flight.remove(&hash);
+            if self.attempts.get(&hash).copied().unwrap_or(0) >= MAX_BLOCK_REQUESTS {
+                let peer = self.announced_by.get(&hash).copied().unwrap_or(peer);
+                self.abort();
+                return Err(SyncError::BlockUnavailable { hash, peer });
+            }
+            self.retry.push_back((hash, peer));
+        }
+
+        let mut assigned = HashMap::<SocketAddr, Vec<Hash256>>::new();
+        let mut load = HashMap::<SocketAddr, usize>::new();
+        for request in self.in_flight.values() {
+            *load.entry(request.peer).or_default() += 1;
+        }
+
+        // Requests that timed out go to another peer if there is one.
+        while let Some(&(hash, failed)) = self.retry.front() {
+            let Some(peer) = least_loaded(peers, &load, Some(failed)) else {
+                break;
+            };
+            self.retry.pop_front();
+            self.request_block(hash, peer, now, &mut load, &mut assigned);
+        }
+
+        while self.ahead
+            && self.requested < self.headers.len()
+            && self.requested < self.connected + DOWNLOAD_WINDOW
+        {
+            let Some(peer) = least_loaded(peers, &load, None) else {
+                break;
+            };
+            let hash = self.headers[self.requested].current_hash;
+            self.requested += 1;
+            self.request_block(hash, peer, now, &mut load, &mut assigned);
+        }
+
+        requests.extend(
+            assigned
+                .into_iter()
+                .map(|(peer, hashes)| SyncRequest::Blocks { peer, hashes }),
+        );
+
+        if self.headers_done && self.connected == self.headers.len() {
+            self.active = false;
+        }
+        Ok(requests)
+    }
+
+    fn poll_headers(
+        &mut self,
+        chain: &BlockChain,
+        peers: &[SocketAddr],
+        now: Instant,
+    ) -> Option<SyncRequest> {
+        if self.headers_done {
+            return None;
+        }
+
+        if let Some((peer, Some(since))) = self.header_peer {
+            if now.duration_since(since) <= HEADERS_TIMEOUT {
+                return None;
+            }
+            self.failed.insert(peer);
+            self.header_peer = None;
+        }
+
+        // Wait for blocks to be connected before taking in more headers.
+        if self.headers.len() - self.connected >= MAX_PENDING_HEADERS {
+            return None;
+        }
+
+        let peer = match self.header_peer {
+            Some((peer, _)) => peer,
+            None => match peers.iter().find(|peer| !self.failed.contains(peer)) {
+                Some(peer) => *peer,
+                // Nobody left to ask: sync whatever headers there are.
+                None => {
+                    self.headers_done = true;
+                    return None;
+                }
+            },
+        };
+        self.header_peer = Some((peer, Some(now)));
+
+        let mut locator = chain.locator();
+        if let Some(last) = self.last_header {
+            locator.insert(0, last);
+        }
+        Some(SyncRequest::Headers { peer, locator })
+    }
+
+    fn request_block(
+        &mut self,
+        hash: Hash256,
+        peer: SocketAddr,
+        now: Instant,
+        load: &mut HashMap<SocketAddr, usize>,
+        assigned: &mut HashMap<SocketAddr, Vec<Hash256>>,
+    ) {
+        self.in_flight.insert(hash, InFlight { peer, since: now });
+        *self.attempts.entry(hash).or_default() += 1;
+        *load.entry(peer).or_default() += 1;
+        assigned.entry(peer).or_default().push(hash);
+    }
+}
+
+/// Peer with the fewest blocks in flight that can take another one, preferring any peer but `avoid`.
+fn least_loaded(
+    peers: &[SocketAddr],
+    load: &HashMap<SocketAddr, usize>,
+    avoid: Option<SocketAddr>,
+) -> Option<SocketAddr> {
+    let available =
+        |peer: &&SocketAddr| load.get(*peer).copied().unwrap_or(0) < MAX_BLOCKS_PER_PEER;
+    let by_load = |peer: &&SocketAddr| load.get(*peer).copied().unwrap_or(0);
+
+    peers
+        .iter()
+        .filter(available)
+        .filter(|peer| Some(**peer) != avoid)
+        .min_by_key(by_load)
+        .or_else(|| peers.iter().filter(available).min_by_key(by_load))
+        .copied()
+}
+
+/// Checks of a header that need nothing but the headers before it, given newest first and enough of them to cover
+/// the retarget window and the median time past. The full checks run when the block is added to the tree.
+pub fn check_header(
+    header: &BlockHeader,
+    ancestors: &[&BlockHeader],
+    params: &ChainParams,
+) -> Result<(), ValidationError> {
+    if header.version != BLOCK_VERSION {
+        return Err(ValidationError::UnsupportedVersion(header.version));
+    }
+
+    let expected = header.hash();
+    if header.current_hash != expected {
+        return Err(ValidationError::HashMismatch {
+            expected,
+            found: header.current_hash,
+        });
+    }
+
+    let expected_index = match ancestors.first() {
+        Some(parent) => parent.index + 1,
+        None => {
+            if header.previous_hash != GENESIS_PREVIOUS_HASH {
+                return Err(ValidationError::InvalidGenesisParent {
+                    expected: GENESIS_PREVIOUS_HASH,
+                    found: header.previous_hash,
+                });
+            }
+            0
+        }
+    };
+    if header.index != expected_index {
+        return Err(ValidationError::UnexpectedIndex {
+            expected: expected_index,
+            found: header.index,
+        });
+    }
+
+    let expected_bits = params.next_bits(ancestors.iter().copied());
+    if header.bits != expected_bits {
+        return Err(ValidationError::UnexpectedTarget {
+            expected: expected_bits,
+            found: header.bits,
+        });
+    }
+    if !meets_target(&header.current_hash, header.bits) {
+        return Err(ValidationError::InsufficientWork(header.bits));
+    }
+
+    check_timestamp(header.timestamp, median_time_past(ancestors.iter().copied()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::tests::{extend, mine, test_params};
+    use crate::pow::target_from_leading_zeros;
+    use crate::transaction::Address;
+
+    const PEER: SocketAddr = SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST), 1729);
+
+    /// Two chains sharing a genesis block: ours with `ours` blocks on top of it, theirs with `theirs`.
+    fn fork(ours: usize, theirs: usize) -> (BlockChain, BlockChain) {
+        let mut our_chain = BlockChain::with_params(test_params());
+        let mut their_chain = BlockChain::with_params(test_params());
+        let genesis = mine(&our_chain, None, Address::from([1; 20]), vec![]);
+        let hash = genesis.block_header.current_hash;
+        our_chain.add_block(genesis.clone()).unwrap();
+        their_chain.add_block(genesis).unwrap();
+
+        let mut tip = hash;
+        for _ in 0..ours {
+            tip = extend(&mut our_chain, Some(tip), Address::from([2; 20]), vec![]);
+        }
+        let mut tip = hash;
+        for _ in 0..theirs {
+            tip = extend(&mut their_chain, Some(tip), Address::from([3; 20]), vec![]);
+        }
+        (our_chain, their_chain)
+    }
+
+    /// Syncs `chain` from `PEER`, up to where its headers have been asked for.
+    fn asked_for_headers(chain: &BlockChain) -> BlockSync {
+        let mut sync = BlockSync::new();
+        sync.start(PEER);
+        let requests = sync.poll(chain, &[PEER]).unwrap();
+        assert!(matches!(requests[..], [SyncRequest::Headers { peer: PEER, .. }]));
+        sync
+    }
+
+    fn headers_of(chain: &BlockChain) -> Vec<BlockHeader> {
+        chain
+            .all_blocks_in_longest_chain()
+            .into_iter()
+            .map(|block| block.block_header)
+            .collect()
+    }
+
+    #[test]
+    fn headers_with_more_work_get_their_blocks_fetched() {
+        let (ours, theirs) = fork(1, 3);
+        let mut sync = asked_for_headers(&ours);
+
+        sync.receive_headers(PEER, headers_of(&theirs), &ours).unwrap();
+        assert!(sync.is_ahead());
+        assert_eq!(sync.progress().headers, 3);
+
+        let requests = sync.poll(&ours, &[PEER]).unwrap();
+        assert!(matches!(&requests[..], [SyncRequest::Blocks { hashes, .. }] if hashes.len() == 3));
+    }
+
+    #[test]
+    fn headers_with_less_work_end_the_sync() {
+        let (ours, theirs) = fork(3, 1);
+        let mut sync = asked_for_headers(&ours);
+
+        assert!(matches!(
+            sync.receive_headers(PEER, headers_of(&theirs), &ours),
+            Err(SyncError::LessWork { .. })
+        ));
+        assert!(!sync.is_syncing());
+    }
+
+    #[test]
+    fn header_with_a_target_the_rules_do_not_expect_is_rejected() {
+        let (ours, theirs) = fork(0, 2);
+        let mut headers = headers_of(&theirs);
+        let header = headers.last_mut().unwrap();
+        header.bits = target_from_leading_zeros(2).to_compact();
+        while !meets_target(&header.hash(), header.bits) {
+            header.nonce = header.nonce.wrapping_add(1);
+        }
+        header.current_hash = header.hash();
+
+        let mut sync = asked_for_headers(&ours);
+        assert!(matches!(
+            sync.receive_headers(PEER, headers, &ours),
+            Err(SyncError::InvalidHeader(ValidationError::UnexpectedTarget { .. }))
+        ));
+        assert!(!sync.is_ahead());
+    }
+
+    #[test]
+    fn oversized_batch_is_rejected() {
+        let (ours, theirs) = fork(0, 1);
+        let headers = vec![headers_of(&theirs)[1].clone(); MAX_HEADERS + 1];
+
+        let mut sync = asked_for_headers(&ours);
+        assert!(matches!(
+            sync.receive_headers(PEER, headers, &ours),
+            Err(SyncError::TooManyHeaders { .. })
+        ));
+    }
+}
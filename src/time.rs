@@ -0,0 +1,10 @@
+// Wall clock time as stored in block headers, mempool entries, address books and bans
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Current unix time in seconds.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
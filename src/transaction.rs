@@ -1,57 +1,177 @@
 use anyhow::Result;
 use bytes::Bytes;
+use crate::error::TxnError;
+use crate::hash::Hash256;
+use crate::node::Message;
 use crate::sender::MessageSender;
-use rand::{thread_rng, Rng};
+use ed25519_dalek::{Signature, Signer as _, SigningKey, Verifier as _, VerifyingKey};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::net::SocketAddr;
+use std::str::FromStr;
+
+/// Domain tag in front of the canonical transaction encoding, so a signature can't be replayed as anything else.
+const TXN_SIGNING_TAG: &[u8] = b"simple-blockchain/txn/v1";
+
+/// Transaction layout written by `Txn::encode`.
+pub const TXN_VERSION: u8 = 1;
+
+/// Length of the encoding of an unsigned transaction.
+pub const UNSIGNED_TXN_SIZE: usize = 89;
+
+/// Account address: the first 20 bytes of the SHA-256 of an Ed25519 public key.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Address([u8; 20]);
+
+impl Address {
+    pub fn from_public_key(public_key: &VerifyingKey) -> Self {
+        let hash = Sha256::digest(public_key.as_bytes());
+        let mut address = [0u8; 20];
+        address.copy_from_slice(&hash[..20]);
+        Self(address)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<[u8; 20]> for Address {
+    fn from(bytes: [u8; 20]) -> Self {
+        Self(bytes)
+    }
+}
+
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "0x{}", hex::encode(self.0))
+    }
+}
+
+impl FromStr for Address {
+    type Err = TxnError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = hex::decode(s.trim_start_matches("0x")).map_err(|_| TxnError::InvalidAddress)?;
+        let address = bytes.try_into().map_err(|_| TxnError::InvalidAddress)?;
+        Ok(Self(address))
+    }
+}
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize, Hash, PartialEq, Eq)]
 pub struct Txn {
-    pub id: String,
-    pub sender: String,
-    pub receiver: String,
+    pub id: Hash256,
+    pub sender: Address,
+    pub receiver: Address,
     pub amount: u32,
+    /// Paid to the miner of the block including this transaction, on top of `amount`.
+    pub fee: u32,
+    /// Position of this transaction among all transactions sent by `sender`, starting at 0.
+    pub nonce: u64,
+    pub public_key: [u8; 32],
+    pub signature: Vec<u8>,
 }
 
-#[allow(dead_code)]
 impl Txn {
-    pub fn new(sender: String, receiver: String, amount: u32) -> Txn {
-        let id = Self::calculate_id(&sender, &receiver, &amount);
-        Txn {
-            id,
-            sender,
+    /// Creates an unsigned transfer from the owner of `public_key`.
+    pub fn new(
+        public_key: &VerifyingKey,
+        receiver: Address,
+        amount: u32,
+        fee: u32,
+        nonce: u64,
+    ) -> Txn {
+        let mut txn = Txn {
+            id: Hash256::ZERO,
+            sender: Address::from_public_key(public_key),
             receiver,
             amount,
-        }
+            fee,
+            nonce,
+            public_key: public_key.to_bytes(),
+            signature: vec![],
+        };
+        txn.id = txn.calculate_id();
+        txn
     }
 
-    pub fn with_id(id: String, sender: String, receiver: String, amount: u32) -> Self {
-        Self {
-            id,
-            sender,
-            receiver,
-            amount,
+    /// Canonical encoding of everything but the id and signature:
+    /// version (1) || sender (20) || receiver (20) || amount (u32 BE) || fee (u32 BE) || nonce (u64 BE) || public key (32).
+    pub fn encode_unsigned(&self) -> [u8; UNSIGNED_TXN_SIZE] {
+        let fields: [&[u8]; 7] = [
+            &[TXN_VERSION],
+            self.sender.as_bytes(),
+            self.receiver.as_bytes(),
+            &self.amount.to_be_bytes(),
+            &self.fee.to_be_bytes(),
+            &self.nonce.to_be_bytes(),
+            &self.public_key,
+        ];
+
+        let mut bytes = [0u8; UNSIGNED_TXN_SIZE];
+        let mut offset = 0;
+        for field in fields {
+            bytes[offset..offset + field.len()].copy_from_slice(field);
+            offset += field.len();
         }
+        bytes
     }
 
-    fn calculate_id(sender: &str, receiver: &str, amount: &u32) -> String {
-        let mut random = thread_rng();
-        let noise = random.gen::<u32>();
-        let mut hasher = Sha256::new();
-        hasher.update(&sender.as_bytes());
-        hasher.update(&receiver.as_bytes());
-        hasher.update(&amount.to_string().as_bytes());
-        hasher.update(&noise.to_string().as_bytes());
-        let hash = hasher.finalize().as_slice().to_owned();
-        let hash = hex::encode(hash);
-        hash
+    /// Canonical encoding of the signed transaction: `encode_unsigned` followed by the signature (64).
+    pub fn encode(&self) -> Vec<u8> {
+        [&self.encode_unsigned()[..], &self.signature].concat()
+    }
+
+    /// Bytes covered by the signature: tag || `encode_unsigned`.
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        [TXN_SIGNING_TAG, &self.encode_unsigned()].concat()
+    }
+
+    fn calculate_id(&self) -> Hash256 {
+        Hash256::digest(&self.signing_bytes())
+    }
+
+    /// Amount and fee together, which is what the sender's balance must cover.
+    pub fn total_cost(&self) -> u64 {
+        self.amount as u64 + self.fee as u64
+    }
+
+    /// Size of the transaction on the wire, used to rank it by fee rate and to fill blocks.
+    pub fn size(&self) -> usize {
+        bincode::serialized_size(self).unwrap_or(u64::MAX) as usize
+    }
+
+    pub fn sign(&mut self, key: &SigningKey) {
+        self.public_key = key.verifying_key().to_bytes();
+        self.sender = Address::from_public_key(&key.verifying_key());
+        self.id = self.calculate_id();
+        self.signature = key.sign(&self.signing_bytes()).to_bytes().to_vec();
+    }
+
+    /// Checks that the sender owns the public key, the id matches the contents and the signature is valid.
+    pub fn verify(&self) -> Result<(), TxnError> {
+        let public_key =
+            VerifyingKey::from_bytes(&self.public_key).map_err(|_| TxnError::InvalidPublicKey)?;
+
+        if Address::from_public_key(&public_key) != self.sender {
+            return Err(TxnError::SenderMismatch(self.sender));
+        }
+
+        if self.id != self.calculate_id() {
+            return Err(TxnError::IdMismatch(self.id));
+        }
+
+        let signature =
+            Signature::from_slice(&self.signature).map_err(|_| TxnError::InvalidSignature)?;
+        public_key
+            .verify(&self.signing_bytes(), &signature)
+            .map_err(|_| TxnError::InvalidSignature)
     }
 
     pub async fn send_to(self, address: SocketAddr) -> Result<()> {
         let mut sender = MessageSender::new();
 
-        let txn_message: Bytes = bincode::serialize(&self)?.into();
+        let txn_message: Bytes = bincode::serialize(&Message::Txn(self))?.into();
 
         sender.send(address, txn_message).await;
         Ok(())
@@ -60,15 +180,62 @@ impl Txn {
 
 #[derive(Debug, Clone, Serialize, Deserialize, Hash, PartialEq, Eq)]
 pub struct CoinbaseTxn {
-    pub amount: u8,
-    pub validator: String,
+    /// Block subsidy plus the fees of every transaction in the block.
+    pub amount: u64,
+    pub validator: Address,
+    /// Rolled by miners once every `nonce` in the header has been tried.
+    pub extra_nonce: u64,
+}
+
+impl Default for CoinbaseTxn {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl CoinbaseTxn {
     pub fn new() -> Self {
         Self {
             amount: 0,
-            validator: String::new(),
+            validator: Address::default(),
+            extra_nonce: 0,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn txn_encoding_and_id_match_known_vector() {
+        let mut txn = Txn {
+            id: Hash256::ZERO,
+            sender: Address::from([0x01; 20]),
+            receiver: Address::from([0x02; 20]),
+            amount: 30,
+            fee: 2,
+            nonce: 5,
+            public_key: [0x55; 32],
+            signature: vec![0x66; 64],
+        };
+        txn.id = txn.calculate_id();
+
+        let unsigned = [
+            "01",
+            &"01".repeat(20),
+            &"02".repeat(20),
+            "0000001e",
+            "00000002",
+            "0000000000000005",
+            &"55".repeat(32),
+        ]
+        .concat();
+        assert_eq!(hex::encode(txn.encode_unsigned()), unsigned);
+        assert_eq!(hex::encode(txn.encode()), unsigned + &"66".repeat(64));
+        assert_eq!(
+            txn.id,
+            "58bb3ec137feb26e7a5ed6a5d039e073c4265929bd08fa01a9f4256b0487192a".parse().unwrap()
+        );
+    }
+}
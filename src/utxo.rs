@@ -0,0 +1,535 @@
+// Bitcoin-style ledger: transactions spend unspent outputs of earlier transactions and create new ones
+use crate::block::Block;
+use crate::error::{TxnError, UtxoError};
+use crate::hash::Hash256;
+use crate::node::Message;
+use crate::sender::MessageSender;
+use crate::transaction::Address;
+
+use anyhow::Result;
+use bytes::Bytes;
+use ed25519_dalek::{Signature, Signer as _, SigningKey, Verifier as _, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+const UTXO_TXN_SIGNING_TAG: &[u8] = b"simple-blockchain/utxo-txn/v2";
+
+/// Reference to an output of an earlier transaction.
+/// Coinbase outputs use the hash of the block that created them as `txid`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct OutPoint {
+    pub txid: Hash256,
+    pub index: u32,
+}
+
+impl std::fmt::Display for OutPoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.txid, self.index)
+    }
+}
+
+impl FromStr for OutPoint {
+    type Err = UtxoError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || UtxoError::InvalidOutPoint(s.to_string());
+        let (txid, index) = s.rsplit_once(':').ok_or_else(invalid)?;
+        Ok(Self {
+            txid: txid.parse().map_err(|_| invalid())?,
+            index: index.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Hash, PartialEq, Eq)]
+pub struct TxIn {
+    pub previous_output: OutPoint,
+    /// Key whose address must match the lock of the spent output.
+    pub public_key: [u8; 32],
+    pub signature: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Hash, PartialEq, Eq)]
+pub struct TxOut {
+    pub amount: u64,
+    /// Only the owner of the key hashing to this address can spend the output.
+    pub lock: Address,
+}
+
+impl FromStr for TxOut {
+    type Err = UtxoError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || UtxoError::InvalidOutput(s.to_string());
+        let (lock, amount) = s.split_once(':').ok_or_else(invalid)?;
+        Ok(Self {
+            amount: amount.parse().map_err(|_| invalid())?,
+            lock: lock.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Hash, PartialEq, Eq)]
+pub struct UtxoTxn {
+    pub id: Hash256,
+    pub inputs: Vec<TxIn>,
+    pub outputs: Vec<TxOut>,
+}
+
+impl UtxoTxn {
+    /// Creates an unsigned transaction spending `inputs` with keys given later by `sign_input`.
+    pub fn new(inputs: Vec<OutPoint>, outputs: Vec<TxOut>) -> Self {
+        let inputs = inputs
+            .into_iter()
+            .map(|previous_output| TxIn {
+                previous_output,
+                ..Default::default()
+            })
+            .collect();
+
+        let mut txn = Self {
+            id: Hash256::ZERO,
+            inputs,
+            outputs,
+        };
+        txn.id = txn.calculate_id();
+        txn
+    }
+
+    /// Canonical byte encoding covered by every input signature:
+    /// tag || input count (u32 BE) || for each input: txid (32), index (u32 BE) ||
+    /// output count (u32 BE) || for each output: amount (u64 BE), lock (20).
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        let mut bytes = UTXO_TXN_SIGNING_TAG.to_vec();
+
+        bytes.extend_from_slice(&(self.inputs.len() as u32).to_be_bytes());
+        for input in &self.inputs {
+            bytes.extend_from_slice(input.previous_output.txid.as_bytes());
+            bytes.extend_from_slice(&input.previous_output.index.to_be_bytes());
+        }
+
+        bytes.extend_from_slice(&(self.outputs.len() as u32).to_be_bytes());
+        for output in &self.outputs {
+            bytes.extend_from_slice(&output.amount.to_be_bytes());
+            bytes.extend_from_slice(output.lock.as_bytes());
+        }
+        bytes
+    }
+
+    /// Canonical encoding of the signed transaction:
+    /// input count (u32 BE) || for each input: txid (32), index (u32 BE), public key (32), signature length (u32 BE), signature ||
+    /// output count (u32 BE) || for each output: amount (u64 BE), lock (20).
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+
+        bytes.extend_from_slice(&(self.inputs.len() as u32).to_be_bytes());
+        for input in &self.inputs {
+            bytes.extend_from_slice(input.previous_output.txid.as_bytes());
+            bytes.extend_from_slice(&input.previous_output.index.to_be_bytes());
+            bytes.extend_from_slice(&input.public_key);
+            bytes.extend_from_slice(&(input.signature.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(&input.signature);
+        }
+
+        bytes.extend_from_slice(&(self.outputs.len() as u32).to_be_bytes());
+        for output in &self.outputs {
+            bytes.extend_from_slice(&output.amount.to_be_bytes());
+            bytes.extend_from_slice(output.lock.as_bytes());
+        }
+        bytes
+    }
+
+    fn calculate_id(&self) -> Hash256 {
+        Hash256::digest(&self.signing_bytes())
+    }
+
+    pub fn sign_input(&mut self, index: usize, key: &SigningKey) {
+        self.id = self.calculate_id();
+        let signature = key.sign(&self.signing_bytes());
+
+        let input = &mut self.inputs[index];
+        input.public_key = key.verifying_key().to_bytes();
+        input.signature = signature.to_bytes().to_vec();
+    }
+
+    /// Size of the transaction on the wire, used to rank it by fee rate and to fill blocks.
+    pub fn size(&self) -> usize {
+        bincode::serialized_size(self).unwrap_or(u64::MAX) as usize
+    }
+
+    pub fn total_output(&self) -> u64 {
+        self.outputs.iter().map(|output| output.amount).sum()
+    }
+
+    /// Checks the id and every input signature. Whether the keys may spend the inputs is checked by `UtxoSet`.
+    pub fn verify(&self) -> Result<(), TxnError> {
+        if self.id != self.calculate_id() {
+            return Err(TxnError::IdMismatch(self.id));
+        }
+
+        let message = self.signing_bytes();
+        for input in &self.inputs {
+            let public_key = VerifyingKey::from_bytes(&input.public_key)
+                .map_err(|_| TxnError::InvalidPublicKey)?;
+            let signature =
+                Signature::from_slice(&input.signature).map_err(|_| TxnError::InvalidSignature)?;
+            public_key
+                .verify(&message, &signature)
+                .map_err(|_| TxnError::InvalidSignature)?;
+        }
+        Ok(())
+    }
+
+    pub async fn send_to(self, address: SocketAddr) -> Result<()> {
+        let mut sender = MessageSender::new();
+
+        let txn_message: Bytes = bincode::serialize(&Message::UtxoTxn(self))?.into();
+
+        sender.send(address, txn_message).await;
+        Ok(())
+    }
+}
+
+/// An unspent output together with where it was created.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct UtxoEntry {
+    pub output: TxOut,
+    pub height: u32,
+    pub is_coinbase: bool,
+}
+
+/// What a block changed in the UTXO set, so it can be reverted on reorg.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UtxoUndo {
+    height: u32,
+    spent: Vec<(OutPoint, UtxoEntry)>,
+    created: Vec<OutPoint>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UtxoSet {
+    unspent: HashMap<OutPoint, UtxoEntry>,
+    /// Height of the next block to apply.
+    next_height: u32,
+    /// Blocks before a coinbase output can be spent.
+    coinbase_maturity: u32,
+}
+
+impl UtxoSet {
+    pub fn new(coinbase_maturity: u32) -> Self {
+        Self {
+            coinbase_maturity,
+            ..Self::default()
+        }
+    }
+
+    /// Height of the first block that may spend `entry`.
+    pub fn spendable_at(&self, entry: &UtxoEntry) -> u32 {
+        if entry.is_coinbase {
+            entry.height.saturating_add(self.coinbase_maturity)
+        } else {
+            entry.height
+        }
+    }
+
+    pub fn get(&self, outpoint: &OutPoint) -> Option<&UtxoEntry> {
+        self.unspent.get(outpoint)
+    }
+
+    pub fn len(&self) -> usize {
+        self.unspent.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.unspent.is_empty()
+    }
+
+    /// Sum of all unspent outputs locked to `address`.
+    pub fn balance_of(&self, address: &Address) -> u64 {
+        self.unspent
+            .values()
+            .filter(|entry| entry.output.lock == *address)
+            .map(|entry| entry.output.amount)
+            .sum()
+    }
+
+    pub fn outputs_of<'a>(
+        &'a self,
+        address: &'a Address,
+    ) -> impl Iterator<Item = (&'a OutPoint, &'a UtxoEntry)> + 'a {
+        self.unspent
+            .iter()
+            .filter(move |(_, entry)| entry.output.lock == *address)
+    }
+
+    /// Checks that every input exists, is spent only once, is unlocked by its key and, for coinbase outputs,
+    /// has matured by the next block, and that the outputs don't exceed the inputs. Returns the fee.
+    pub fn check_txn(&self, txn: &UtxoTxn) -> Result<u64, UtxoError> {
+        if txn.inputs.is_empty() {
+            return Err(UtxoError::NoInputs(txn.id));
+        }
+
+        let mut seen = HashSet::new();
+        let mut total_input = 0u64;
+
+        for input in &txn.inputs {
+            let outpoint = &input.previous_output;
+            if !seen.insert(outpoint) {
+                return Err(UtxoError::DoubleSpend(outpoint.clone()));
+            }
+
+            let entry = self
+                .unspent
+                .get(outpoint)
+                .ok_or_else(|| UtxoError::MissingOutput(outpoint.clone()))?;
+
+            let owner = VerifyingKey::from_bytes(&input.public_key)
+                .map(|key| Address::from_public_key(&key))
+                .map_err(|_| UtxoError::LockMismatch(outpoint.clone()))?;
+            if owner != entry.output.lock {
+                return Err(UtxoError::LockMismatch(outpoint.clone()));
+            }
+
+            let spendable_at = self.spendable_at(entry);
+            if spendable_at > self.next_height {
+                return Err(UtxoError::ImmatureCoinbase {
+                    outpoint: outpoint.clone(),
+                    spendable_at,
+                });
+            }
+
+            total_input = total_input
+                .checked_add(entry.output.amount)
+                .ok_or(UtxoError::ValueOverflow(txn.id))?;
+        }
+
+        let total_output = txn
+            .outputs
+            .iter()
+            .try_fold(0u64, |sum, output| sum.checked_add(output.amount))
+            .ok_or(UtxoError::ValueOverflow(txn.id))?;
+
+        if total_output > total_input {
+            return Err(UtxoError::OutputsExceedInputs {
+                id: txn.id,
+                inputs: total_input,
+                outputs: total_output,
+            });
+        }
+
+        Ok(total_input - total_output)
+    }
+
+    /// Applies a transaction as part of the next block.
+    pub fn apply_txn(&mut self, txn: &UtxoTxn, undo: &mut UtxoUndo) -> Result<u64, UtxoError> {
+        let fee = self.check_txn(txn)?;
+        let height = self.next_height;
+
+        for input in &txn.inputs {
+            let outpoint = input.previous_output.clone();
+            let entry = self.unspent.remove(&outpoint).unwrap();
+            undo.spent.push((outpoint, entry));
+        }
+
+        for (index, output) in txn.outputs.iter().enumerate() {
+            let outpoint = OutPoint {
+                txid: txn.id,
+                index: index as u32,
+            };
+            self.insert_output(outpoint, output.clone(), height, false, undo)?;
+        }
+
+        Ok(fee)
+    }
+
+    /// Spends and creates the outputs of every transaction in the block, then adds the coinbase output,
+    /// which must claim exactly `subsidy` plus the fees. Leaves the set untouched if any of it fails.
+    pub fn apply_block(&mut self, block: &Block, subsidy: u64) -> Result<UtxoUndo, UtxoError> {
+        let mut next = self.clone();
+        let height = block.block_header.index;
+        let mut undo = UtxoUndo {
+            height,
+            ..UtxoUndo::default()
+        };
+        let mut fees = 0u64;
+        next.next_height = height;
+
+        for txn in &block.body.utxo_txns {
+            // An output spent by an earlier transaction of the same block is a double spend, not a missing output.
+            for input in &txn.inputs {
+                if undo.spent.iter().any(|(spent, _)| *spent == input.previous_output) {
+                    return Err(UtxoError::DoubleSpend(input.previous_output.clone()));
+                }
+            }
+            let fee = next.apply_txn(txn, &mut undo)?;
+            fees = fees
+                .checked_add(fee)
+                .ok_or(UtxoError::ValueOverflow(txn.id))?;
+        }
+
+        let coinbase = &block.block_header.coinbase_txn;
+        let expected = subsidy.saturating_add(fees);
+        if coinbase.amount != expected {
+            return Err(UtxoError::InvalidCoinbaseAmount {
+                expected,
+                found: coinbase.amount,
+            });
+        }
+
+        let outpoint = OutPoint {
+            txid: block.block_header.current_hash,
+            index: 0,
+        };
+        let output = TxOut {
+            amount: coinbase.amount,
+            lock: coinbase.validator,
+        };
+        next.insert_output(outpoint, output, height, true, &mut undo)?;
+
+        next.next_height = height + 1;
+        *self = next;
+        Ok(undo)
+    }
+
+    /// Reverts a block applied with `apply_block`.
+    /// Spent outputs go back before created ones are dropped, since an output the block created may also be
+    /// one it spent.
+    pub fn undo_block(&mut self, undo: &UtxoUndo) {
+        for (outpoint, entry) in &undo.spent {
+            self.unspent.insert(outpoint.clone(), entry.clone());
+        }
+        for outpoint in &undo.created {
+            self.unspent.remove(outpoint);
+        }
+        self.next_height = undo.height;
+    }
+
+    fn insert_output(
+        &mut self,
+        outpoint: OutPoint,
+        output: TxOut,
+        height: u32,
+        is_coinbase: bool,
+        undo: &mut UtxoUndo,
+    ) -> Result<(), UtxoError> {
+        if self.unspent.contains_key(&outpoint) {
+            return Err(UtxoError::DuplicateOutput(outpoint));
+        }
+
+        undo.created.push(outpoint.clone());
+        self.unspent.insert(
+            outpoint,
+            UtxoEntry {
+                output,
+                height,
+                is_coinbase,
+            },
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{Body, GENESIS_PREVIOUS_HASH};
+
+    fn block(index: u32, miner: Address, subsidy: u64, txns: Vec<UtxoTxn>) -> Block {
+        let body = Body {
+            utxo_txns: txns,
+            ..Body::default()
+        };
+        let mut block = Block::new(index, GENESIS_PREVIOUS_HASH, body);
+        block.block_header.coinbase_txn.validator = miner;
+        block.block_header.coinbase_txn.amount = subsidy;
+        block.block_header.current_hash = block.block_header.hash();
+        block
+    }
+
+    fn coinbase_of(block: &Block) -> OutPoint {
+        OutPoint {
+            txid: block.block_header.current_hash,
+            index: 0,
+        }
+    }
+
+    fn spend(key: &SigningKey, input: OutPoint, outputs: Vec<TxOut>) -> UtxoTxn {
+        let mut txn = UtxoTxn::new(vec![input], outputs);
+        txn.sign_input(0, key);
+        txn
+    }
+
+    #[test]
+    fn undo_restores_the_set_before_the_block() {
+        let alice = SigningKey::from_bytes(&[1; 32]);
+        let carol = SigningKey::from_bytes(&[3; 32]);
+        let alice_address = Address::from_public_key(&alice.verifying_key());
+        let carol_address = Address::from_public_key(&carol.verifying_key());
+        let bob = Address::from([2; 20]);
+        let dave = Address::from([4; 20]);
+
+        let mut utxos = UtxoSet::new(1);
+        let genesis = block(0, alice_address, 50, vec![]);
+        utxos.apply_block(&genesis, 50).unwrap();
+        let before = utxos.clone();
+
+        // Carol passes on an output in the same block that created it.
+        let to_carol = spend(
+            &alice,
+            coinbase_of(&genesis),
+            vec![
+                TxOut { amount: 30, lock: carol_address },
+                TxOut { amount: 15, lock: alice_address },
+            ],
+        );
+        let to_dave = spend(
+            &carol,
+            OutPoint { txid: to_carol.id, index: 0 },
+            vec![TxOut { amount: 28, lock: dave }],
+        );
+        let next = block(1, bob, 50 + 5 + 2, vec![to_carol.clone(), to_dave]);
+        let undo = utxos.apply_block(&next, 50).unwrap();
+
+        assert_eq!(utxos.balance_of(&dave), 28);
+        assert_eq!(utxos.balance_of(&alice_address), 15);
+        assert_eq!(utxos.balance_of(&carol_address), 0);
+        assert_eq!(utxos.balance_of(&bob), 57);
+        assert!(utxos.get(&coinbase_of(&genesis)).is_none());
+
+        utxos.undo_block(&undo);
+        assert_eq!(utxos, before);
+
+        // The reverted block applies again as if it never had been.
+        utxos.apply_block(&next, 50).unwrap();
+        assert_eq!(utxos.balance_of(&dave), 28);
+    }
+
+    #[test]
+    fn failed_block_leaves_the_set_untouched() {
+        let alice = SigningKey::from_bytes(&[1; 32]);
+        let alice_address = Address::from_public_key(&alice.verifying_key());
+        let bob = Address::from([2; 20]);
+
+        let mut utxos = UtxoSet::new(2);
+        let genesis = block(0, alice_address, 50, vec![]);
+        utxos.apply_block(&genesis, 50).unwrap();
+        let before = utxos.clone();
+
+        // The coinbase of height 0 can't be spent before height 2.
+        let early = spend(&alice, coinbase_of(&genesis), vec![TxOut { amount: 50, lock: bob }]);
+        let next = block(1, bob, 50, vec![early.clone()]);
+        assert!(matches!(
+            utxos.apply_block(&next, 50),
+            Err(UtxoError::ImmatureCoinbase { spendable_at: 2, .. })
+        ));
+        assert_eq!(utxos, before);
+
+        // Spending the same output twice in one block is refused outright.
+        let twice = spend(&alice, coinbase_of(&genesis), vec![TxOut { amount: 49, lock: bob }]);
+        let later = block(2, bob, 50 + 1, vec![early, twice]);
+        assert!(matches!(utxos.apply_block(&later, 50), Err(UtxoError::DoubleSpend(_))));
+        assert_eq!(utxos, before);
+    }
+}
@@ -0,0 +1,408 @@
+// Consensus checks a block must pass before it is added to the block tree
+use crate::block::*;
+use crate::blockchain::BlockChain;
+use crate::error::ValidationError;
+use crate::hash::Hash256;
+use crate::pow::meets_target;
+use crate::time::now;
+
+use std::collections::HashSet;
+
+/// Number of ancestors whose timestamps make up the median time past.
+pub const MEDIAN_TIME_SPAN: usize = 11;
+
+/// How far ahead of the local clock a block timestamp may be, in seconds.
+pub const MAX_FUTURE_BLOCK_TIME: u64 = 2 * 60 * 60;
+
+/// Validates blocks against the branch of the tree they extend.
+pub struct BlockValidator<'a> {
+    chain: &'a BlockChain,
+}
+
+impl<'a> BlockValidator<'a> {
+    pub fn new(chain: &'a BlockChain) -> Self {
+        Self { chain }
+    }
+
+    /// Runs every header and body check. The block's parent must already be in the chain,
+    /// unless the chain is empty and the block is a genesis block.
+    pub fn validate(&self, block: &Block) -> Result<(), ValidationError> {
+        self.check_version(block)?;
+        self.check_hash(block)?;
+        self.check_proof_of_work(block)?;
+        self.check_index(block)?;
+        self.check_timestamp(block)?;
+        self.check_merkle_root(block)?;
+        self.check_size(block)?;
+        self.check_duplicates(block)?;
+        self.check_signatures(block)?;
+        Ok(())
+    }
+
+    fn check_version(&self, block: &Block) -> Result<(), ValidationError> {
+        let version = block.block_header.version;
+        if version != BLOCK_VERSION {
+            return Err(ValidationError::UnsupportedVersion(version));
+        }
+        Ok(())
+    }
+
+    fn check_hash(&self, block: &Block) -> Result<(), ValidationError> {
+        let expected = block.block_header.hash();
+        if block.block_header.current_hash != expected {
+            return Err(ValidationError::HashMismatch {
+                expected,
+                found: block.block_header.current_hash,
+            });
+        }
+        Ok(())
+    }
+
+    fn check_proof_of_work(&self, block: &Block) -> Result<(), ValidationError> {
+        let bits = block.block_header.bits;
+        let parent = Some(&block.block_header.previous_hash).filter(|hash| self.chain.contains(hash));
+        let expected = self.chain.next_bits(parent);
+        if bits != expected {
+            return Err(ValidationError::UnexpectedTarget {
+                expected,
+                found: bits,
+            });
+        }
+
+        // `check_hash` already made sure the header hashes to `current_hash`.
+        if !meets_target(&block.block_header.current_hash, bits) {
+            return Err(ValidationError::InsufficientWork(bits));
+        }
+        Ok(())
+    }
+
+    fn check_index(&self, block: &Block) -> Result<(), ValidationError> {
+        let header = &block.block_header;
+        let expected = match self.chain.height_of(&header.previous_hash) {
+            Some(parent_height) => parent_height + 1,
+            None => {
+                if header.previous_hash != GENESIS_PREVIOUS_HASH {
+                    return Err(ValidationError::InvalidGenesisParent {
+                        expected: GENESIS_PREVIOUS_HASH,
+                        found: header.previous_hash,
+                    });
+                }
+                0
+            }
+        };
+
+        if header.index != expected {
+            return Err(ValidationError::UnexpectedIndex {
+                expected,
+                found: header.index,
+            });
+        }
+        Ok(())
+    }
+
+    fn check_timestamp(&self, block: &Block) -> Result<(), ValidationError> {
+        check_timestamp(
+            block.block_header.timestamp,
+            self.median_time_past(&block.block_header.previous_hash),
+        )
+    }
+
+    /// The root commits to the full encoding of every transaction, and the tree never pairs a node with a copy
+    /// of itself, so a reordered, repeated or re-signed transaction list can't keep the root of the original.
+    fn check_merkle_root(&self, block: &Block) -> Result<(), ValidationError> {
+        let expected = MerkleRoot::from_body(&block.body);
+        if block.block_header.merkle_root != expected {
+            return Err(ValidationError::MerkleRootMismatch {
+                expected,
+                found: block.block_header.merkle_root,
+            });
+        }
+        Ok(())
+    }
+
+    fn check_size(&self, block: &Block) -> Result<(), ValidationError> {
+        let count = block.body.len();
+        if count > MAX_BLOCK_TXNS {
+            return Err(ValidationError::TooManyTransactions {
+                count,
+                max: MAX_BLOCK_TXNS,
+            });
+        }
+
+        let size = block.size();
+        if size > MAX_BLOCK_SIZE {
+            return Err(ValidationError::BlockTooLarge {
+                size,
+                max: MAX_BLOCK_SIZE,
+            });
+        }
+        Ok(())
+    }
+
+    fn check_duplicates(&self, block: &Block) -> Result<(), ValidationError> {
+        let mut seen = HashSet::new();
+        let ids = block
+            .body
+            .txn_data
+            .iter()
+            .map(|txn| &txn.id)
+            .chain(block.body.utxo_txns.iter().map(|txn| &txn.id));
+
+        for id in ids {
+            if !seen.insert(id) {
+                return Err(ValidationError::DuplicateTransaction(*id));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_signatures(&self, block: &Block) -> Result<(), ValidationError> {
+        for txn in &block.body.txn_data {
+            txn.verify()
+                .map_err(|source| ValidationError::InvalidTransaction {
+                    id: txn.id,
+                    source,
+                })?;
+        }
+
+        for txn in &block.body.utxo_txns {
+            txn.verify()
+                .map_err(|source| ValidationError::InvalidTransaction {
+                    id: txn.id,
+                    source,
+                })?;
+        }
+        Ok(())
+    }
+
+    /// Median timestamp of the last `MEDIAN_TIME_SPAN` blocks ending at `hash`.
+    pub fn median_time_past(&self, hash: &Hash256) -> Option<u64> {
+        median_time_past(self.chain.ancestors(hash).map(|block| &block.block_header))
+    }
+}
+
+/// Checks a block timestamp against the local clock and the median time past of the block's ancestors.
+pub fn check_timestamp(timestamp: u64, median_time_past: Option<u64>) -> Result<(), ValidationError> {
+    let max = now() + MAX_FUTURE_BLOCK_TIME;
+    if timestamp > max {
+        return Err(ValidationError::TimestampTooNew { timestamp, max });
+    }
+
+    // Blocks can be found within the same second at low difficulty,
+    // so a timestamp equal to the median is still accepted.
+    if let Some(median_time_past) = median_time_past {
+        if timestamp < median_time_past {
+            return Err(ValidationError::TimestampTooOld {
+                timestamp,
+                median_time_past,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Median timestamp of the first `MEDIAN_TIME_SPAN` of `ancestors`, which run from a block back towards genesis.
+pub fn median_time_past<'a>(ancestors: impl IntoIterator<Item = &'a BlockHeader>) -> Option<u64> {
+    let mut timestamps = ancestors
+        .into_iter()
+        .take(MEDIAN_TIME_SPAN)
+        .map(|header| header.timestamp)
+        .collect::<Vec<_>>();
+
+    if timestamps.is_empty() {
+        return None;
+    }
+
+    timestamps.sort_unstable();
+    Some(timestamps[timestamps.len() / 2])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::tests::{extend, mine, test_params};
+    use crate::error::{ChainError, StateError};
+    use crate::transaction::{Address, Txn};
+    use ed25519_dalek::SigningKey;
+
+    fn miner() -> Address {
+        Address::from([2; 20])
+    }
+
+    /// Finds a nonce for a block whose header was changed after it was mined.
+    fn reseal(block: &mut Block) {
+        while !meets_target(&block.block_header.hash(), block.block_header.bits) {
+            block.block_header.nonce = block.block_header.nonce.wrapping_add(1);
+        }
+        block.block_header.current_hash = block.block_header.hash();
+    }
+
+    /// Chain of a genesis block and one more, with the hash of its tip.
+    fn chain_of_two() -> (BlockChain, Hash256) {
+        let mut chain = BlockChain::with_params(test_params());
+        let genesis = extend(&mut chain, None, miner(), vec![]);
+        let tip = extend(&mut chain, Some(genesis), miner(), vec![]);
+        (chain, tip)
+    }
+
+    fn validate(chain: &BlockChain, block: &Block) -> Result<(), ValidationError> {
+        BlockValidator::new(chain).validate(block)
+    }
+
+    #[test]
+    fn mined_block_passes() {
+        let (chain, tip) = chain_of_two();
+        assert_eq!(validate(&chain, &mine(&chain, Some(tip), miner(), vec![])), Ok(()));
+    }
+
+    #[test]
+    fn header_must_hash_to_its_hash_and_meet_its_target() {
+        let (chain, tip) = chain_of_two();
+
+        let mut block = mine(&chain, Some(tip), miner(), vec![]);
+        block.block_header.current_hash = Hash256::ZERO;
+        assert!(matches!(validate(&chain, &block), Err(ValidationError::HashMismatch { .. })));
+
+        let mut block = mine(&chain, Some(tip), miner(), vec![]);
+        while meets_target(&block.block_header.hash(), block.block_header.bits) {
+            block.block_header.nonce = block.block_header.nonce.wrapping_add(1);
+        }
+        block.block_header.current_hash = block.block_header.hash();
+        assert_eq!(
+            validate(&chain, &block),
+            Err(ValidationError::InsufficientWork(block.block_header.bits))
+        );
+
+        let mut block = mine(&chain, Some(tip), miner(), vec![]);
+        block.block_header.version = BLOCK_VERSION + 1;
+        reseal(&mut block);
+        assert_eq!(
+            validate(&chain, &block),
+            Err(ValidationError::UnsupportedVersion(BLOCK_VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn block_must_follow_its_parent() {
+        let (chain, tip) = chain_of_two();
+
+        let mut block = mine(&chain, Some(tip), miner(), vec![]);
+        block.block_header.index = 5;
+        reseal(&mut block);
+        assert_eq!(
+            validate(&chain, &block),
+            Err(ValidationError::UnexpectedIndex { expected: 2, found: 5 })
+        );
+
+        // A parent the chain doesn't have makes the block a genesis block, which must point to the zero hash.
+        let mut block = mine(&chain, Some(tip), miner(), vec![]);
+        block.block_header.previous_hash = Hash256::from([7; 32]);
+        reseal(&mut block);
+        assert!(matches!(
+            validate(&chain, &block),
+            Err(ValidationError::InvalidGenesisParent { .. })
+        ));
+    }
+
+    #[test]
+    fn merkle_root_must_commit_to_the_body() {
+        let (chain, tip) = chain_of_two();
+        let mut block = mine(&chain, Some(tip), miner(), vec![]);
+        block.block_header.merkle_root = Hash256::from([1; 32]);
+        reseal(&mut block);
+        assert!(matches!(
+            validate(&chain, &block),
+            Err(ValidationError::MerkleRootMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn repeating_the_last_transactions_is_caught() {
+        let (chain, tip) = chain_of_two();
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let txns = (0..3)
+            .map(|nonce| {
+                let mut txn = Txn::new(&key.verifying_key(), miner(), 10, 1, nonce);
+                txn.sign(&key);
+                txn
+            })
+            .collect::<Vec<_>>();
+        let block = mine(&chain, Some(tip), miner(), txns);
+
+        // A repeated tail doesn't hash to the root of the original list, so the header no longer matches.
+        let mut repeated = block.clone();
+        repeated.body.txn_data.extend_from_slice(&block.body.txn_data[1..]);
+        assert!(matches!(
+            validate(&chain, &repeated),
+            Err(ValidationError::MerkleRootMismatch { .. })
+        ));
+
+        // Committing to the longer list instead still repeats a transaction.
+        repeated.block_header.merkle_root = MerkleRoot::from_body(&repeated.body);
+        reseal(&mut repeated);
+        assert_eq!(
+            validate(&chain, &repeated),
+            Err(ValidationError::DuplicateTransaction(block.body.txn_data[1].id))
+        );
+    }
+
+    #[test]
+    fn timestamp_must_be_after_the_median_and_not_far_ahead() {
+        let (chain, tip) = chain_of_two();
+        let median = BlockValidator::new(&chain).median_time_past(&tip).unwrap();
+
+        let mut block = mine(&chain, Some(tip), miner(), vec![]);
+        block.block_header.timestamp = median - 1;
+        reseal(&mut block);
+        assert_eq!(
+            validate(&chain, &block),
+            Err(ValidationError::TimestampTooOld {
+                timestamp: median - 1,
+                median_time_past: median,
+            })
+        );
+
+        let mut block = mine(&chain, Some(tip), miner(), vec![]);
+        block.block_header.timestamp = now() + MAX_FUTURE_BLOCK_TIME + 60;
+        reseal(&mut block);
+        assert!(matches!(
+            validate(&chain, &block),
+            Err(ValidationError::TimestampTooNew { .. })
+        ));
+
+        assert_eq!(check_timestamp(median, Some(median)), Ok(()));
+    }
+
+    #[test]
+    fn median_time_past_takes_the_middle_of_the_last_blocks() {
+        let headers = [5, 1, 4, 2, 3].map(|timestamp| BlockHeader {
+            timestamp,
+            ..Block::new(0, GENESIS_PREVIOUS_HASH, Body::default()).block_header
+        });
+        assert_eq!(median_time_past(&headers), Some(3));
+        assert_eq!(median_time_past(&[]), None);
+    }
+
+    #[test]
+    fn coinbase_may_only_claim_the_subsidy_and_fees() {
+        let (mut chain, tip) = chain_of_two();
+        let mut block = mine(&chain, Some(tip), miner(), vec![]);
+        let subsidy = chain.params().subsidy(2);
+        block.block_header.coinbase_txn.amount = subsidy + 1;
+        reseal(&mut block);
+
+        // The header checks pass, the ledger refuses the payment.
+        assert_eq!(validate(&chain, &block), Ok(()));
+        assert!(matches!(
+            chain.add_block(block),
+            Err(ChainError::InvalidBlock {
+                source: ValidationError::InvalidStateTransition(StateError::InvalidCoinbaseAmount {
+                    expected,
+                    found,
+                }),
+                ..
+            }) if expected == subsidy && found == subsidy + 1
+        ));
+        assert_eq!(chain.tip(), Some(tip));
+    }
+}